@@ -0,0 +1,40 @@
+// Captures the build details reported by the 'buildinfo' command. SOURCE_DATE_EPOCH
+// replaces the build time for reproducible builds.
+use std::{env, process::Command, time::SystemTime};
+
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|out| String::from_utf8(out.stdout).ok())
+        .unwrap_or_default();
+    let build_time = env::var("SOURCE_DATE_EPOCH")
+        .ok()
+        .and_then(|epoch| epoch.trim().parse::<u64>().ok())
+        .unwrap_or_else(|| {
+            SystemTime::now()
+                .duration_since(SystemTime::UNIX_EPOCH)
+                .map(|d| d.as_secs())
+                .unwrap_or_default()
+        });
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| key.strip_prefix("CARGO_FEATURE_").map(str::to_lowercase))
+        .collect();
+    features.sort();
+
+    // The compiler and the features are part of the fingerprint of the build script already.
+    println!("cargo:rerun-if-changed=build.rs");
+    println!("cargo:rerun-if-env-changed=SOURCE_DATE_EPOCH");
+    println!(
+        "cargo:rustc-env=STARS_RUSTC_VERSION={}",
+        rustc_version.trim()
+    );
+    println!("cargo:rustc-env=STARS_BUILD_TIME={build_time}");
+    println!(
+        "cargo:rustc-env=STARS_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!("cargo:rustc-env=STARS_FEATURES={}", features.join(","));
+}
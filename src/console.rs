@@ -0,0 +1,214 @@
+// Optional ANSI coloring of the console output, the level of the logged messages and
+// the copy of the messages in a log file (--logfile).
+use std::{
+    env,
+    fmt::Display,
+    fs::{File, OpenOptions},
+    io::{self, IsTerminal, Write, stdout},
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, AtomicU8, Ordering},
+    },
+};
+
+use chrono::Local;
+
+use clap::ValueEnum;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum ColorMode {
+    /// Color if stdout is a terminal and NO_COLOR is not set
+    Auto,
+    Always,
+    Never,
+}
+
+#[derive(Clone, Copy, Debug)]
+pub enum Style {
+    Connect,
+    Disconnect,
+    Error,
+    Warning,
+    Debug,
+    Trace,
+    Summary,
+}
+
+// Ordered from quiet to verbose, a message is printed if its level is not above the set one.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Off,
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Trace,
+}
+
+impl LogLevel {
+    const ALL: [LogLevel; 6] = [
+        LogLevel::Off,
+        LogLevel::Error,
+        LogLevel::Warn,
+        LogLevel::Info,
+        LogLevel::Debug,
+        LogLevel::Trace,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            LogLevel::Off => "off",
+            LogLevel::Error => "error",
+            LogLevel::Warn => "warn",
+            LogLevel::Info => "info",
+            LogLevel::Debug => "debug",
+            LogLevel::Trace => "trace",
+        }
+    }
+}
+
+impl FromStr for LogLevel {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        LogLevel::ALL
+            .into_iter()
+            .find(|level| level.name().eq_ignore_ascii_case(s))
+            .ok_or(())
+    }
+}
+
+impl Style {
+    // Connections and the summary lines are informational, the --trace-messages lines
+    // are at the trace level.
+    fn level(self) -> LogLevel {
+        match self {
+            Style::Error => LogLevel::Error,
+            Style::Warning => LogLevel::Warn,
+            Style::Connect | Style::Disconnect | Style::Summary => LogLevel::Info,
+            Style::Debug => LogLevel::Debug,
+            Style::Trace => LogLevel::Trace,
+        }
+    }
+
+    fn code(self) -> &'static str {
+        match self {
+            Style::Connect => "32",
+            Style::Disconnect => "33",
+            Style::Error => "31",
+            Style::Warning => "35",
+            Style::Debug => "34",
+            Style::Trace => "2",
+            Style::Summary => "36",
+        }
+    }
+}
+
+static ENABLED: AtomicBool = AtomicBool::new(false);
+static LEVEL: AtomicU8 = AtomicU8::new(LogLevel::Info as u8);
+static LOGFILE: Mutex<Option<(PathBuf, File)>> = Mutex::new(None);
+// Set by the SIGHUP handler, the accept loop reopens the log file.
+pub static REOPEN_REQUESTED: AtomicBool = AtomicBool::new(false);
+
+pub fn init(mode: ColorMode) {
+    let enabled = match mode {
+        ColorMode::Always => true,
+        ColorMode::Never => false,
+        ColorMode::Auto => {
+            env::var_os("NO_COLOR").is_none_or(|v| v.is_empty()) && stdout().is_terminal()
+        }
+    };
+    ENABLED.store(enabled, Ordering::Relaxed);
+}
+
+// Changes the level at runtime (System command setloglevel).
+pub fn set_level(level: LogLevel) {
+    LEVEL.store(level as u8, Ordering::Relaxed);
+}
+
+pub fn level() -> LogLevel {
+    LogLevel::ALL[LEVEL.load(Ordering::Relaxed) as usize]
+}
+
+fn enabled(style: Style) -> bool {
+    style.level() <= level()
+}
+
+pub fn open_logfile(path: impl Into<PathBuf>) -> io::Result<()> {
+    let path = path.into();
+    let file = OpenOptions::new().create(true).append(true).open(&path)?;
+    *LOGFILE.lock().expect("can't get the lock!") = Some((path, file));
+    Ok(())
+}
+
+// Closes the log file and opens it again by its name, after it was moved away
+// by log rotation. Returns false without a log file.
+pub fn reopen_logfile() -> io::Result<bool> {
+    let mut logfile = LOGFILE.lock().expect("can't get the lock!");
+    let Some((path, file)) = logfile.as_mut() else {
+        return Ok(false);
+    };
+    *file = OpenOptions::new().create(true).append(true).open(path)?;
+    Ok(true)
+}
+
+// Reopens on SIGHUP, the classic way to tell a daemon its log was rotated.
+#[cfg(unix)]
+pub fn install_sighup_handler() {
+    extern "C" fn on_sighup(_: libc::c_int) {
+        REOPEN_REQUESTED.store(true, Ordering::Relaxed);
+    }
+    let handler: extern "C" fn(libc::c_int) = on_sighup;
+    // SAFETY: the handler only stores to an atomic, which is async-signal-safe.
+    unsafe {
+        libc::signal(libc::SIGHUP, handler as libc::sighandler_t);
+    }
+}
+
+fn write_logfile(text: &str) {
+    if let Some((_, file)) = LOGFILE.lock().expect("can't get the lock!").as_mut() {
+        let time = Local::now().format("%Y-%m-%d %H:%M:%S%.3f");
+        let _ = writeln!(file, "{time} {text}");
+    }
+}
+
+pub fn paint(style: Style, text: impl Display) -> String {
+    if ENABLED.load(Ordering::Relaxed) {
+        format!("\x1b[{}m{text}\x1b[0m", style.code())
+    } else {
+        text.to_string()
+    }
+}
+
+pub fn print(style: Style, text: impl Display) {
+    if enabled(style) {
+        let text = text.to_string();
+        println!("{}", paint(style, &text));
+        write_logfile(&text);
+    }
+}
+
+// Printed whatever the level, the caller has its own switch (--trace-messages).
+pub fn trace(text: impl Display) {
+    let text = text.to_string();
+    println!("{}", paint(Style::Trace, &text));
+    write_logfile(&text);
+}
+
+pub fn error(text: impl Display) {
+    eprint(Style::Error, text);
+}
+
+pub fn warn(text: impl Display) {
+    eprint(Style::Warning, text);
+}
+
+fn eprint(style: Style, text: impl Display) {
+    if enabled(style) {
+        let text = text.to_string();
+        eprintln!("{}", paint(style, &text));
+        write_logfile(&text);
+    }
+}
@@ -1,4 +1,11 @@
-use std::{collections::HashMap, net::TcpStream};
+use std::{
+    collections::HashMap,
+    net::TcpStream,
+    ops::{Deref, DerefMut},
+    time::Duration,
+};
+
+use regex::Regex;
 
 // All STARS definitions
 pub const VERSION: &str = env!("CARGO_PKG_VERSION");
@@ -8,7 +15,33 @@ pub const DEFAULT_LIBDIR: &str = "takaserv-lib";
 
 pub const TCP_BUFFER_SIZE: usize = 4096;
 pub const READ_TIMEOUT: u64 = 2000; // timeout in msec
+pub const MAX_TIMEOUT: u64 = 600_000; // largest timeout accepted by settimeout
 pub const RNDMAX: u16 = 10000;
+pub const MAX_FRAME_LEN: usize = 16 * 1024 * 1024; // largest body of a binary frame
+pub const DEFAULT_TIMESTAMP_FORMAT: &str = "%Y-%m-%dT%H:%M:%S%.6f";
+pub const MAX_RETAINED_MESSAGES: usize = 1024; // kept of all the 'retain=1' nodes together
+pub const MAX_REPLY_CMD_LEN: usize = 64; // command echoed in the reply to an oversize message
+pub const MAX_FLG_HISTORY: usize = 8; // notices kept per flgon subscription ('flghistory')
+pub const MAX_WATCH_PATTERNS: usize = 16; // 'watch' patterns per node
+pub const MAX_PAUSED_MESSAGES: usize = 1024; // messages kept while the routing is paused
+pub const MAX_KEY_LEN: usize = 256; // longest key response checked against the key file
+pub const DEFAULT_RECENT_DISCONNECTS: usize = 32; // kept for 'recentdisconnects'
+pub const DEFAULT_SHUTDOWN_GRACE: u64 = 1000; // msec the nodes get to close at shutdown
+pub const DEFAULT_REGISTRATION_TIMEOUT: u64 = 10_000; // msec if neither timeout is set
+pub const DEFAULT_MAX_REGISTRATION_LEN: usize = 512; // '<node> <key> [cap=value ...]' line
+pub const DEFAULT_AUTOBAN_SECS: u64 = 600; // ban time after too many protocol violations
+pub const DEFAULT_PINGNODE_TIMEOUT: u64 = 5000; // msec 'pingnode' waits for the echo
+pub const DEFAULT_KEEPALIVE_IDLE: u64 = 120; // sec without traffic before the first probe
+pub const DEFAULT_KEEPALIVE_INTERVAL: u64 = 30; // sec between the keepalive probes
+pub const DEFAULT_KEEPALIVE_COUNT: u32 = 4; // unanswered probes closing the connection
+pub const MAX_CLAIMS: usize = 16; // additional names of one connection ('claim')
+pub const LIST_PAGE_SIZE: usize = 200; // names per reply of listnodes and listaliases
+pub const ACCEPT_POLL_INTERVAL: Duration = Duration::from_millis(100);
+pub const SHUTDOWN_POLL_INTERVAL: Duration = Duration::from_millis(20); // nodes left at shutdown
+pub const DNS_CACHE_TTL: Duration = Duration::from_secs(300); // reverse DNS results per ip
+pub const DNS_LOOKUP_TIMEOUT: Duration = Duration::from_secs(2);
+// An event sent this soon after the node got one counts as caused by it (--event-hop-limit).
+pub const EVENT_HOP_WINDOW: Duration = Duration::from_secs(1);
 
 pub const HOST_LIST: &str = "allow.cfg";
 pub const ALIASES: &str = "aliases.cfg";
@@ -17,12 +50,116 @@ pub const CMD_ALLOW: &str = "command_allow.cfg";
 pub const RECONNECT_TABLE_DENY: &str = "reconnectable_deny.cfg";
 pub const RECONNECT_TABLE_ALLOW: &str = "reconnectable_allow.cfg";
 pub const SHUTDOWN_ALLOW: &str = "shutdown_allow.cfg";
+pub const NODE_PREFIX: &str = "node_prefix.cfg";
+pub const MAX_INCLUDE_DEPTH: usize = 8; // nesting limit for include directives in cfg files
 
 // Type definitions
-pub type NodeList = HashMap<String, TcpStream>;
 pub type GenericError = Box<dyn std::error::Error + Send + Sync + 'static>;
 pub type GenericResult<T> = Result<T, GenericError>;
 
+// Streams of the connected nodes by name. Also holds the node that gets a copy of
+// every message written by the server (see sendtodebugger), as both are needed together.
+#[derive(Debug, Default)]
+pub struct NodeList {
+    streams: HashMap<String, TcpStream>,
+    pub debugger: Option<String>,
+    pub debug_filter: Option<DebugFilter>,
+    // Name patterns registered with 'watch' by node, as given and compiled.
+    pub watchers: HashMap<String, Vec<(String, Regex)>>,
+    // Additional names claimed with 'claim' by node. They map to a clone of the node's stream.
+    pub claims: HashMap<String, Vec<String>>,
+    // Lines are sent with '\r\n' instead of '\n' (--line-ending crlf).
+    pub crlf: bool,
+    // Retries of a write which hits the write timeout (--write-retries).
+    pub write_retries: u32,
+}
+
+impl NodeList {
+    pub fn new() -> NodeList {
+        NodeList::default()
+    }
+
+    // Removing the debugger node also ends the debug forwarding, and the watching of the node.
+    // The names claimed by the node go with it.
+    pub fn remove(&mut self, node: &str) -> Option<TcpStream> {
+        if self.debugger.as_deref() == Some(node) {
+            self.debugger = None;
+            self.debug_filter = None;
+        }
+        self.watchers.remove(node);
+        for name in self.claims.remove(node).unwrap_or_default() {
+            self.streams.remove(&name);
+        }
+        for names in self.claims.values_mut() {
+            names.retain(|name| name != node);
+        }
+        self.streams.remove(node)
+    }
+
+    // True for a name claimed by another node, which shares the stream of that node.
+    pub fn is_claimed(&self, name: &str) -> bool {
+        self.owner(name).is_some()
+    }
+
+    // The node which claimed the name. Its NodeInfo stands for the claimed name.
+    pub fn owner(&self, name: &str) -> Option<&str> {
+        self.claims
+            .iter()
+            .find(|(_, names)| names.iter().any(|n| n == name))
+            .map(|(owner, _)| owner.as_str())
+    }
+
+    // Registered connections, without the claimed names.
+    pub fn connections(&self) -> usize {
+        self.streams.len() - self.claims.values().map(Vec::len).sum::<usize>()
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Direction {
+    In,
+    Out,
+    Both,
+}
+
+// Limits the debugger copies to the messages to ('in') and/or from ('out') one node.
+#[derive(Debug, Clone)]
+pub struct DebugFilter {
+    pub node: String,
+    pub direction: Direction,
+}
+
+impl DebugFilter {
+    // Returns the message tagged with its direction, or None if it does not pass the filter.
+    pub fn apply(&self, msg: &str) -> Option<String> {
+        let (from, rest) = msg.split_once('>')?;
+        let to = rest.split([' ', '\n']).next()?;
+        let is_node = |name: &str| name.split('.').next() == Some(self.node.as_str());
+        let tag = if is_node(to) && self.direction != Direction::Out {
+            "in"
+        } else if is_node(from) && self.direction != Direction::In {
+            "out"
+        } else {
+            return None;
+        };
+        Some(format!("[{tag}] {msg}"))
+    }
+}
+
+impl Deref for NodeList {
+    type Target = HashMap<String, TcpStream>;
+
+    fn deref(&self) -> &Self::Target {
+        &self.streams
+    }
+}
+
+impl DerefMut for NodeList {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        &mut self.streams
+    }
+}
+
 // Macros
 #[macro_export]
 macro_rules! dbprint { // To print messages only in debug build
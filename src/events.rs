@@ -1,6 +1,10 @@
 use std::sync::mpsc;
 
-/// Events sent from the TCP server threads to the Bevy visualization.
+use chrono::Local;
+use serde_json::{Value, json};
+
+/// Events sent from the TCP server threads to the Bevy visualization and the event sink.
+/// Without a receiver the sends fail immediately.
 #[derive(Debug, Clone)]
 pub enum ServerEvent {
     NodeConnected {
@@ -13,7 +17,67 @@ pub enum ServerEvent {
         from: String,
         to: String,
     },
+    RoutingPaused {
+        paused: bool,
+    },
+    RegistrationsLocked {
+        locked: bool,
+    },
+    CommandDenied {
+        node: String,
+        command: String,
+    },
+    ConnectionRejected {
+        node: String,
+        peer: String,
+        reason: String,
+    },
+    /// The server has shut down, the visualization closes its window.
+    ServerShutdown,
+}
+
+impl ServerEvent {
+    /// One line of the event sink, e.g. '{"event":"node_connected","name":"term1","time":...}'.
+    pub fn to_json(&self) -> Value {
+        let mut value = match self {
+            ServerEvent::NodeConnected { name } => json!({"event": "node_connected", "name": name}),
+            ServerEvent::NodeDisconnected { name } => {
+                json!({"event": "node_disconnected", "name": name})
+            }
+            ServerEvent::MessageRouted { from, to } => {
+                json!({"event": "message_routed", "from": from, "to": to})
+            }
+            ServerEvent::RoutingPaused { paused } => {
+                json!({"event": "routing_paused", "paused": paused})
+            }
+            ServerEvent::RegistrationsLocked { locked } => {
+                json!({"event": "registrations_locked", "locked": locked})
+            }
+            ServerEvent::CommandDenied { node, command } => {
+                json!({"event": "command_denied", "node": node, "command": command})
+            }
+            ServerEvent::ConnectionRejected { node, peer, reason } => json!({
+                "event": "connection_rejected", "node": node, "peer": peer, "reason": reason
+            }),
+            ServerEvent::ServerShutdown => json!({"event": "server_shutdown"}),
+        };
+        value["time"] = json!(Local::now().to_rfc3339());
+        value
+    }
 }
 
-pub type EventSender = mpsc::Sender<ServerEvent>;
+pub type EventSender = mpsc::SyncSender<ServerEvent>;
 pub type EventReceiver = mpsc::Receiver<ServerEvent>;
+
+// Events beyond this are dropped until the visualization catches up.
+const EVENT_QUEUE_LEN: usize = 4096;
+
+pub fn channel() -> (EventSender, EventReceiver) {
+    mpsc::sync_channel(EVENT_QUEUE_LEN)
+}
+
+// Never blocks, the sends happen while the node list is locked.
+// A full queue or a missing receiver drops the event.
+pub fn notify(event_tx: &EventSender, event: ServerEvent) {
+    let _ = event_tx.try_send(event);
+}
@@ -0,0 +1,128 @@
+// Ships the server events as newline delimited JSON (--event-sink <uri>) to a file,
+// a Unix socket or a TCP endpoint. An unavailable destination drops the events with a
+// counter and is opened again after SINK_RETRY_INTERVAL, the server never waits for it.
+use std::{
+    fs::OpenOptions,
+    io::Write,
+    net::{TcpStream, ToSocketAddrs},
+    str::FromStr,
+    sync::mpsc::Receiver,
+    thread::{self, JoinHandle},
+    time::{Duration, Instant},
+};
+
+use crate::console;
+use crate::definitions::*;
+use crate::events::{self, EventSender, ServerEvent};
+use crate::starserror::StarsError;
+
+const SINK_RETRY_INTERVAL: Duration = Duration::from_secs(1);
+const SINK_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum Destination {
+    File(String),
+    #[cfg(unix)]
+    Unix(String),
+    Tcp(String),
+}
+
+// 'file:<path>', 'unix:<path>' or 'tcp:<host>:<port>'. Anything else is a file path.
+impl FromStr for Destination {
+    type Err = GenericError;
+
+    fn from_str(uri: &str) -> Result<Self, Self::Err> {
+        let dest = match uri.split_once(':') {
+            Some(("file", path)) => Destination::File(path.to_string()),
+            #[cfg(unix)]
+            Some(("unix", path)) => Destination::Unix(path.to_string()),
+            Some(("tcp", addr)) => Destination::Tcp(addr.to_string()),
+            _ => Destination::File(uri.to_string()),
+        };
+        let empty = match &dest {
+            Destination::File(s) | Destination::Tcp(s) => s.is_empty(),
+            #[cfg(unix)]
+            Destination::Unix(s) => s.is_empty(),
+        };
+        if empty {
+            return Err(GenericError::from(StarsError::InvalidSetting {
+                name: "event sink".to_string(),
+                value: uri.to_string(),
+            }));
+        }
+        Ok(dest)
+    }
+}
+
+impl Destination {
+    fn open(&self) -> GenericResult<Box<dyn Write + Send>> {
+        Ok(match self {
+            Destination::File(path) => {
+                Box::new(OpenOptions::new().create(true).append(true).open(path)?)
+            }
+            #[cfg(unix)]
+            Destination::Unix(path) => {
+                let stream = std::os::unix::net::UnixStream::connect(path)?;
+                stream.set_write_timeout(Some(SINK_WRITE_TIMEOUT))?;
+                Box::new(stream)
+            }
+            Destination::Tcp(addr) => {
+                let addr = addr
+                    .to_socket_addrs()?
+                    .next()
+                    .ok_or(StarsError::Message(format!("No address for {addr}")))?;
+                let stream = TcpStream::connect_timeout(&addr, SINK_WRITE_TIMEOUT)?;
+                stream.set_write_timeout(Some(SINK_WRITE_TIMEOUT))?;
+                Box::new(stream)
+            }
+        })
+    }
+}
+
+// Consumes the events until all senders are gone. Each event is passed on to `forward`
+// (the visualization) as well.
+pub fn spawn(
+    dest: Destination,
+    rx: Receiver<ServerEvent>,
+    forward: Option<EventSender>,
+) -> JoinHandle<()> {
+    thread::spawn(move || {
+        let mut writer: Option<Box<dyn Write + Send>> = None;
+        let mut last_open: Option<Instant> = None;
+        let mut dropped = 0_u64;
+        for event in rx {
+            let line = format!("{}\n", event.to_json());
+            if let Some(forward) = &forward {
+                events::notify(forward, event);
+            }
+            if writer.is_none() && last_open.is_none_or(|t| t.elapsed() >= SINK_RETRY_INTERVAL) {
+                last_open = Some(Instant::now());
+                match dest.open() {
+                    Ok(w) => writer = Some(w),
+                    Err(err) if dropped == 0 => {
+                        console::error(format!("Event sink unavailable, dropping events: {err}"));
+                    }
+                    Err(_) => {}
+                }
+            }
+            let Some(w) = &mut writer else {
+                dropped += 1;
+                continue;
+            };
+            match w.write_all(line.as_bytes()).and_then(|_| w.flush()) {
+                Ok(_) if dropped > 0 => {
+                    console::error(format!("Event sink available again, {dropped} dropped."));
+                    dropped = 0;
+                }
+                Ok(_) => {}
+                Err(err) => {
+                    if dropped == 0 {
+                        console::error(format!("Event sink unavailable, dropping events: {err}"));
+                    }
+                    writer = None;
+                    dropped += 1;
+                }
+            }
+        }
+    })
+}
@@ -0,0 +1,169 @@
+// Load generator for benchmarking a running server (hidden option --loadtest <n> <rate>).
+// Every client registers as <prefix><i> like a real node, so the server needs the key files
+// of these names. Each client sends messages to itself, the time in the message gives
+// the round trip latency through the routing of the server.
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    sync::mpsc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::definitions::*;
+use crate::starserror::StarsError;
+use crate::utilities::load_keyfile;
+
+pub struct LoadTest {
+    pub addr: SocketAddr,
+    pub keydir: String,
+    pub prefix: String,
+    pub clients: usize,
+    // Messages per second and client
+    pub rate: u64,
+    pub duration: Duration,
+}
+
+#[derive(Debug, Default)]
+pub struct LoadReport {
+    pub connected: usize,
+    pub sent: u64,
+    pub received: u64,
+    pub elapsed: Duration,
+    // Round trip times of the received messages, sorted
+    pub latencies: Vec<Duration>,
+}
+
+impl LoadReport {
+    pub fn percentile(&self, p: f64) -> Duration {
+        if self.latencies.is_empty() {
+            return Duration::ZERO;
+        }
+        let index = ((self.latencies.len() - 1) as f64 * p / 100.0).round() as usize;
+        self.latencies[index]
+    }
+
+    pub fn summary(&self) -> String {
+        let secs = self.elapsed.as_secs_f64().max(f64::EPSILON);
+        format!(
+            "clients={} sent={} received={} throughput={:.1} msg/s \
+             latency p50={:?} p90={:?} p99={:?} max={:?}",
+            self.connected,
+            self.sent,
+            self.received,
+            self.received as f64 / secs,
+            self.percentile(50.0),
+            self.percentile(90.0),
+            self.percentile(99.0),
+            self.latencies.last().copied().unwrap_or_default(),
+        )
+    }
+}
+
+// Registers like a STARS client: the key is the line of <name>.key selected by the nodekey.
+fn register(addr: SocketAddr, name: &str, keydir: &str) -> GenericResult<TcpStream> {
+    let stream = TcpStream::connect(addr)?;
+    stream.set_read_timeout(Some(Duration::from_millis(READ_TIMEOUT)))?;
+    let mut reader = BufReader::new(stream.try_clone()?);
+    let mut line = String::new();
+    reader.read_line(&mut line)?;
+    let nodekey: usize = line.trim().parse().map_err(|_| {
+        StarsError::Message(format!("{name}: unexpected greeting '{}'", line.trim()))
+    })?;
+    let keys = load_keyfile(&format!("{name}.key"), keydir)?;
+    let key = keys
+        .get(nodekey % keys.len().max(1))
+        .cloned()
+        .unwrap_or_default();
+    (&stream).write_all(format!("{name} {key}\n").as_bytes())?;
+    line.clear();
+    reader.read_line(&mut line)?;
+    if line.trim() != format!("System>{name} Ok:") {
+        return Err(GenericError::from(StarsError::AuthFailed {
+            node: name.to_string(),
+            reply: line.trim().to_string(),
+        }));
+    }
+    stream.set_read_timeout(None)?;
+    Ok(stream)
+}
+
+pub fn run(test: &LoadTest) -> GenericResult<LoadReport> {
+    let start = Instant::now();
+    let (tx, rx) = mpsc::channel();
+    let mut writers = Vec::new();
+    let mut report = LoadReport::default();
+    for i in 1..=test.clients {
+        let name = format!("{}{i}", test.prefix);
+        let stream = match register(test.addr, &name, &test.keydir) {
+            Ok(stream) => stream,
+            Err(err) => {
+                eprintln!("{err}");
+                continue;
+            }
+        };
+        report.connected += 1;
+        let reader = BufReader::new(stream.try_clone()?);
+        let tx = tx.clone();
+        thread::spawn(move || {
+            for line in reader.lines() {
+                let Ok(line) = line else { break };
+                // '<name>>name lt <micros since start>'
+                if let Some(sent) = line.rsplit(' ').next().and_then(|t| t.parse().ok()) {
+                    let latency = start.elapsed().saturating_sub(Duration::from_micros(sent));
+                    if tx.send(latency).is_err() {
+                        break;
+                    }
+                }
+            }
+        });
+        let (duration, rate) = (test.duration, test.rate.max(1));
+        writers.push(thread::spawn(move || {
+            let interval = Duration::from_secs_f64(1.0 / rate as f64);
+            let mut sent = 0_u64;
+            let mut writer = &stream;
+            let begin = Instant::now();
+            while begin.elapsed() < duration {
+                let micros = start.elapsed().as_micros();
+                if writer
+                    .write_all(format!("{name} lt {micros}\n").as_bytes())
+                    .is_err()
+                {
+                    break;
+                }
+                sent += 1;
+                // Paced from the begin, so a slow write does not lower the rate.
+                let next = interval * sent as u32;
+                thread::sleep(next.saturating_sub(begin.elapsed()));
+            }
+            (stream, sent)
+        }));
+    }
+    drop(tx);
+    let mut streams = Vec::new();
+    for writer in writers {
+        let (stream, sent) = writer.join().expect("loadtest thread panicked");
+        report.sent += sent;
+        streams.push(stream);
+    }
+    // Wait for the messages still on their way.
+    let deadline = Instant::now() + Duration::from_millis(READ_TIMEOUT);
+    while report.received < report.sent {
+        let Some(wait) = deadline.checked_duration_since(Instant::now()) else {
+            break;
+        };
+        match rx.recv_timeout(wait) {
+            Ok(latency) => {
+                report.received += 1;
+                report.latencies.push(latency);
+            }
+            Err(_) => break,
+        }
+    }
+    report.elapsed = start.elapsed();
+    report.latencies.sort();
+    for stream in streams {
+        let _ = (&stream).write_all(b"exit\n");
+    }
+    Ok(report)
+}
@@ -3,26 +3,46 @@
  * Based on Perl STARS server from Takashi Kosuge; KEK Tsukuba
  * stars.kek.jp
  */
-use std::{sync::mpsc, thread};
+use std::{
+    net::{IpAddr, SocketAddr},
+    process,
+    time::Duration,
+};
 
-use clap::Parser;
+use clap::{ArgMatches, CommandFactory, FromArgMatches, Parser, parser::ValueSource};
 use configparser::ini::Ini;
 
+mod console;
 mod definitions;
 use definitions::*;
-mod utilities;
-mod starsdata;
-mod starserror;
 mod events;
+mod eventsink;
+mod loadtest;
+mod mirror;
 mod server;
+mod starsdata;
+mod starserror;
+mod stats;
+mod syslog;
+#[cfg(test)]
+mod tests;
+mod utilities;
+#[cfg(feature = "gui")]
 mod visualization;
 
-use server::ServerConfig;
+use console::ColorMode;
+use server::{
+    LineEnding, OverLimitPolicy, PauseMode, PauseOverflow, ServerConfig, Timestamps, UnknownCommand,
+};
 use starserror::StarsError;
 
 #[derive(Parser)]
 #[command(version, about, long_about = None)]
 struct Arguments {
+    /// Config file to use instead of stars.cfg. The server will not start if it can't be read.
+    /// Port, lib, key and timeout given on the command line override its values.
+    #[arg(long)]
+    config: Option<String>,
     /// Portnumber of the server.
     #[arg(short, long, default_value_t = 6057)]
     port: u16,
@@ -32,12 +52,194 @@ struct Arguments {
     /// Directory with the server .key files. If empty lib directory will be used.
     #[arg(short, long, default_value_t = String::from(""))]
     keydir: String,
-    /// Read timeout in msec
-    #[arg(short, long, default_value_t = READ_TIMEOUT)]
+    /// Registration read timeout in msec, 0 is none
+    #[arg(
+        short,
+        long,
+        default_value_t = READ_TIMEOUT,
+        value_parser = parse_msec,
+        allow_hyphen_values = true
+    )]
     timeout: u64,
     /// Enable Bevy node graph visualization window
+    #[cfg(feature = "gui")]
     #[arg(long, default_value_t = false)]
     visualize: bool,
+    /// JSON file to save and restore the node positions of the visualization
+    #[cfg(feature = "gui")]
+    #[arg(long)]
+    positions_file: Option<String>,
+    /// JSON file with a fixed layout (node name -> [x, y]) of the visualization. Unlisted nodes
+    /// are placed automatically, the L key switches between this layout and the automatic one
+    #[cfg(feature = "gui")]
+    #[arg(long)]
+    layout_file: Option<String>,
+    /// Reject messages to sub-nodes (node.sub) not declared by the node with 'subnodes='
+    #[arg(long, default_value_t = false)]
+    strict_subnodes: bool,
+    /// Reject received lines with invalid UTF-8 instead of replacing the invalid bytes
+    #[arg(long, default_value_t = false)]
+    strict_utf8: bool,
+    /// Log every routed message with its disposition (delivered, denied, down, ...), whatever
+    /// the log level
+    #[arg(long, default_value_t = false)]
+    trace_messages: bool,
+    /// Time in msec a registration waits for the old connection of the same node to close
+    #[arg(long, default_value_t = 0)]
+    reconnect_grace: u64,
+    /// Replace the existing connection of a node at registration if it is found dead
+    #[arg(long, default_value_t = false)]
+    probe_dead_nodes: bool,
+    /// A node registering as 'Debugger' gets a copy of all messages (old behavior)
+    #[arg(long, default_value_t = false)]
+    debugger_by_name: bool,
+    /// Reply to unknown System commands
+    #[arg(long, value_enum, default_value_t = UnknownCommand::Error)]
+    unknown_command: UnknownCommand,
+    /// Add the server time to routed messages
+    #[arg(long, value_enum, default_value_t = Timestamps::Off)]
+    timestamps: Timestamps,
+    /// chrono format string of the message timestamps
+    #[arg(long, default_value_t = DEFAULT_TIMESTAMP_FORMAT.to_string())]
+    timestamp_format: String,
+    /// Write timeout in msec, a node not accepting data for this time is disconnected. 0 is none
+    #[arg(long, default_value_t = 0, value_parser = parse_msec, allow_hyphen_values = true)]
+    write_timeout: u64,
+    /// Retries of a write that hit the write timeout before the node is disconnected
+    #[arg(long, default_value_t = 0)]
+    write_retries: u32,
+    /// Messages between nodes while the routing is paused with 'pause'
+    #[arg(long, value_enum, default_value_t = PauseMode::Reject)]
+    pause_mode: PauseMode,
+    /// Maximum bytes of the messages kept with --pause-mode buffer, 0 only limits their number
+    #[arg(long, default_value_t = 0)]
+    pause_buffer_bytes: usize,
+    /// Messages not fitting into the pause buffer any more
+    #[arg(long, value_enum, default_value_t = PauseOverflow::Reject)]
+    pause_overflow: PauseOverflow,
+    /// Name of the pseudo node for the server commands and replies, instead of System
+    #[arg(long, default_value_t = String::from("System"))]
+    system_name: String,
+    /// Benchmark a running server with <N> clients sending <RATE> messages per second each
+    #[arg(long, num_args = 2, value_names = ["N", "RATE"], hide = true)]
+    loadtest: Option<Vec<u64>>,
+    /// Duration of the load test in seconds
+    #[arg(long, default_value_t = 10, hide = true)]
+    loadtest_secs: u64,
+    /// Node name prefix of the load test clients, they need key files <prefix>1.key ...
+    #[arg(long, default_value_t = String::from("loadtest"), hide = true)]
+    loadtest_prefix: String,
+    /// Maximum length in bytes of a message body after the command word, 0 is unlimited.
+    /// System commands are not limited
+    #[arg(long, default_value_t = 0)]
+    max_body_len: usize,
+    /// Token the privileged commands (shutdown, ...) must be given as last argument
+    #[arg(long)]
+    admin_token: Option<String>,
+    /// Copy connects, disconnects, rejections and the shutdown to the local syslog
+    /// (syslog feature)
+    #[arg(long, default_value_t = false)]
+    syslog: bool,
+    /// Seconds between summary lines (nodes, routed messages, rate, threads), 0 is off
+    #[arg(long, default_value_t = 0)]
+    summary_interval: u64,
+    /// At every disconnect remove the flgon subscriptions to nodes which are not registered
+    #[arg(long, default_value_t = false)]
+    prune_subscriptions: bool,
+    /// Don't resolve the client host names, the host lists only match ips
+    #[arg(long, default_value_t = false)]
+    no_reverse_dns: bool,
+    /// Write the server events as JSON lines to file:<path>, unix:<path> or tcp:<host>:<port>
+    #[arg(long)]
+    event_sink: Option<String>,
+    /// Save the subscriptions, command overrides and bans to this file at shutdown and
+    /// load them again at startup
+    #[arg(long)]
+    state_file: Option<String>,
+    /// Write the process id to this file, removed at shutdown. The server does not start
+    /// if the file names a running process
+    #[arg(long)]
+    pidfile: Option<String>,
+    /// Copy every routed line to this host:port. Lines are dropped while it is down or behind
+    #[arg(long)]
+    mirror: Option<String>,
+    /// Number of disconnects kept for 'recentdisconnects'
+    #[arg(long, default_value_t = DEFAULT_RECENT_DISCONNECTS)]
+    recent_disconnects: usize,
+    /// Line ending of the lines sent to the clients
+    #[arg(long, value_enum, default_value_t = LineEnding::Lf)]
+    line_ending: LineEnding,
+    /// Time in msec the nodes get to close their connection after the shutdown notice
+    #[arg(long, default_value_t = DEFAULT_SHUTDOWN_GRACE)]
+    shutdown_grace: u64,
+    /// Maximum number of nodes connected from one host at the same time, 0 is unlimited
+    #[arg(long, default_value_t = 0)]
+    max_nodes_per_host: usize,
+    /// Ip not counted against --max-nodes-per-host, can be given more than once
+    #[arg(long)]
+    host_limit_exempt: Vec<IpAddr>,
+    /// Maximum length in bytes of the registration line sent before the node is authenticated
+    #[arg(long, default_value_t = DEFAULT_MAX_REGISTRATION_LEN)]
+    max_registration_len: usize,
+    /// Time in msec a connection has to register, 0 uses --timeout (or 10 s if that is 0 too)
+    #[arg(long, default_value_t = 0, value_parser = parse_msec, allow_hyphen_values = true)]
+    registration_timeout: u64,
+    /// Count a registration which times out as a protocol violation, so a host holding
+    /// connections open without registering gets banned by --autoban-threshold
+    #[arg(long, default_value_t = false)]
+    registration_timeout_violation: bool,
+    /// Protocol violations (oversized registration or frame) after which the ip is banned,
+    /// 0 never bans. The --host-limit-exempt ips are never banned
+    #[arg(long, default_value_t = 0)]
+    autoban_threshold: u32,
+    /// Seconds an auto-banned ip is refused
+    #[arg(long, default_value_t = DEFAULT_AUTOBAN_SECS)]
+    autoban_secs: u64,
+    /// Maximum number of connected nodes, 0 is unlimited
+    #[arg(long, default_value_t = 0)]
+    max_connections: usize,
+    /// Registrations beyond --max-connections
+    #[arg(long, value_enum, default_value_t = OverLimitPolicy::Reject)]
+    over_limit_policy: OverLimitPolicy,
+    /// Node never evicted by --over-limit-policy evict-oldest, can be given more than once.
+    /// The nodes in shutdown_allow.cfg are never evicted
+    #[arg(long)]
+    evict_exempt: Vec<String>,
+    /// Hops after which an event is dropped, 0 is unlimited. An event sent within a second of
+    /// receiving one is one hop further, so nodes answering each other's events stop
+    #[arg(long, default_value_t = 0)]
+    event_hop_limit: u32,
+    /// Don't enable TCP keepalive on the node connections
+    #[arg(long, default_value_t = false)]
+    no_tcp_keepalive: bool,
+    /// Seconds without traffic before the first TCP keepalive probe
+    #[arg(long, default_value_t = DEFAULT_KEEPALIVE_IDLE)]
+    keepalive_idle: u64,
+    /// Seconds between the TCP keepalive probes
+    #[arg(long, default_value_t = DEFAULT_KEEPALIVE_INTERVAL)]
+    keepalive_interval: u64,
+    /// Unanswered TCP keepalive probes after which a connection is closed
+    #[arg(long, default_value_t = DEFAULT_KEEPALIVE_COUNT)]
+    keepalive_count: u32,
+    /// Also write the server messages to this file. It is reopened on SIGHUP or 'reopenlog'
+    #[arg(long)]
+    logfile: Option<String>,
+    /// Colored console output. NO_COLOR disables it in auto mode
+    #[arg(long, value_enum, default_value_t = ColorMode::Auto)]
+    color: ColorMode,
+}
+
+// Timeouts are msec with 0 for none. A negative value gets its own message instead of
+// being taken for an option.
+fn parse_msec(value: &str) -> Result<u64, String> {
+    if value.starts_with('-') {
+        return Err(format!(
+            "'{value}' is negative, timeouts are msec with 0 for none"
+        ));
+    }
+    value
+        .parse()
+        .map_err(|_| format!("'{value}' is not a number of msec"))
 }
 
 struct Param {
@@ -56,57 +258,131 @@ fn read_parameter(args: &Arguments) -> Param {
     }
 }
 
+// An option given on the command line wins over the config file. A different value in the
+// file is reported, so neither of them is ignored without notice.
+fn apply_command_line(param: &mut Param, args: &Arguments, matches: &ArgMatches, fname: &str) {
+    let given = |id: &str| matches.value_source(id) == Some(ValueSource::CommandLine);
+    let report = |option: &str, keyword: &str, cli: String, file: String| {
+        if cli != file {
+            console::error(format!(
+                "Warning: --{option} {cli} overrides {keyword} = {file} of {fname}, using {cli}."
+            ));
+        }
+    };
+    if given("port") {
+        report(
+            "port",
+            "starsport",
+            args.port.to_string(),
+            param.port.to_string(),
+        );
+        param.port = args.port;
+    }
+    if given("libdir") {
+        report(
+            "libdir",
+            "starslib",
+            args.libdir.clone(),
+            param.libdir.clone(),
+        );
+        param.libdir = args.libdir.clone();
+    }
+    if given("keydir") {
+        report(
+            "keydir",
+            "starskey",
+            args.keydir.clone(),
+            param.keydir.clone(),
+        );
+        param.keydir = args.keydir.clone();
+    }
+    if given("timeout") {
+        report(
+            "timeout",
+            "timeout",
+            args.timeout.to_string(),
+            param.timeout.to_string(),
+        );
+        param.timeout = args.timeout;
+    }
+}
+
 fn read_config_file(fname: &str) -> GenericResult<Param> {
     let mut config = Ini::new();
     config.load(fname)?;
-    let p = config
-        .get("param", "starsport")
-        .ok_or(GenericError::from(StarsError {
-            message: "starsport keyword not found!".to_string(),
-        }))?;
-    let lb = config
-        .get("param", "starslib")
-        .ok_or(GenericError::from(StarsError {
-            message: "starslib keyword not found!".to_string(),
-        }))?;
-    let kd = config
-        .get("param", "starskey")
-        .ok_or(GenericError::from(StarsError {
-            message: "starskey keyword not found!".to_string(),
-        }))?;
-    let to = config
-        .get("param", "timeout")
-        .ok_or(GenericError::from(StarsError {
-            message: "timeout keyword not found!".to_string(),
-        }))?;
+    let get = |keyword: &str| {
+        config
+            .get("param", keyword)
+            .ok_or(StarsError::MissingKeyword {
+                keyword: keyword.to_string(),
+            })
+    };
+    let invalid = |name: &str, value: &str| StarsError::InvalidSetting {
+        name: name.to_string(),
+        value: value.to_string(),
+    };
+    let p = get("starsport")?;
+    let lb = get("starslib")?;
+    let kd = get("starskey")?;
+    let to = get("timeout")?;
     let param = Param {
-        port: p.parse()?,
+        port: p.parse().map_err(|_| invalid("starsport", &p))?,
         libdir: lb,
         keydir: kd,
-        timeout: to.parse()?,
+        timeout: parse_msec(&to).map_err(|_| invalid("timeout", &to))?,
     };
     println!("Config file found.");
     Ok(param)
 }
 
 fn main() {
-    let args = Arguments::parse();
+    let matches = Arguments::command().get_matches();
+    let args = Arguments::from_arg_matches(&matches).unwrap_or_else(|err| err.exit());
+    console::init(args.color);
+    if let Some(fname) = &args.logfile {
+        if let Err(err) = console::open_logfile(fname) {
+            exit_with_error(GenericError::from(StarsError::Message(format!(
+                "ERROR: Can't open log file {fname}!\n{err}"
+            ))));
+        }
+        #[cfg(unix)]
+        console::install_sighup_handler();
+    }
+    #[cfg(feature = "gui")]
     let visualize = args.visualize;
+    #[cfg(feature = "gui")]
+    let positions_file = args.positions_file.clone();
+    #[cfg(feature = "gui")]
+    let layout_file = args.layout_file.clone();
 
     println!();
     println!("STARS Server Version: {VERSION}");
     dbprint!("ON");
     println!();
 
-    let mut param = match read_config_file(CONFIG_FILE) {
-        Ok(p) => p,
-        Err(err) => {
-            let msg = format!("{err}");
-            println!(
-                "No config file found or error at reading file!\n{msg}\nUsing given or default arguments."
-            );
-            read_parameter(&args)
+    let mut param = match &args.config {
+        Some(fname) => {
+            let mut p = read_config_file(fname).unwrap_or_else(|err| {
+                exit_with_error(GenericError::from(StarsError::Message(format!(
+                    "ERROR: Can't read config file {fname}!\n{err}"
+                ))))
+            });
+            apply_command_line(&mut p, &args, &matches, fname);
+            p
         }
+        None => match read_config_file(CONFIG_FILE) {
+            Ok(mut p) => {
+                apply_command_line(&mut p, &args, &matches, CONFIG_FILE);
+                p
+            }
+            Err(err) => {
+                let msg = format!("{err}");
+                println!(
+                    "No config file found or error at reading file!\n{msg}\nUsing given or default arguments."
+                );
+                read_parameter(&args)
+            }
+        },
     };
     if param.keydir.is_empty() {
         param.keydir = param.libdir.clone();
@@ -120,24 +396,116 @@ fn main() {
     println!("------------------");
     println!();
 
+    if let Some(load) = &args.loadtest {
+        let test = loadtest::LoadTest {
+            addr: SocketAddr::from(([127, 0, 0, 1], param.port)),
+            keydir: param.keydir,
+            prefix: args.loadtest_prefix.clone(),
+            clients: load[0] as usize,
+            rate: load[1],
+            duration: Duration::from_secs(args.loadtest_secs),
+        };
+        match loadtest::run(&test) {
+            Ok(report) => println!("{}", report.summary()),
+            Err(err) => exit_with_error(err),
+        }
+        return;
+    }
+
+    if args.syslog
+        && let Err(err) = syslog::init()
+    {
+        exit_with_error(GenericError::from(StarsError::Message(format!(
+            "ERROR: Can't open syslog!\n{err}"
+        ))));
+    }
+
     let server_config = ServerConfig {
         port: param.port,
         libdir: param.libdir,
         keydir: param.keydir,
         timeout: param.timeout,
+        strict_subnodes: args.strict_subnodes,
+        strict_utf8: args.strict_utf8,
+        trace_messages: args.trace_messages,
+        reconnect_grace: args.reconnect_grace,
+        probe_dead_nodes: args.probe_dead_nodes,
+        debugger_by_name: args.debugger_by_name,
+        unknown_command: args.unknown_command,
+        timestamps: args.timestamps,
+        timestamp_format: args.timestamp_format,
+        write_timeout: args.write_timeout,
+        write_retries: args.write_retries,
+        pause_mode: args.pause_mode,
+        pause_buffer_bytes: args.pause_buffer_bytes,
+        pause_overflow: args.pause_overflow,
+        system_name: args.system_name,
+        max_body_len: args.max_body_len,
+        admin_token: args.admin_token,
+        summary_interval: args.summary_interval,
+        prune_subscriptions: args.prune_subscriptions,
+        reverse_dns: !args.no_reverse_dns,
+        recent_disconnects: args.recent_disconnects,
+        line_ending: args.line_ending,
+        shutdown_grace: args.shutdown_grace,
+        max_nodes_per_host: args.max_nodes_per_host,
+        host_limit_exempt: args.host_limit_exempt,
+        max_registration_len: args.max_registration_len,
+        registration_timeout: args.registration_timeout,
+        registration_timeout_violation: args.registration_timeout_violation,
+        autoban_threshold: args.autoban_threshold,
+        autoban_secs: args.autoban_secs,
+        max_connections: args.max_connections,
+        over_limit_policy: args.over_limit_policy,
+        evict_exempt: args.evict_exempt,
+        event_hop_limit: args.event_hop_limit,
+        tcp_keepalive: !args.no_tcp_keepalive,
+        keepalive_idle: args.keepalive_idle,
+        keepalive_interval: args.keepalive_interval,
+        keepalive_count: args.keepalive_count,
+        state_file: args.state_file,
+        pidfile: args.pidfile,
+        mirror: args.mirror,
     };
 
-    let (event_tx, event_rx) = mpsc::channel();
+    let (event_tx, event_rx) = events::channel();
+    // The sink takes the events first and passes them on to the visualization.
+    let event_rx = match &args.event_sink {
+        Some(uri) => {
+            let dest = uri.parse().unwrap_or_else(|err| exit_with_error(err));
+            let (forward_tx, forward_rx) = events::channel();
+            eventsink::spawn(dest, event_rx, Some(forward_tx));
+            forward_rx
+        }
+        None => event_rx,
+    };
 
+    #[cfg(feature = "gui")]
     if visualize {
-        // Spawn TCP server on background thread, run Bevy on main thread (macOS requirement)
-        thread::spawn(move || {
-            server::run_server(server_config, event_tx);
+        // Spawn TCP server on background thread, run Bevy on main thread (macOS requirement).
+        // The two end together: a server shutdown closes the window (ServerShutdown event),
+        // closing the window shuts the server down like the 'shutdown' command.
+        let server = server::Server::bind(server_config).unwrap_or_else(|err| exit_with_error(err));
+        let handle = server.handle();
+        let server_thread = std::thread::spawn(move || {
+            if let Err(err) = server.run(event_tx) {
+                exit_with_error(err);
+            }
         });
-        visualization::run_visualization(event_rx);
-    } else {
-        // Original behavior: run server on main thread, events are silently dropped
-        drop(event_rx);
-        server::run_server(server_config, event_tx);
+        visualization::run_visualization(event_rx, positions_file, layout_file);
+        handle.request_shutdown();
+        let _ = server_thread.join();
+        return;
+    }
+
+    // Original behavior: run server on main thread, events are silently dropped
+    drop(event_rx);
+    if let Err(err) = server::run_server(server_config, event_tx) {
+        exit_with_error(err);
     }
 }
+
+fn exit_with_error(err: GenericError) -> ! {
+    console::error(err);
+    process::exit(1);
+}
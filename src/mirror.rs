@@ -0,0 +1,96 @@
+// Tees the routed lines to a downstream listener (--mirror <host:port>), e.g. the
+// replica of a hot-standby dashboard. Write only: nothing is read from the mirror.
+// The lines pass a bounded queue, so routing never waits. A full queue or a mirror
+// which is down drops the lines with a counter, the connection is opened again with
+// a delay doubling up to MIRROR_MAX_BACKOFF.
+use std::{
+    io::Write,
+    net::{TcpStream, ToSocketAddrs},
+    sync::mpsc::{self, Receiver, SyncSender, TrySendError},
+    thread,
+    time::{Duration, Instant},
+};
+
+use crate::console;
+use crate::definitions::*;
+use crate::starserror::StarsError;
+use crate::stats::{MIRROR_DROPS, TrafficStats};
+
+const MIRROR_QUEUE_LEN: usize = 4096;
+const MIRROR_MIN_BACKOFF: Duration = Duration::from_millis(500);
+const MIRROR_MAX_BACKOFF: Duration = Duration::from_secs(30);
+const MIRROR_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+
+#[derive(Debug, Clone)]
+pub struct MirrorFeed {
+    tx: SyncSender<String>,
+}
+
+impl MirrorFeed {
+    pub fn send(&self, line: &str) {
+        match self.tx.try_send(line.to_string()) {
+            Ok(_) => {}
+            Err(TrySendError::Full(_)) => TrafficStats::add(&MIRROR_DROPS, 1),
+            // The mirror thread only ends when the feed is dropped.
+            Err(TrySendError::Disconnected(_)) => {}
+        }
+    }
+}
+
+// The thread ends with the last MirrorFeed, i.e. with the server.
+pub fn spawn(addr: String) -> MirrorFeed {
+    let (tx, rx) = mpsc::sync_channel(MIRROR_QUEUE_LEN);
+    thread::spawn(move || forward(&addr, rx));
+    MirrorFeed { tx }
+}
+
+fn connect(addr: &str) -> GenericResult<TcpStream> {
+    let sockaddr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or(StarsError::Message(format!("No address for {addr}")))?;
+    let stream = TcpStream::connect_timeout(&sockaddr, MIRROR_WRITE_TIMEOUT)?;
+    stream.set_write_timeout(Some(MIRROR_WRITE_TIMEOUT))?;
+    Ok(stream)
+}
+
+fn forward(addr: &str, rx: Receiver<String>) {
+    let mut stream: Option<TcpStream> = None;
+    let mut next_attempt = Instant::now();
+    let mut backoff = MIRROR_MIN_BACKOFF;
+    let mut dropped = 0_u64;
+    for line in rx {
+        if stream.is_none() && Instant::now() >= next_attempt {
+            match connect(addr) {
+                Ok(s) => {
+                    if dropped > 0 {
+                        let text = format!("Mirror {addr} available again, {dropped} dropped.");
+                        console::error(text);
+                        dropped = 0;
+                    }
+                    stream = Some(s);
+                    backoff = MIRROR_MIN_BACKOFF;
+                }
+                Err(err) => {
+                    if dropped == 0 {
+                        console::error(format!("Mirror {addr} unavailable, dropping lines: {err}"));
+                    }
+                    next_attempt = Instant::now() + backoff;
+                    backoff = (backoff * 2).min(MIRROR_MAX_BACKOFF);
+                }
+            }
+        }
+        let Some(s) = &mut stream else {
+            TrafficStats::add(&MIRROR_DROPS, 1);
+            dropped += 1;
+            continue;
+        };
+        if let Err(err) = s.write_all(line.as_bytes()) {
+            console::error(format!("Mirror {addr} unavailable, dropping lines: {err}"));
+            TrafficStats::add(&MIRROR_DROPS, 1);
+            dropped += 1;
+            stream = None;
+            next_attempt = Instant::now() + backoff;
+        }
+    }
+}
@@ -1,18 +1,33 @@
 use std::{
-    collections::HashSet,
-    io::prelude::*,
-    net::{Shutdown, SocketAddr, TcpListener, TcpStream},
-    process,
-    sync::{Arc, Mutex},
+    borrow::Cow,
+    collections::{HashSet, VecDeque},
+    io::{ErrorKind, prelude::*},
+    net::{IpAddr, Shutdown, SocketAddr, TcpListener, TcpStream},
+    sync::{
+        Arc, Mutex,
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+    },
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
+use chrono::{DateTime, Local};
+use clap::ValueEnum;
 use regex::Regex;
 
+use crate::console::{self, LogLevel, Style};
 use crate::definitions::*;
-use crate::events::{EventSender, ServerEvent};
-use crate::starsdata::StarsData;
+use crate::events::{self, EventSender, ServerEvent};
+use crate::mirror;
+use crate::starsdata::{
+    DisconnectReason, NodeInfo, NoticeResult, PausedMessage, PendingPing, StarsData,
+};
+use crate::starserror::{RecvError, StarsError};
+use crate::stats::{
+    ACCEPTS, INVALID_UTF8, IntervalStats, MIRROR_DROPS, PAUSE_DROPS, STATS, ThreadCount,
+    TrafficStats, ViolationStats,
+};
+use crate::syslog::{self, Severity};
 use crate::utilities::*;
 use crate::{dbprint, lazy_static};
 
@@ -23,287 +38,1024 @@ lazy_static! {
     static ref SEARCHCMD1: Regex = Regex::new(r"^[^@]").expect("Error parsing regex");
     static ref SEARCHCMD2: Regex = Regex::new(r"^[^_]").expect("Error parsing regex");
     static ref SEARCHCMD3: Regex = Regex::new(r"^[_@]").expect("Error parsing regex");
-    static ref SEARCHDISCONN: Regex = Regex::new(r"disconnect ").expect("Error parsing regex");
-    static ref SEARCHFLGON: Regex = Regex::new(r"flgon ").expect("Error parsing regex");
-    static ref SEARCHFLGOFF: Regex = Regex::new(r"flgoff ").expect("Error parsing regex");
-    static ref SEARCHSPLIT: Regex = Regex::new(r"\r*\n").expect("Error parsing regex");
     static ref SEARCHEXIT: Regex = Regex::new(r"(?i)^(exit|quit)").expect("Error parsing regex");
+    static ref SEARCHBINARY: Regex =
+        Regex::new(r"^(?:[a-zA-Z_0-9.\-]+>)?[a-zA-Z_0-9.\-]+\s+##(\d+)$")
+            .expect("Error parsing regex");
+    static ref SEARCHSYSNAME: Regex =
+        Regex::new(r"^[a-zA-Z_0-9\-]+$").expect("Error parsing regex");
     static ref SEARCHPARAM: Regex =
         Regex::new(r"^([a-zA-Z_0-9.\-]+)").expect("Error parsing regex");
 }
 
+/// Reply to a System command that is not known.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum UnknownCommand {
+    /// Reply with an error
+    Error,
+    /// Only log the command, don't reply
+    Ignore,
+    /// Reply with an error naming the closest command
+    Suggest,
+}
+
+/// Where routed messages get the server timestamp.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum Timestamps {
+    Off,
+    /// '{from}>{to} [{ts}] {body}'
+    Prefix,
+    /// '{from}>{to} {body} [{ts}]'
+    Append,
+}
+
+/// What happens to the messages between nodes while the routing is paused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PauseMode {
+    /// Reply 'Er: Routing paused.'
+    Reject,
+    /// Keep them (up to a limit) and route them on 'resume'
+    Buffer,
+}
+
+/// What a message does that does not fit into the pause buffer any more.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum PauseOverflow {
+    /// Reply 'Er: Routing paused.' to it
+    Reject,
+    /// Drop the oldest buffered messages to make room
+    DropOldest,
+    /// Drop the oldest messages of the node with the most buffered bytes to make room
+    DropBusiest,
+}
+
+/// Line ending of the lines sent by the server.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum LineEnding {
+    Lf,
+    /// For clients expecting '\r\n'
+    Crlf,
+}
+
+/// What a registration beyond max_connections does.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, ValueEnum)]
+pub enum OverLimitPolicy {
+    /// Reply 'Er: Too many connections.'
+    Reject,
+    /// Disconnect the node inactive for the longest time to make room
+    EvictOldest,
+}
+
 pub struct ServerConfig {
     pub port: u16,
     pub libdir: String,
     pub keydir: String,
+    /// Read timeout in msec of the registration, 0 is none (see registration_timeout). The
+    /// registered nodes are read without a timeout, an idle node stays connected.
     pub timeout: u64,
+    /// Reject messages to sub-nodes (node.sub) the node has not declared with 'subnodes='.
+    pub strict_subnodes: bool,
+    /// Reject received lines with invalid UTF-8 instead of replacing the invalid bytes.
+    pub strict_utf8: bool,
+    /// Log every message passing through sendmes with its disposition.
+    pub trace_messages: bool,
+    /// Time in msec a registration waits for the old connection of the same node to be
+    /// cleaned up before being rejected as duplicate. 0 disables the wait.
+    pub reconnect_grace: u64,
+    /// Replace the existing connection of a node at registration if it is found dead.
+    pub probe_dead_nodes: bool,
+    /// A node registering as 'Debugger' gets a copy of all messages, like in older versions.
+    /// Otherwise a node has to ask for it with 'becomedebugger'.
+    pub debugger_by_name: bool,
+    pub unknown_command: UnknownCommand,
+    pub timestamps: Timestamps,
+    /// chrono format of the timestamps.
+    pub timestamp_format: String,
+    /// Write timeout in msec for the node connections, 0 waits forever.
+    pub write_timeout: u64,
+    /// Retries of a write that hit the write timeout, before the connection is closed.
+    pub write_retries: u32,
+    /// Maximum length in bytes of a message body after the command word, 0 is unlimited.
+    /// Only messages delivered to a node are checked, System commands are not.
+    pub max_body_len: usize,
+    /// Messages between nodes while the routing is paused.
+    pub pause_mode: PauseMode,
+    /// Maximum bytes of the messages kept while paused, 0 only limits their number.
+    pub pause_buffer_bytes: usize,
+    pub pause_overflow: PauseOverflow,
+    /// Name of the pseudo node handling the server commands and sending the replies.
+    pub system_name: String,
+    /// Token the privileged commands must end with, in addition to the shutdown_allow.cfg check.
+    pub admin_token: Option<String>,
+    /// Seconds between the summary lines on the console, 0 prints none.
+    pub summary_interval: u64,
+    /// Remove the subscriptions to nodes which are not registered whenever a node disconnects.
+    /// Off by default, as a node may subscribe to another one before it connects.
+    pub prune_subscriptions: bool,
+    /// Look up the host names of the clients for the host lists, otherwise only ips match.
+    pub reverse_dns: bool,
+    /// Number of disconnects kept for 'recentdisconnects'.
+    pub recent_disconnects: usize,
+    pub line_ending: LineEnding,
+    /// Time in msec the nodes get at shutdown to close their connection after the notice.
+    pub shutdown_grace: u64,
+    /// Maximum number of nodes connected from one ip at the same time, 0 is unlimited.
+    pub max_nodes_per_host: usize,
+    /// Hosts not counted against max_nodes_per_host and never auto-banned.
+    pub host_limit_exempt: Vec<IpAddr>,
+    /// Maximum length in bytes of the registration line, a longer one closes the connection.
+    pub max_registration_len: usize,
+    /// Time in msec a connection has to send its registration line. 0 uses 'timeout'
+    /// (changed with 'settimeout'), and DEFAULT_REGISTRATION_TIMEOUT if that is 0 as well.
+    pub registration_timeout: u64,
+    /// A registration which times out counts as a protocol violation of its ip.
+    pub registration_timeout_violation: bool,
+    /// Protocol violations of one ip after which it is banned, 0 never bans.
+    pub autoban_threshold: u32,
+    /// Seconds an auto-banned ip is refused.
+    pub autoban_secs: u64,
+    /// Maximum number of connected nodes, 0 is unlimited.
+    pub max_connections: usize,
+    pub over_limit_policy: OverLimitPolicy,
+    /// Nodes never evicted, in addition to the ones in shutdown_allow.cfg.
+    pub evict_exempt: Vec<String>,
+    /// SO_KEEPALIVE on the node connections, so the kernel closes the ones to vanished peers.
+    pub tcp_keepalive: bool,
+    /// Seconds without traffic before the first keepalive probe.
+    pub keepalive_idle: u64,
+    /// Seconds between the keepalive probes.
+    pub keepalive_interval: u64,
+    /// Unanswered keepalive probes after which the connection is closed.
+    pub keepalive_count: u32,
+    /// Hops after which an event is dropped, 0 is unlimited. An event sent by a node within
+    /// EVENT_HOP_WINDOW of receiving one is one hop further than that.
+    pub event_hop_limit: u32,
+    /// File the subscriptions, command overrides and bans are saved to at shutdown and
+    /// loaded from at startup. None keeps no state.
+    pub state_file: Option<String>,
+    /// File the process id is written to once the listener is bound, removed at shutdown.
+    pub pidfile: Option<String>,
+    /// host:port the routed lines are copied to, write only. None mirrors nothing.
+    pub mirror: Option<String>,
 }
 
-pub fn run_server(config: ServerConfig, event_tx: EventSender) {
-    let tout: Option<Duration> = if config.timeout > 0_u64 {
-        Some(Duration::from_millis(config.timeout))
-    } else {
-        None
-    };
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            port: 6057,
+            libdir: DEFAULT_LIBDIR.to_string(),
+            keydir: DEFAULT_LIBDIR.to_string(),
+            timeout: READ_TIMEOUT,
+            strict_subnodes: false,
+            strict_utf8: false,
+            trace_messages: false,
+            reconnect_grace: 0,
+            probe_dead_nodes: false,
+            debugger_by_name: false,
+            unknown_command: UnknownCommand::Error,
+            timestamps: Timestamps::Off,
+            timestamp_format: DEFAULT_TIMESTAMP_FORMAT.to_string(),
+            write_timeout: 0,
+            write_retries: 0,
+            max_body_len: 0,
+            pause_mode: PauseMode::Reject,
+            pause_buffer_bytes: 0,
+            pause_overflow: PauseOverflow::Reject,
+            system_name: String::from("System"),
+            admin_token: None,
+            summary_interval: 0,
+            prune_subscriptions: false,
+            reverse_dns: true,
+            recent_disconnects: DEFAULT_RECENT_DISCONNECTS,
+            line_ending: LineEnding::Lf,
+            shutdown_grace: DEFAULT_SHUTDOWN_GRACE,
+            max_nodes_per_host: 0,
+            host_limit_exempt: Vec::new(),
+            max_registration_len: DEFAULT_MAX_REGISTRATION_LEN,
+            registration_timeout: 0,
+            registration_timeout_violation: false,
+            autoban_threshold: 0,
+            autoban_secs: DEFAULT_AUTOBAN_SECS,
+            max_connections: 0,
+            over_limit_policy: OverLimitPolicy::Reject,
+            evict_exempt: Vec::new(),
+            event_hop_limit: 0,
+            tcp_keepalive: true,
+            keepalive_idle: DEFAULT_KEEPALIVE_IDLE,
+            keepalive_interval: DEFAULT_KEEPALIVE_INTERVAL,
+            keepalive_count: DEFAULT_KEEPALIVE_COUNT,
+            state_file: None,
+            pidfile: None,
+            mirror: None,
+        }
+    }
+}
+
+/// A STARS server with loaded cfg files and a bound listener, ready to run.
+pub struct Server {
+    config: ServerConfig,
+    listener: TcpListener,
+    sdata: StarsData,
+    // Set at the end of system_shutdown, or by ServerHandle::stop. The accept loop ends.
+    shutdown: Arc<AtomicBool>,
+    // Set by ServerHandle::request_shutdown or 'shutdown', the accept loop runs system_shutdown.
+    shutdown_requested: Arc<AtomicBool>,
+}
 
-    let nodes: Arc<Mutex<NodeList>> = Arc::new(Mutex::new(NodeList::new()));
-    let sd: Arc<Mutex<StarsData>> = Arc::new(Mutex::new(StarsData::new(
-        &config.libdir,
-        &config.keydir,
-    )));
+/// Handle to stop a running server from another thread.
+#[cfg(any(test, feature = "gui"))]
+#[derive(Clone)]
+pub struct ServerHandle {
+    #[cfg(test)]
+    shutdown: Arc<AtomicBool>,
+    shutdown_requested: Arc<AtomicBool>,
+}
 
-    {
-        let mut sdata = sd.lock().expect("can't get the lock!");
-        startcheck(system_load_commandpermission(&mut sdata));
-        startcheck(system_load_aliases(&mut sdata));
-        startcheck(system_load_reconnecttable_permission(&mut sdata));
+#[cfg(any(test, feature = "gui"))]
+impl ServerHandle {
+    /// Ends the accept loop without notifying the nodes.
+    #[cfg(test)]
+    pub fn stop(&self) {
+        self.shutdown.store(true, Ordering::SeqCst);
+    }
+
+    /// Shuts the server down like the 'shutdown' command: the nodes get the notice and the
+    /// grace time, then the accept loop ends.
+    pub fn request_shutdown(&self) {
+        self.shutdown_requested.store(true, Ordering::SeqCst);
+    }
+}
+
+impl Server {
+    /// Loads the cfg files and binds the listener. Port 0 binds an OS assigned port.
+    pub fn bind(config: ServerConfig) -> GenericResult<Server> {
+        // A dot would make it a sub-node name, which never matches the routing check.
+        if !SEARCHSYSNAME.is_match(&config.system_name) {
+            return Err(GenericError::from(StarsError::InvalidSetting {
+                name: "system node name".to_string(),
+                value: config.system_name.clone(),
+            }));
+        }
+        let mut sdata = StarsData::new(&config.libdir, &config.keydir);
+        set_reverse_dns(config.reverse_dns);
+        sdata.timeout = config.timeout;
+        sdata.prune_subscriptions = config.prune_subscriptions;
+        let shutdown = Arc::new(AtomicBool::new(false));
+        let shutdown_requested = Arc::new(AtomicBool::new(false));
+        sdata.shutdown_requested = Arc::clone(&shutdown_requested);
+        sdata.recent_capacity = config.recent_disconnects;
+        sdata.mirror = config.mirror.clone().map(mirror::spawn);
+        startcheck(system_load_commandpermission(&mut sdata))?;
+        startcheck(system_load_aliases(&mut sdata))?;
+        startcheck(system_load_reconnecttable_permission(&mut sdata))?;
         system_load_shutdown_permission(&mut sdata);
+        // Without allow.cfg no host is allowed until it is loaded with 'loadhosts'.
+        let _ = system_load_hosts(&mut sdata);
+        if let Some(path) = &config.state_file {
+            startcheck(sdata.load_state(path))?;
+        }
+
+        if let Some(path) = &config.pidfile {
+            check_pidfile(path)?;
+        }
+
+        let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
+        let listener = match TcpListener::bind(addr) {
+            Ok(listener) => listener,
+            Err(err) => {
+                return Err(GenericError::from(StarsError::Bind {
+                    port: config.port,
+                    source: err,
+                }));
+            }
+        };
+        // The accept loop polls, so it can check the shutdown flag between connections.
+        listener.set_nonblocking(true)?;
+        if let Some(path) = &config.pidfile {
+            write_pidfile(path)?;
+        }
+        Ok(Server {
+            config,
+            listener,
+            sdata,
+            shutdown,
+            shutdown_requested,
+        })
     }
 
-    let addr = SocketAddr::from(([0, 0, 0, 0], config.port));
-    let listener = match TcpListener::bind(addr) {
-        Ok(listener) => listener,
-        Err(err) => {
-            panic!("{} {}", "ERROR: Can't create socket for listining! ", err);
+    #[cfg(test)]
+    pub fn local_addr(&self) -> SocketAddr {
+        self.listener
+            .local_addr()
+            .expect("listener has no local address!")
+    }
+
+    #[cfg(any(test, feature = "gui"))]
+    pub fn handle(&self) -> ServerHandle {
+        ServerHandle {
+            #[cfg(test)]
+            shutdown: Arc::clone(&self.shutdown),
+            shutdown_requested: Arc::clone(&self.shutdown_requested),
         }
-    };
+    }
 
-    println!("Server started. Time: {}", system_get_time());
-    println!();
+    /// Runs the accept loop until the server is stopped.
+    pub fn run(self, event_tx: EventSender) -> GenericResult<()> {
+        let config = Arc::new(self.config);
+        let listener = self.listener;
+        let mut node_list = NodeList::new();
+        node_list.crlf = config.line_ending == LineEnding::Crlf;
+        node_list.write_retries = config.write_retries;
+        let nodes: Arc<Mutex<NodeList>> = Arc::new(Mutex::new(node_list));
+        let handlers = Arc::clone(&self.sdata.handlers);
+        let violations = Arc::clone(&self.sdata.violation_stats);
+        let sd: Arc<Mutex<StarsData>> = Arc::new(Mutex::new(self.sdata));
 
-    loop {
-        match listener.accept() {
-            Ok((stream, _addr)) => {
-                let (host, ip) = system_get_hostname_or_ip(&stream);
-                dbprint!((&host, &ip));
-                if !system_check_host(HOST_LIST, &host, &ip, false, &config.libdir) {
-                    let errmsg = format!("Bad host. {host}\n");
-                    {
-                        let mut nodes_list = nodes.lock().expect("can't get the lock!");
-                        writemsg(
-                            &stream.try_clone().expect("stream clone failed!"),
-                            errmsg,
-                            &mut nodes_list,
-                        );
+        println!("Server started. Time: {}", system_get_time());
+        println!();
+
+        if config.summary_interval > 0 {
+            let nodes = Arc::clone(&nodes);
+            let handlers = Arc::clone(&handlers);
+            let shutdown = Arc::clone(&self.shutdown);
+            let interval = Duration::from_secs(config.summary_interval);
+            thread::spawn(move || print_summaries(interval, &nodes, &handlers, &shutdown));
+        }
+
+        loop {
+            if self.shutdown_requested.swap(false, Ordering::SeqCst) {
+                let notified = {
+                    let mut nodes_list = nodes.lock().expect("can't get the lock!");
+                    let sdata = sd.lock().expect("can't get the lock!");
+                    system_shutdown(&config.system_name, &mut nodes_list, &sdata, &config)
+                };
+                // Without the locks, so the handlers can remove the nodes which close.
+                close_after_grace(&nodes, notified, &config);
+                self.shutdown.store(true, Ordering::SeqCst);
+                events::notify(&event_tx, ServerEvent::ServerShutdown);
+            }
+            if self.shutdown.load(Ordering::SeqCst) {
+                break;
+            }
+            expire_pings(&nodes, &sd, &config);
+            if console::REOPEN_REQUESTED.swap(false, Ordering::Relaxed)
+                && let Err(err) = console::reopen_logfile()
+            {
+                console::error(format!("Can't reopen the log file: {err}"));
+            }
+            match listener.accept() {
+                Ok((stream, _addr)) => {
+                    TrafficStats::add(&ACCEPTS.accepted, 1);
+                    // Accepted sockets inherit the non-blocking mode on some platforms.
+                    if let Err(err) = stream.set_nonblocking(false) {
+                        console::error(format!("Couldn't set client blocking: {err:?}"));
+                        continue;
                     }
-                    stream
-                        .shutdown(Shutdown::Both)
-                        .expect("shutdown call failed")
-                } else {
-                    let nodekey = get_node_id_key();
-                    let msg = format!("{nodekey}\n");
-                    {
-                        let mut nodes_list = nodes.lock().expect("can't get the lock!");
-                        writemsg(
-                            &stream.try_clone().expect("stream clone failed!"),
-                            msg,
-                            &mut nodes_list,
-                        );
+                    if config.tcp_keepalive {
+                        enable_keepalive(&stream, &config);
                     }
-                    let rmsg = match recvmsg(
-                        stream.try_clone().expect("stream clone failed!"),
-                        "unknown",
-                        tout,
-                    ) {
-                        Ok(rmsg) => rmsg,
-                        Err(err) => {
-                            eprintln!("{err}");
-                            String::new()
-                        }
+                    let (host, ip) = system_get_hostname_or_ip(&stream);
+                    dbprint!((&host, &ip));
+                    let (allowed, banned) = {
+                        let mut sdata = sd.lock().expect("can't get the lock!");
+                        let banned = stream
+                            .peer_addr()
+                            .is_ok_and(|peer| sdata.is_banned(peer.ip(), Instant::now()));
+                        (check_host_list(&sdata.hosts, &host, &ip, false), banned)
                     };
-                    dbprint!(rmsg);
-                    if !rmsg.is_empty() {
-                        match addnode(
-                            stream.try_clone().expect("stream clone failed!"),
-                            rmsg.trim().to_string(),
-                            nodekey,
-                            &nodes,
-                            &mut sd.lock().expect("can't get the lock!"),
-                            &event_tx,
-                        ) {
-                            Some(node) => {
-                                let nodes = Arc::clone(&nodes);
-                                let sd = Arc::clone(&sd);
-                                let tx = event_tx.clone();
-                                thread::spawn(move || {
-                                    handle_node(
-                                        node,
-                                        stream.try_clone().expect("stream clone failed!"),
-                                        nodes,
-                                        sd,
-                                        tx,
-                                    );
-                                });
-                                continue;
+                    if banned {
+                        TrafficStats::add(&ACCEPTS.rejected_badhost, 1);
+                        log_rejection("-", &stream, "Banned host.", &event_tx);
+                        let _ = stream.shutdown(Shutdown::Both);
+                    } else if !allowed {
+                        TrafficStats::add(&ACCEPTS.rejected_badhost, 1);
+                        log_rejection("-", &stream, "Bad host.", &event_tx);
+                        let errmsg = format!("Bad host. {host}\n");
+                        {
+                            let mut nodes_list = nodes.lock().expect("can't get the lock!");
+                            writemsg(
+                                &stream.try_clone().expect("stream clone failed!"),
+                                errmsg,
+                                &mut nodes_list,
+                            );
+                        }
+                        // The client may have gone already.
+                        let _ = stream.shutdown(Shutdown::Both);
+                    } else {
+                        let nodekey = get_node_id_key();
+                        let msg = format!("{nodekey}\n");
+                        {
+                            let mut nodes_list = nodes.lock().expect("can't get the lock!");
+                            writemsg(
+                                &stream.try_clone().expect("stream clone failed!"),
+                                msg,
+                                &mut nodes_list,
+                            );
+                        }
+                        // The timeout can be changed with 'settimeout' while running.
+                        // An unregistered connection never waits without a limit.
+                        let settimeout = || sd.lock().expect("can't get the lock!").timeout;
+                        let tout = msec_timeout(config.registration_timeout)
+                            .or_else(|| msec_timeout(settimeout()))
+                            .unwrap_or(Duration::from_millis(DEFAULT_REGISTRATION_TIMEOUT));
+                        let maxlen = config.max_registration_len;
+                        let rmsg = match recv_registration(&stream, tout, maxlen, &violations) {
+                            Registration::Line(rmsg) => rmsg,
+                            Registration::TooLong => {
+                                let reason = "Registration too long.";
+                                TrafficStats::add(&ACCEPTS.rejected_badkey, 1);
+                                TrafficStats::add(&violations.registration_too_long, 1);
+                                let mut sdata = sd.lock().expect("can't get the lock!");
+                                protocol_violation(&stream, &mut sdata, reason, &config);
+                                drop(sdata);
+                                reject_registration(&stream, &nodes, &event_tx, &config, reason);
+                                String::new()
                             }
-                            None => {
-                                match stream.shutdown(Shutdown::Both) {
-                                    Ok(_) => {}
-                                    Err(_) => {
-                                        eprintln!("shutdown call failed");
-                                    }
+                            Registration::TimedOut => {
+                                let reason = "Registration timed out.";
+                                TrafficStats::add(&ACCEPTS.registration_timeouts, 1);
+                                if config.registration_timeout_violation {
+                                    let mut sdata = sd.lock().expect("can't get the lock!");
+                                    protocol_violation(&stream, &mut sdata, reason, &config);
                                 }
-                                continue;
+                                reject_registration(&stream, &nodes, &event_tx, &config, reason);
+                                String::new()
                             }
+                            Registration::Failed(err) => {
+                                console::error(&err);
+                                String::new()
+                            }
+                        };
+                        dbprint!(rmsg);
+                        let name = rmsg.split_whitespace().next().unwrap_or_default();
+                        let registered = !name.is_empty()
+                            && nodes
+                                .lock()
+                                .expect("can't get the lock!")
+                                .contains_key(name);
+                        if registered && config.reconnect_grace > 0 {
+                            // The grace wait would hold up the other connections here.
+                            let nodes = Arc::clone(&nodes);
+                            let sd = Arc::clone(&sd);
+                            let tx = event_tx.clone();
+                            let config = Arc::clone(&config);
+                            let handlers = Arc::clone(&handlers);
+                            thread::spawn(move || {
+                                let name = rmsg.split_whitespace().next().unwrap_or_default();
+                                wait_node_released(&nodes, name, config.reconnect_grace);
+                                register_node(
+                                    stream, rmsg, nodekey, &nodes, &sd, &tx, &config, &handlers,
+                                );
+                            });
+                            continue;
                         }
-                    }
-                    match stream.shutdown(Shutdown::Both) {
-                        Ok(_) => {}
-                        Err(_) => {
-                            eprintln!("shutdown call failed");
+                        if !rmsg.is_empty() {
+                            register_node(
+                                stream, rmsg, nodekey, &nodes, &sd, &event_tx, &config, &handlers,
+                            );
+                            continue;
+                        }
+                        match stream.shutdown(Shutdown::Both) {
+                            Ok(_) => {}
+                            Err(_) => {
+                                console::error("shutdown call failed");
+                            }
                         }
+                        continue;
                     }
-                    continue;
+                }
+                Err(err) if err.kind() == ErrorKind::WouldBlock => {
+                    thread::sleep(ACCEPT_POLL_INTERVAL);
+                }
+                Err(err) => {
+                    console::error(format!("Couldn't get client: {err:?}"));
                 }
             }
-            Err(err) => {
-                eprintln!("Couldn't get client: {err:?}");
-            }
         }
+        if let Some(path) = &config.pidfile
+            && let Err(err) = std::fs::remove_file(path)
+        {
+            console::error(format!("Can't remove the PID file {path}: {err}"));
+        }
+        Ok(())
+    }
+}
+
+// A missing socket option fails the same way for every connection, so it is logged once.
+fn enable_keepalive(stream: &TcpStream, config: &ServerConfig) {
+    static LOGGED: AtomicBool = AtomicBool::new(false);
+    let idle = Duration::from_secs(config.keepalive_idle);
+    let interval = Duration::from_secs(config.keepalive_interval);
+    if let Err(err) = set_tcp_keepalive(stream, idle, interval, config.keepalive_count)
+        && !LOGGED.swap(true, Ordering::Relaxed)
+    {
+        console::error(format!("Can't enable TCP keepalive: {err}"));
+    }
+}
+
+// Answers the 'pingnode' callers whose target did not echo the probe in time.
+fn expire_pings(nodes: &Mutex<NodeList>, sdata: &Mutex<StarsData>, config: &ServerConfig) {
+    if sdata.lock().expect("can't get the lock!").pings.is_empty() {
+        return;
+    }
+    let mut nodes_list = nodes.lock().expect("can't get the lock!");
+    let mut sd = sdata.lock().expect("can't get the lock!");
+    let now = Instant::now();
+    let expired: Vec<u64> = sd
+        .pings
+        .iter()
+        .filter(|(_, ping)| ping.deadline <= now)
+        .map(|(token, _)| *token)
+        .collect();
+    for token in expired {
+        let Some(ping) = sd.pings.remove(&token) else {
+            continue;
+        };
+        let msecs = ping.deadline.duration_since(ping.sent).as_millis();
+        let result = format!("Er: No reply from {} within {msecs} msec.", ping.target);
+        answer_ping(&ping, &result, &mut nodes_list, config);
+    }
+}
+
+fn answer_ping(
+    ping: &PendingPing,
+    result: &str,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    config: &ServerConfig,
+) {
+    let Some(stream) = nodes
+        .get(&ping.caller_node)
+        .and_then(|s| s.try_clone().ok())
+    else {
+        return;
+    };
+    let sys = config.system_name.as_str();
+    let msg = format!("{sys}>{} @pingnode {} {result}\n", ping.caller, ping.target);
+    writemsg(&stream, msg, nodes);
+}
+
+// Prints the summary line every interval until the server is stopped. The numbers come
+// from the atomic counters, the node list is only read if its lock is free.
+fn print_summaries(
+    interval: Duration,
+    nodes: &Mutex<NodeList>,
+    handlers: &AtomicUsize,
+    shutdown: &AtomicBool,
+) {
+    let mut stats = IntervalStats::new(TrafficStats::get(&STATS.messages_out), Instant::now());
+    let mut node_count = 0;
+    let mut next = Instant::now() + interval;
+    while !shutdown.load(Ordering::SeqCst) {
+        thread::sleep(ACCEPT_POLL_INTERVAL);
+        let now = Instant::now();
+        if now < next {
+            continue;
+        }
+        next = now + interval;
+        if let Ok(nodes) = nodes.try_lock() {
+            node_count = nodes.len();
+        }
+        let (routed, rate) = stats.update(TrafficStats::get(&STATS.messages_out), now);
+        let threads = handlers.load(Ordering::Relaxed);
+        console::print(
+            Style::Summary,
+            format!(
+                "[SUMMARY] nodes={node_count} routed={routed} rate={rate:.1}/s threads={threads}"
+            ),
+        );
     }
 }
 
+pub fn run_server(config: ServerConfig, event_tx: EventSender) -> GenericResult<()> {
+    Server::bind(config)?.run(event_tx)
+}
+
+// A received message: the text line and, for binary frames, the bytes following it.
+struct Frame<'a> {
+    line: &'a str,
+    // The line with the '\r' at its end, as received (for 'rawecho').
+    raw: &'a str,
+    body: Option<&'a [u8]>,
+}
+
 fn handle_node(
     node: String,
     stream: TcpStream,
     nodes: Arc<Mutex<NodeList>>,
     sd: Arc<Mutex<StarsData>>,
     event_tx: EventSender,
+    config: Arc<ServerConfig>,
 ) {
-    let mut savebuf = String::new();
+    let sys = config.system_name.as_str();
+    let sdata = sd.lock().expect("can't get the lock!");
+    let violations = Arc::clone(&sdata.violation_stats);
+    let (traffic, binary) = match sdata.nodeinfo.get(&node) {
+        Some(info) => (Arc::clone(&info.traffic), info.binary),
+        None => (Arc::new(TrafficStats::default()), false),
+    };
+    drop(sdata);
+    // Received data not handled yet. Lines are split on bytes, so a multi byte character
+    // or a binary body cut by the read is kept whole until the rest arrives.
+    let mut savebuf = Vec::new();
+    let mut reason = DisconnectReason::Exit;
     'main: loop {
-        let mut rmsg = match recvmsg(
-            stream.try_clone().expect("stream clone failed!"),
-            &node,
-            None,
-        ) {
-            Ok(data) => data,
+        match recvchunk(&stream, &node, &traffic) {
+            Ok(data) => savebuf.extend_from_slice(&data),
             Err(err) => {
-                eprintln!("{err}");
+                console::error(&err);
+                reason = match err.downcast_ref::<RecvError>() {
+                    Some(RecvError::PeerClosed { .. }) => DisconnectReason::PeerClosed,
+                    _ => DisconnectReason::ReadError,
+                };
                 break 'main;
             }
         };
-        if !savebuf.is_empty() {
-            rmsg = format!("{savebuf}{rmsg}");
-            savebuf.clear();
-        }
-        if !rmsg.is_empty() {
-            let mut m: Vec<_> = SEARCHSPLIT.split(&rmsg).collect();
-            if let Some(pos) = m.iter().position(|x| x.is_empty()) {
-                m.remove(pos);
-            } else if let Some(data) = m.pop() {
-                savebuf = data.to_string();
-            }
-            for buf in m {
-                if SEARCHEXIT.is_match(buf) {
-                    break 'main;
-                } else {
-                    sendmes(
-                        &node,
-                        &stream,
-                        buf,
-                        &mut nodes.lock().expect("can't get the lock!"),
-                        &sd,
-                        &event_tx,
-                    );
-                }
+        while let Some(end) = savebuf.iter().position(|b| *b == b'\n') {
+            let Some(raw) = decode_line(&savebuf[..end], config.strict_utf8, &violations) else {
+                savebuf.drain(..=end);
+                let msg = format!("{sys}>{node} Er: Invalid UTF-8.\n");
+                writemsg(
+                    &stream,
+                    msg,
+                    &mut nodes.lock().expect("can't get the lock!"),
+                );
+                continue;
+            };
+            let buf = raw.trim_end_matches('\r');
+            // Binary frame: '[from>]to ##<length>' followed by exactly <length> bytes.
+            let bodylen = match SEARCHBINARY.captures(buf).filter(|_| binary) {
+                Some(caps) => match caps[1].parse::<usize>() {
+                    Ok(len) if len <= MAX_FRAME_LEN => Some(len),
+                    _ => {
+                        TrafficStats::add(&violations.frame_too_large, 1);
+                        let msg = format!("{sys}>{node} Er: Binary frame is too large.\n");
+                        writemsg(
+                            &stream,
+                            msg,
+                            &mut nodes.lock().expect("can't get the lock!"),
+                        );
+                        let mut sdata = sd.lock().expect("can't get the lock!");
+                        protocol_violation(&stream, &mut sdata, "Binary frame too large.", &config);
+                        reason = DisconnectReason::Protocol;
+                        break 'main;
+                    }
+                },
+                None => None,
+            };
+            let framelen = end + 1 + bodylen.unwrap_or(0);
+            if savebuf.len() < framelen {
+                break;
+            }
+            let body: Vec<u8> = savebuf.drain(..framelen).skip(end + 1).collect();
+            if SEARCHEXIT.is_match(buf) {
+                break 'main;
+            } else {
+                TrafficStats::add(&traffic.messages_in, 1);
+                TrafficStats::add(&STATS.messages_in, 1);
+                let frame = Frame {
+                    line: buf,
+                    raw: &raw,
+                    body: bodylen.map(|_| body.as_slice()),
+                };
+                sendmes(
+                    &node,
+                    &stream,
+                    &frame,
+                    &mut nodes.lock().expect("can't get the lock!"),
+                    &sd,
+                    &event_tx,
+                    &config,
+                );
             }
-        } else {
-            break 'main;
         }
     }
     {
         let mut nodes_list = nodes.lock().expect("can't get the lock!");
         let mut sdata = sd.lock().expect("can't get the lock!");
-        delnode(&node, &mut nodes_list, &mut sdata, &event_tx);
+        delnode(&node, &mut nodes_list, &mut sdata, &event_tx, reason);
     }
 }
 
 fn writemsg(stream: &TcpStream, msg: String, nodes: &mut std::sync::MutexGuard<'_, NodeList>) {
     dbprint!(msg);
-    sendtonode(stream, &msg);
+    sendtonode(stream, &msg, nodes.crlf, nodes.write_retries);
     sendtodebugger(&msg, nodes);
 }
 
-fn recvmsg(mut stream: TcpStream, name: &str, timeout: Option<Duration>) -> GenericResult<String> {
-    match stream.set_read_timeout(timeout) {
-        Ok(_) => {}
-        Err(err) => {
-            return Err(GenericError::from(crate::starserror::StarsError {
-                message: format!("Set timeout faild! {err}."),
-            }));
-        }
-    }
+// Outcome of reading the registration line '<node> <key>' after the node key was sent.
+enum Registration {
+    Line(String),
+    // No complete line within the timeout, e.g. a client stalling before the newline.
+    TimedOut,
+    // More than max_registration_len bytes before the newline, nothing more is read.
+    TooLong,
+    Failed(GenericError),
+}
 
+// The timeout applies to the whole line, a client trickling bytes does not extend it.
+// Data without newline is taken as the line only if the client closes after it.
+fn recv_registration(
+    stream: &TcpStream,
+    timeout: Duration,
+    maxlen: usize,
+    violations: &ViolationStats,
+) -> Registration {
+    let deadline = Instant::now() + timeout;
+    let mut reader = stream;
     let mut datamsg = Vec::new();
     let mut datapiece: [u8; TCP_BUFFER_SIZE] = [0u8; TCP_BUFFER_SIZE];
     loop {
-        match stream.read(&mut datapiece) {
+        let remaining = match deadline.checked_duration_since(Instant::now()) {
+            Some(remaining) if !remaining.is_zero() => remaining,
+            _ => return Registration::TimedOut,
+        };
+        if let Err(err) = stream.set_read_timeout(Some(remaining)) {
+            return Registration::Failed(GenericError::from(StarsError::Message(format!(
+                "Set timeout faild! {err}."
+            ))));
+        }
+        match reader.read(&mut datapiece) {
+            Ok(0) if datamsg.is_empty() => {
+                return Registration::Failed(GenericError::from(RecvError::PeerClosed {
+                    name: "unknown".to_string(),
+                }));
+            }
             Ok(0) => break,
             Ok(datacount) => {
+                TrafficStats::add(&STATS.bytes_in, datacount as u64);
                 datamsg.extend_from_slice(&datapiece[..datacount]);
-                if datapiece[..datacount].contains(&b'\n') {
+                let end = datamsg.iter().position(|b| *b == b'\n');
+                if trim_cr(&datamsg[..end.unwrap_or(datamsg.len())]).len() > maxlen {
+                    return Registration::TooLong;
+                }
+                if end.is_some() {
                     break;
                 }
             }
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {
+                return Registration::TimedOut;
+            }
             Err(err) => {
-                eprintln!("Error reading from client ({name}): {err}");
-                break;
+                return Registration::Failed(GenericError::from(RecvError::IoError {
+                    name: "unknown".to_string(),
+                    source: err,
+                }));
             }
         }
     }
-    let msg = String::from_utf8_lossy(&datamsg).to_string();
+    Registration::Line(decode_line(&datamsg, false, violations).unwrap_or_default())
+}
+
+// Oversized data, and with --registration-timeout-violation a registration which timed
+// out, is logged and counted by the ip of the sender. With --autoban-threshold
+// the ip is refused for autoban_secs once it has reached the threshold.
+fn protocol_violation(
+    stream: &TcpStream,
+    sdata: &mut StarsData,
+    what: &str,
+    config: &ServerConfig,
+) {
+    TrafficStats::add(&ACCEPTS.protocol_violations, 1);
+    let Ok(peer) = stream.peer_addr() else {
+        return;
+    };
+    let ip = peer.ip();
+    let count = sdata.add_violation(ip);
+    console::warn(format!("Protocol violation {count} from {ip}: {what}"));
+    syslog::log(Severity::Warning, "violation", "-", Some(ip), what);
+    if config.autoban_threshold == 0
+        || count < config.autoban_threshold
+        || config.host_limit_exempt.contains(&ip)
+    {
+        return;
+    }
+    let duration = Duration::from_secs(config.autoban_secs);
+    sdata.violations.remove(&ip);
+    sdata.banned.insert(ip, Instant::now() + duration);
+    let text = format!(
+        "Banned for {} s after {count} protocol violations.",
+        config.autoban_secs
+    );
+    console::warn(format!("{ip} {text}"));
+    syslog::log(Severity::Warning, "ban", "-", Some(ip), &text);
+}
+
+// For a registration line which could not be read, the connection is closed by the caller.
+fn reject_registration(
+    stream: &TcpStream,
+    nodes: &Arc<Mutex<NodeList>>,
+    event_tx: &EventSender,
+    config: &ServerConfig,
+    reason: &str,
+) {
+    log_rejection("-", stream, reason, event_tx);
+    let peer = stream
+        .peer_addr()
+        .map_or_else(|_| "-".to_string(), |addr| addr.to_string());
+    console::warn(format!("{} ({peer})", reason.trim_end_matches('.')));
+    let errmsg = format!("{}> Er: {reason}\n", config.system_name);
+    writemsg(
+        stream,
+        errmsg,
+        &mut nodes.lock().expect("can't get the lock!"),
+    );
+}
+
+// Strips the line ending carriage returns.
+fn trim_cr(line: &[u8]) -> &[u8] {
+    let end = line
+        .iter()
+        .rposition(|b| *b != b'\r')
+        .map_or(0, |pos| pos + 1);
+    &line[..end]
+}
+
+// Reads whatever data is available, at least one byte.
+fn recvchunk(stream: &TcpStream, name: &str, traffic: &TrafficStats) -> GenericResult<Vec<u8>> {
+    let mut reader = stream;
+    let mut datapiece: [u8; TCP_BUFFER_SIZE] = [0u8; TCP_BUFFER_SIZE];
+    match reader.read(&mut datapiece) {
+        Ok(0) => Err(GenericError::from(RecvError::PeerClosed {
+            name: name.to_string(),
+        })),
+        Ok(datacount) => {
+            TrafficStats::add(&STATS.bytes_in, datacount as u64);
+            TrafficStats::add(&traffic.bytes_in, datacount as u64);
+            Ok(datapiece[..datacount].to_vec())
+        }
+        Err(err) => Err(GenericError::from(RecvError::IoError {
+            name: name.to_string(),
+            source: err,
+        })),
+    }
+}
+
+// Decodes a received line. Invalid UTF-8 is replaced with U+FFFD and counted,
+// in strict mode None is returned instead.
+fn decode_line(data: &[u8], strict: bool, violations: &ViolationStats) -> Option<String> {
+    match std::str::from_utf8(data) {
+        Ok(s) => Some(s.to_string()),
+        Err(_) if strict => {
+            TrafficStats::add(&violations.invalid_utf8, 1);
+            None
+        }
+        Err(_) => {
+            TrafficStats::add(&INVALID_UTF8, 1);
+            TrafficStats::add(&violations.invalid_utf8, 1);
+            Some(String::from_utf8_lossy(data).into_owned())
+        }
+    }
+}
+
+// Returns false if the write failed, the connection is closed then.
+fn sendtonode(stream: &TcpStream, msg: &str, crlf: bool, retries: u32) -> bool {
+    sendbytes(stream, line_ending(msg, crlf).as_bytes(), retries)
+}
 
-    if msg.is_empty() {
-        Err(GenericError::from(crate::starserror::StarsError {
-            message: format!("({name}) Connection lost!"),
-        }))
+// All text sent by the server passes here. The lines are built with '\n', a message
+// never contains a line break of its own.
+fn line_ending(msg: &str, crlf: bool) -> Cow<'_, str> {
+    if crlf {
+        Cow::Owned(msg.replace('\n', "\r\n"))
     } else {
-        Ok(msg)
+        Cow::Borrowed(msg)
     }
 }
 
-fn sendtonode(stream: &TcpStream, msg: &String) {
-    let mut writer = stream;
-    match writer.write(msg.as_bytes()) {
-        Ok(_success) => {}
+fn sendbytes(stream: &TcpStream, data: &[u8], retries: u32) -> bool {
+    match write_with_retry(stream, data, retries) {
+        Ok(_) => {
+            TrafficStats::add(&STATS.bytes_out, data.len() as u64);
+            true
+        }
         Err(err) => {
-            eprintln!("Write Error: {err:?}");
-            writer
-                .shutdown(Shutdown::Both)
-                .expect("shutdown call failed");
+            log_write_error(&err);
+            if closes_connection(&err) {
+                // The peer may have gone already, the reader of the node cleans up then.
+                let _ = stream.shutdown(Shutdown::Both);
+            }
+            false
+        }
+    }
+}
+
+// Retries interrupted writes, and writes that hit the write timeout up to --write-retries
+// times. A receiver still stuck after that gets the error, and its connection is closed.
+fn write_with_retry(stream: &TcpStream, data: &[u8], max_retries: u32) -> std::io::Result<()> {
+    let mut writer = stream;
+    let mut written = 0;
+    let mut retries = 0;
+    while written < data.len() {
+        match writer.write(&data[written..]) {
+            Ok(0) => return Err(ErrorKind::WriteZero.into()),
+            Ok(n) => written += n,
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err)
+                if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut)
+                    && retries < max_retries =>
+            {
+                retries += 1;
+                console::print(
+                    Style::Debug,
+                    format!("Write timeout, retry {retries}: {err:?}"),
+                );
+            }
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(())
+}
+
+// A stuck receiver hits the write timeout, its connection is closed like on any write error.
+// A peer which is gone, or still stuck after the retries, loses the connection. Other
+// write errors leave it to the reader of the node.
+fn closes_connection(err: &std::io::Error) -> bool {
+    matches!(
+        err.kind(),
+        ErrorKind::BrokenPipe
+            | ErrorKind::ConnectionReset
+            | ErrorKind::ConnectionAborted
+            | ErrorKind::WouldBlock
+            | ErrorKind::TimedOut
+    )
+}
+
+fn log_write_error(err: &std::io::Error) {
+    match err.kind() {
+        ErrorKind::WouldBlock | ErrorKind::TimedOut => {
+            console::error(format!("Write timeout: {err:?}"))
         }
+        _ => console::error(format!("Write Error: {err:?}")),
     }
 }
 
-fn sendtodebugger(msg: &String, nodes: &mut NodeList) {
-    if let Some(stream) = nodes.get("Debugger") {
-        let mut writer = stream;
-        match writer.write(msg.as_bytes()) {
-            Ok(_success) => {}
+fn sendtodebugger(msg: &str, nodes: &mut NodeList) {
+    let Some(debugger) = nodes.debugger.clone() else {
+        return;
+    };
+    let msg = match &nodes.debug_filter {
+        Some(filter) => match filter.apply(msg) {
+            Some(tagged) => tagged,
+            None => return,
+        },
+        None => msg.to_string(),
+    };
+    sendcopy(&debugger, &msg, nodes);
+}
+
+// Copy of a message for a monitoring node. A node failing to take it is removed,
+// its handler cleans up when the connection is closed.
+fn sendcopy(name: &str, msg: &str, nodes: &mut NodeList) {
+    if let Some(stream) = nodes.get(name) {
+        let msg = line_ending(msg, nodes.crlf);
+        match write_with_retry(stream, msg.as_bytes(), nodes.write_retries) {
+            Ok(_) => TrafficStats::add(&STATS.bytes_out, msg.len() as u64),
             Err(err) => {
-                eprintln!("Write Error: {err:?}");
-                match writer.shutdown(Shutdown::Both) {
+                log_write_error(&err);
+                match stream.shutdown(Shutdown::Both) {
                     Ok(_) => {}
                     Err(err) => {
-                        eprintln!("Shutdown call failed (Debugger): {err}");
+                        console::error(format!("Shutdown call failed ({name}): {err}"));
                     }
                 }
-                nodes.remove("Debugger");
+                nodes.remove(name);
             }
         }
     }
 }
 
+// Copies a routed message to the nodes watching its sender or receiver, except to
+// the receiver itself.
+fn sendtowatchers(from: &str, to: &str, msg: &str, nodes: &mut NodeList) {
+    let receiver = to.split('.').next().unwrap_or(to);
+    let targets: Vec<String> = nodes
+        .watchers
+        .iter()
+        .filter(|(watcher, patterns)| {
+            *watcher != receiver
+                && patterns
+                    .iter()
+                    .any(|(_, re)| re.is_match(from) || re.is_match(to))
+        })
+        .map(|(watcher, _)| watcher.clone())
+        .collect();
+    for watcher in targets {
+        sendcopy(&watcher, msg, nodes);
+    }
+}
+
 #[allow(unused_assignments)]
 fn sendmes(
     node: &str,
     stream: &TcpStream,
-    msg: &str,
+    frame: &Frame,
     nodes: &mut std::sync::MutexGuard<'_, NodeList>,
     sdata: &Arc<Mutex<StarsData>>,
     event_tx: &EventSender,
+    config: &ServerConfig,
 ) {
+    let sys = config.system_name.as_str();
+    let msg = frame.line;
     let fromnodes = node.to_string();
     let mut fromnode = fromnodes.clone();
     let mut tonodes = String::new();
@@ -318,7 +1070,8 @@ fn sendmes(
     }
     match SEARCHTO.captures(&buf) {
         None => {
-            let msg = format!("System>{fromnode}> @\n");
+            trace_message(config, &fromnode, "", &buf, "no destination");
+            let msg = format!("{sys}>{fromnode}> @\n");
             writemsg(stream, msg, nodes);
             return;
         }
@@ -328,293 +1081,2330 @@ fn sendmes(
         }
     }
     let mut sd: std::sync::MutexGuard<'_, StarsData> = sdata.lock().expect("can't get the lock!");
-    if let Some(to) = sd.aliasreal.get(&tonodes) {
-        tonodes = to.to_string();
-    }
-    if SEARCHCMD1.is_match(&buf)
-        && ((!sd.cmddeny.is_empty()
-            && is_deny_checkcmd_deny(&fromnodes, &tonodes, &buf, &sd.cmddeny))
-            || (!sd.cmdallow.is_empty()
-                && is_deny_checkcmd_allow(&fromnodes, &tonodes, &buf, &sd.cmdallow)))
-    {
+    if let Some(info) = sd.nodeinfo.get_mut(node) {
+        info.last_active = Some(Instant::now());
+    }
+    let (route, resolved) = resolve_route(
+        node,
+        &tonodes,
+        &buf,
+        frame.body.is_some(),
+        nodes,
+        &sd,
+        config,
+    );
+    tonodes = resolved;
+    if route == Route::Denied {
+        trace_message(config, &fromnode, &tonodes, &buf, "denied");
+        let command = buf.split(' ').next().unwrap_or_default().to_string();
+        events::notify(
+            event_tx,
+            ServerEvent::CommandDenied {
+                node: fromnodes,
+                command,
+            },
+        );
         if SEARCHCMD2.is_match(&buf) {
-            let msg = format!("System>{fromnode} @{buf} Er: Command denied.\n");
+            let msg = format!("{sys}>{fromnode} @{buf} Er: Command denied.\n");
             writemsg(stream, msg, nodes);
         }
         return;
     }
     tonode = (tonodes.split(".").map(str::to_string).collect::<Vec<_>>())[0].clone();
-    if tonode.contains("System") {
-        system_commands(node, stream, &fromnode, &buf, &mut sd, nodes);
+    if route == Route::System {
+        trace_message(config, &fromnode, &tonodes, &buf, "system");
+        system_commands(
+            node, stream, &fromnode, &buf, frame.raw, &mut sd, nodes, event_tx, config,
+        );
+        let replay = std::mem::take(&mut sd.replay);
+        drop(sd);
+        replay_paused(replay, nodes, sdata, event_tx, config);
+        return;
+    }
+    if route == Route::Paused {
+        trace_message(config, &fromnode, &tonodes, &buf, "paused");
+        let paused = PausedMessage {
+            node: node.to_string(),
+            line: msg.to_string(),
+            body: frame.body.map(<[u8]>::to_vec),
+        };
+        let kept = config.pause_mode == PauseMode::Buffer && buffer_paused(&mut sd, paused, config);
+        if !kept && !SEARCHCMD3.is_match(&buf) {
+            let msg = format!("{sys}>{fromnode} Er: Routing paused.\n");
+            writemsg(stream, msg, nodes);
+        }
+        return;
+    }
+    if route == Route::Muted {
+        trace_message(config, &fromnode, &tonodes, &buf, "muted");
+        if !SEARCHCMD3.is_match(&buf) {
+            let msg = format!("{sys}>{fromnode} @{buf} Er: You are muted.\n");
+            writemsg(stream, msg, nodes);
+        }
         return;
     }
     if let Some(from) = sd.aliasreal.get(&fromnode) {
         fromnode = from.to_string();
     }
-    match nodes.get(&tonode) {
-        Some(sock) => {
-            let msg = format!("{fromnode}>{tonodes} {buf}\n");
+    match route {
+        Route::NotHandled => {
+            trace_message(config, &fromnode, &tonodes, &buf, "not handled");
+            if !SEARCHCMD3.is_match(&buf) {
+                let msg =
+                    format!("{sys}>{fromnode} @{buf} Er: {tonodes} is not handled by {tonode}.\n");
+                writemsg(stream, msg, nodes);
+            }
+        }
+        Route::Draining => {
+            trace_message(config, &fromnode, &tonodes, &buf, "draining");
+            if !SEARCHCMD3.is_match(&buf) {
+                let msg = format!("{sys}>{fromnode} @{buf} Er: {tonodes} is draining.\n");
+                writemsg(stream, msg, nodes);
+            }
+        }
+        Route::NotBinary => {
+            trace_message(config, &fromnode, &tonodes, &buf, "not binary");
+            let msg =
+                format!("{sys}>{fromnode} @{buf} Er: {tonode} does not accept binary data.\n");
+            writemsg(stream, msg, nodes);
+        }
+        Route::Deliver => {
+            // The body is what follows the command word, with the data of a binary frame.
+            let body_len = buf.split_once(' ').map_or(0, |(_, args)| args.len())
+                + frame.body.map_or(0, <[u8]>::len);
+            if config.max_body_len > 0 && body_len > config.max_body_len {
+                trace_message(config, &fromnode, &tonodes, "", "too large");
+                let cmd =
+                    truncate_str(buf.split(' ').next().unwrap_or_default(), MAX_REPLY_CMD_LEN);
+                let msg = format!("{sys}>{fromnode} @{cmd} Er: Message too large.\n");
+                writemsg(stream, msg, nodes);
+                TrafficStats::add(&sd.violation_stats.message_too_large, 1);
+                return;
+            }
+            trace_message(config, &fromnode, &tonodes, &buf, "delivered");
+            let msg = match config.timestamps {
+                // Binary frame headers must end with the length.
+                _ if frame.body.is_some() => format!("{fromnode}>{tonodes} {buf}\n"),
+                Timestamps::Off => format!("{fromnode}>{tonodes} {buf}\n"),
+                Timestamps::Prefix => {
+                    let ts = message_timestamp(&config.timestamp_format);
+                    format!("{fromnode}>{tonodes} [{ts}] {buf}\n")
+                }
+                Timestamps::Append => {
+                    let ts = message_timestamp(&config.timestamp_format);
+                    format!("{fromnode}>{tonodes} {buf} [{ts}]\n")
+                }
+            };
+            let sock = nodes
+                .get(&tonode)
+                .expect("routed to a node without connection!");
             let s = sock.try_clone().expect("stream clone failed!");
+            if let Some(info) = sd.nodeinfo.get_mut(&tonode) {
+                TrafficStats::add(&info.traffic.bytes_out, msg.len() as u64);
+                TrafficStats::add(&info.traffic.messages_out, 1);
+                info.last_sent = Some(Instant::now());
+            }
+            TrafficStats::add(&STATS.messages_out, 1);
+            if !nodes.watchers.is_empty() {
+                sendtowatchers(&fromnode, &tonodes, &msg, nodes);
+            }
+            // The mirror and the debugger only get the header line of a binary frame.
+            if let Some(mirror) = &sd.mirror {
+                mirror.send(&msg);
+            }
+            if frame.body.is_none() && sd.nodeinfo.get(node).is_some_and(|info| info.retain) {
+                sd.retain_message(&fromnode, &tonodes, &buf);
+            }
             writemsg(&s, msg, nodes);
-            let _ = event_tx.send(ServerEvent::MessageRouted {
-                from: fromnode.clone(),
-                to: tonodes.clone(),
-            });
+            if let Some(body) = frame.body {
+                if let Some(info) = sd.nodeinfo.get(&tonode) {
+                    TrafficStats::add(&info.traffic.bytes_out, body.len() as u64);
+                }
+                sendbytes(&s, body, nodes.write_retries);
+            }
+            events::notify(
+                event_tx,
+                ServerEvent::MessageRouted {
+                    from: fromnode.clone(),
+                    to: tonodes.clone(),
+                },
+            );
         }
-        None => {
+        Route::Down => {
+            trace_message(config, &fromnode, &tonodes, &buf, "down");
             if !SEARCHCMD3.is_match(&buf) {
-                let msg = format!("System>{fromnode} @{buf} Er: {tonode} is down.\n");
+                let msg = format!("{sys}>{fromnode} @{buf} Er: {tonodes} is down.\n");
                 writemsg(stream, msg, nodes);
             }
         }
+        // Handled above.
+        Route::Denied | Route::System | Route::Paused | Route::Muted => {}
     }
 }
 
-fn addnode(
-    stream: TcpStream,
-    msg: String,
-    nodekey: u16,
-    nodes: &Arc<Mutex<NodeList>>,
-    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
-    event_tx: &EventSender,
-) -> Option<String> {
-    let node_id: Vec<String> = msg.split_whitespace().map(str::to_string).collect();
-    if node_id.len() != 2 {
-        return None;
-    }
-    let mut node = node_id[0].clone();
-    let idmess = &node_id[1];
+// What becomes of a message, as decided by resolve_route.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Route {
+    // By the command permission lists or an allowcmd/denycmd override.
+    Denied,
+    System,
+    Paused,
+    Muted,
+    // A sub-node the receiver did not declare (--strict-subnodes).
+    NotHandled,
+    Draining,
+    // A binary frame to a node without the 'binary=1' capability.
+    NotBinary,
+    Deliver,
+    Down,
+}
 
-    let mut nodes_list = nodes.lock().expect("can't get the lock!");
+// The routing decision of sendmes for a message of `node` to `to` (the first word of
+// the command in `cmd`), in the order of its checks. Returns the destination after the
+// alias lookup as well. Nothing is sent, so 'testroute' uses it as a dry-run.
+fn resolve_route(
+    node: &str,
+    to: &str,
+    cmd: &str,
+    binary: bool,
+    nodes: &NodeList,
+    sd: &StarsData,
+    config: &ServerConfig,
+) -> (Route, String) {
+    let tonodes = sd.aliasreal.get(to).map_or(to, String::as_str).to_string();
+    let tonode = tonodes.split('.').next().unwrap_or_default();
+    let cmdname = cmd.split(' ').next().unwrap_or_default();
+    let overridden = sd
+        .cmd_overrides
+        .get(&(node.to_string(), cmdname.to_string()));
+    let denied = SEARCHCMD1.is_match(cmd)
+        && match overridden {
+            Some(allowed) => !allowed,
+            None => {
+                (!sd.cmddeny.is_empty() && is_deny_checkcmd_deny(node, &tonodes, cmd, &sd.cmddeny))
+                    || (!sd.cmdallow.is_empty()
+                        && is_deny_checkcmd_allow(node, &tonodes, cmd, &sd.cmdallow))
+            }
+        };
+    let target = sd.nodeinfo.get(nodes.owner(tonode).unwrap_or(tonode));
+    let route = if denied {
+        Route::Denied
+    } else if tonode == config.system_name {
+        Route::System
+    } else if sd.paused {
+        Route::Paused
+    } else if sd.nodeinfo.get(node).is_some_and(|info| info.muted) {
+        Route::Muted
+    } else if config.strict_subnodes
+        && nodes.contains_key(tonode)
+        && let Some((_, subnode)) = tonodes.split_once('.')
+        && !target.is_some_and(|info| info.handles_subnode(subnode))
+    {
+        Route::NotHandled
+    } else if !nodes.contains_key(tonode) {
+        Route::Down
+    } else if target.is_some_and(|info| info.draining) {
+        Route::Draining
+    } else if binary && !target.is_some_and(|info| info.binary) {
+        Route::NotBinary
+    } else {
+        Route::Deliver
+    };
+    (route, tonodes)
+}
 
-    if let Some(s) = nodes_list.get(&node) {
-        let stream_ref = s.try_clone().expect("stream clone failed!");
-        if !check_reconnecttable(&node, &stream_ref, sdata) {
-            let existmsg = format!("System> Er: {node} already exists.\n");
-            writemsg(&stream, existmsg, &mut nodes_list);
-            return None;
-        } else {
-            delnode(&node, &mut nodes_list, sdata, event_tx);
-        }
-    }
-    if !check_term_and_host(&node, &stream, &sdata.libdir) {
-        let errmsg = format!("System> Er: Bad host for {}\n", &node);
-        writemsg(&stream, errmsg, &mut nodes_list);
-        return None;
+// Keeps a message received while paused. A message over the limits drops older ones with
+// --pause-overflow drop-oldest or drop-busiest, otherwise it is refused. Returns whether
+// the message was kept.
+fn buffer_paused(sd: &mut StarsData, paused: PausedMessage, config: &ServerConfig) -> bool {
+    let size = paused.size();
+    let max_bytes = config.pause_buffer_bytes;
+    if max_bytes > 0 && size > max_bytes {
+        return false;
     }
-    if !check_nodekey(&node, nodekey as usize, idmess, &sdata.keydir) {
-        let errmsg = "System> Er: Bad node name or key\n".to_string();
-        writemsg(&stream, errmsg, &mut nodes_list);
-        return None;
+    while sd.paused_messages.len() >= MAX_PAUSED_MESSAGES
+        || (max_bytes > 0 && sd.paused_bytes + size > max_bytes)
+    {
+        let index = match config.pause_overflow {
+            PauseOverflow::Reject => return false,
+            PauseOverflow::DropOldest => 0,
+            PauseOverflow::DropBusiest => busiest_paused(&sd.paused_messages),
+        };
+        let Some(dropped) = sd.paused_messages.remove(index) else {
+            break;
+        };
+        sd.paused_bytes -= dropped.size();
+        TrafficStats::add(&PAUSE_DROPS, 1);
+        trace_message(config, &dropped.node, "", &dropped.line, "dropped");
     }
+    sd.paused_bytes += size;
+    sd.paused_messages.push_back(paused);
+    true
+}
 
-    let msg_ok = format!("System>{node} Ok:\n");
+// Index of the oldest buffered message of the node with the most buffered bytes.
+fn busiest_paused(messages: &VecDeque<PausedMessage>) -> usize {
+    let mut bytes: Vec<(&str, usize, usize)> = Vec::new();
+    for (index, paused) in messages.iter().enumerate() {
+        match bytes.iter_mut().find(|(node, _, _)| *node == paused.node) {
+            Some((_, total, _)) => *total += paused.size(),
+            None => bytes.push((&paused.node, paused.size(), index)),
+        }
+    }
+    // max_by_key returns the last maximum, so the ties go to the node buffering first.
+    bytes
+        .iter()
+        .rev()
+        .max_by_key(|(_, total, _)| *total)
+        .map_or(0, |(_, _, index)| *index)
+}
+
+// Routes the messages buffered while paused, in the order received. The messages of
+// nodes which are gone in the meantime are dropped.
+fn replay_paused(
+    replay: Vec<PausedMessage>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    sdata: &Arc<Mutex<StarsData>>,
+    event_tx: &EventSender,
+    config: &ServerConfig,
+) {
+    for paused in replay {
+        let Some(stream) = nodes.get(&paused.node).and_then(|s| s.try_clone().ok()) else {
+            continue;
+        };
+        let frame = Frame {
+            line: &paused.line,
+            raw: &paused.line,
+            body: paused.body.as_deref(),
+        };
+        sendmes(
+            &paused.node,
+            &stream,
+            &frame,
+            nodes,
+            sdata,
+            event_tx,
+            config,
+        );
+    }
+}
+
+// Wall clock time derived from the monotonic clock, so the timestamps of the routed
+// messages never go backwards, even if the system time is changed.
+fn message_timestamp(format: &str) -> String {
+    lazy_static! {
+        static ref CLOCK_START: (Instant, DateTime<Local>) = (Instant::now(), Local::now());
+    }
+    let (start, wallclock) = *CLOCK_START;
+    let elapsed = chrono::Duration::from_std(start.elapsed()).unwrap_or_default();
+    (wallclock + elapsed).format(format).to_string()
+}
+
+// Longest prefix of s with at most max bytes, cut at a char boundary.
+fn truncate_str(s: &str, max: usize) -> &str {
+    let mut end = s.len().min(max);
+    while !s.is_char_boundary(end) {
+        end -= 1;
+    }
+    &s[..end]
+}
+
+// Message firehose for troubleshooting, enabled with --trace-messages.
+fn trace_message(config: &ServerConfig, from: &str, to: &str, body: &str, disposition: &str) {
+    if config.trace_messages {
+        let body = if to == config.system_name {
+            without_token(body, config)
+        } else {
+            Cow::Borrowed(body)
+        };
+        console::trace(format!("[TRACE] {from}>{to} {body} ({disposition})"));
+    }
+}
+
+// The admin token ending a privileged System command is shown as '***'.
+fn without_token<'a>(cmd: &'a str, config: &ServerConfig) -> Cow<'a, str> {
+    let name = cmd.split(' ').next().unwrap_or_default();
+    let privileged = SYSTEM_COMMANDS
+        .iter()
+        .any(|c| c.name == name && c.permission == Permission::Shutdown);
+    match cmd.rsplit_once(' ') {
+        Some((rest, _)) if privileged && config.admin_token.is_some() => {
+            Cow::Owned(format!("{rest} ***"))
+        }
+        _ => Cow::Borrowed(cmd),
+    }
+}
+
+// Runs the handler of the node if addnode accepts the registration, otherwise the
+// connection is closed.
+#[allow(clippy::too_many_arguments)]
+fn register_node(
+    stream: TcpStream,
+    rmsg: String,
+    nodekey: u16,
+    nodes: &Arc<Mutex<NodeList>>,
+    sd: &Arc<Mutex<StarsData>>,
+    event_tx: &EventSender,
+    config: &Arc<ServerConfig>,
+    handlers: &Arc<AtomicUsize>,
+) {
+    match addnode(
+        stream.try_clone().expect("stream clone failed!"),
+        rmsg.trim().to_string(),
+        nodekey,
+        nodes,
+        &mut sd.lock().expect("can't get the lock!"),
+        event_tx,
+        config,
+    ) {
+        Some(node) => {
+            // Counted before the thread runs, so 'threadinfo' never sees a registered node
+            // without its handler.
+            let count = ThreadCount::enter(handlers);
+            let nodes = Arc::clone(nodes);
+            let sd = Arc::clone(sd);
+            let tx = event_tx.clone();
+            let config = Arc::clone(config);
+            thread::spawn(move || {
+                let _count = count;
+                handle_node(node, stream, nodes, sd, tx, config);
+            });
+        }
+        None => {
+            if stream.shutdown(Shutdown::Both).is_err() {
+                console::error("shutdown call failed");
+            }
+        }
+    }
+}
+
+fn addnode(
+    stream: TcpStream,
+    msg: String,
+    nodekey: u16,
+    nodes: &Arc<Mutex<NodeList>>,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    event_tx: &EventSender,
+    config: &ServerConfig,
+) -> Option<String> {
+    let sys = config.system_name.as_str();
+    let node_id: Vec<String> = msg.split_whitespace().map(str::to_string).collect();
+    // Optional capabilities after the key: '<node> <key> [cap=value ...]'
+    let mut info = NodeInfo::default();
+    let unknown_cap = node_id.iter().skip(2).find(|cap| !info.set_capability(cap));
+    let malformed = match unknown_cap {
+        _ if node_id.len() < 2 => Some("too few tokens".to_string()),
+        Some(cap) => Some(format!("too many tokens (unknown capability '{cap}')")),
+        None => None,
+    };
+    if let Some(what) = malformed {
+        TrafficStats::add(&ACCEPTS.rejected_badkey, 1);
+        TrafficStats::add(&sdata.violation_stats.malformed_registration, 1);
+        let reason = format!("Registration must be '<node> <key>', {what}.");
+        let name = node_id.first().map_or("", String::as_str);
+        log_rejection(name, &stream, &reason, event_tx);
+        let errmsg = format!("{sys}> Er: {reason}\n");
+        writemsg(
+            &stream,
+            errmsg,
+            &mut nodes.lock().expect("can't get the lock!"),
+        );
+        return None;
+    }
+    let mut node = node_id[0].clone();
+    let idmess = &node_id[1];
+
+    // Held until the node is inserted, so of two registrations of the same name exactly
+    // one wins, the other gets 'already exists'. writemsg only borrows the guard.
+    let mut nodes_list = nodes.lock().expect("can't get the lock!");
+
+    if sdata.registrations_locked {
+        log_rejection(
+            &node,
+            &stream,
+            "Registrations are temporarily locked.",
+            event_tx,
+        );
+        let errmsg = format!("{sys}> Er: Registrations are temporarily locked.\n");
+        writemsg(&stream, errmsg, &mut nodes_list);
+        return None;
+    }
+    // Set if the connection of the same name gives way, to whether it was found dead. It
+    // is only closed once the new one has passed the checks below.
+    let mut replaces_dead = None;
+    if let Some(s) = nodes_list.get(&node) {
+        let stream_ref = s.try_clone().expect("stream clone failed!");
+        if check_reconnecttable(&node, &stream_ref, sdata) {
+            replaces_dead = Some(false);
+        } else if config.probe_dead_nodes && is_connection_dead(&stream_ref) {
+            replaces_dead = Some(true);
+        } else {
+            TrafficStats::add(&ACCEPTS.rejected_dupe, 1);
+            log_rejection(&node, &stream, "Node already exists.", event_tx);
+            let existmsg = format!("{sys}> Er: {node} already exists.\n");
+            writemsg(&stream, existmsg, &mut nodes_list);
+            return None;
+        }
+    }
+    if !check_term_and_host(&node, &stream, &sdata.libdir) {
+        TrafficStats::add(&ACCEPTS.rejected_badhost, 1);
+        log_rejection(&node, &stream, "Bad host.", event_tx);
+        let errmsg = format!("{sys}> Er: Bad host for {}\n", &node);
+        writemsg(&stream, errmsg, &mut nodes_list);
+        return None;
+    }
+    if !check_node_prefix(&node, &stream, &sdata.libdir) {
+        TrafficStats::add(&ACCEPTS.rejected_badhost, 1);
+        log_rejection(
+            &node,
+            &stream,
+            "Node name not permitted from this host.",
+            event_tx,
+        );
+        let errmsg = format!("{sys}> Er: Node name not permitted from this host.\n");
+        writemsg(&stream, errmsg, &mut nodes_list);
+        return None;
+    }
+    if host_limit_reached(&stream, sdata, config) {
+        TrafficStats::add(&ACCEPTS.rejected_badhost, 1);
+        log_rejection(
+            &node,
+            &stream,
+            "Too many connections from this host.",
+            event_tx,
+        );
+        let errmsg = format!("{sys}> Er: Too many connections from this host.\n");
+        writemsg(&stream, errmsg, &mut nodes_list);
+        return None;
+    }
+    if !check_nodekey(&node, nodekey as usize, idmess, &sdata.keydir) {
+        TrafficStats::add(&ACCEPTS.rejected_badkey, 1);
+        log_rejection(&node, &stream, "Bad node name or key.", event_tx);
+        let errmsg = format!("{sys}> Er: Bad node name or key\n");
+        writemsg(&stream, errmsg, &mut nodes_list);
+        return None;
+    }
+    if let Some(dead) = replaces_dead {
+        if dead {
+            console::print(
+                Style::Disconnect,
+                format!("Replacing dead connection of {node}."),
+            );
+        }
+        delnode(
+            &node,
+            &mut nodes_list,
+            sdata,
+            event_tx,
+            DisconnectReason::Replaced,
+        );
+    }
+
+    if config.max_connections > 0 && nodes_list.connections() >= config.max_connections {
+        let victim = match config.over_limit_policy {
+            OverLimitPolicy::Reject => None,
+            OverLimitPolicy::EvictOldest => stalest_node(sdata, config),
+        };
+        let Some(victim) = victim else {
+            TrafficStats::add(&ACCEPTS.rejected_badhost, 1);
+            log_rejection(&node, &stream, "Too many connections.", event_tx);
+            let errmsg = format!("{sys}> Er: Too many connections.\n");
+            writemsg(&stream, errmsg, &mut nodes_list);
+            return None;
+        };
+        console::print(
+            Style::Disconnect,
+            format!("Evicting {victim} to make room for {node}."),
+        );
+        delnode(
+            &victim,
+            &mut nodes_list,
+            sdata,
+            event_tx,
+            DisconnectReason::Evicted,
+        );
+    }
+
+    let msg_ok = format!("{sys}>{node} Ok:\n");
     writemsg(
         &stream.try_clone().expect("stream clone failed!"),
         msg_ok,
         &mut nodes_list,
     );
-    nodes_list.insert(node.clone(), stream);
+    // Only clients which asked for capabilities get the confirmation.
+    if node_id.len() > 2 {
+        let msg_caps = format!(
+            "{sys}>{node} @caps enabled={}\n",
+            info.capabilities().join(",")
+        );
+        writemsg(&stream, msg_caps, &mut nodes_list);
+    }
+    info.peer = stream.peer_addr().ok();
+    info.nodekey = nodekey;
+    info.connected = Some(Local::now());
+    info.last_active = Some(Instant::now());
+    if let Some(write_timeout) = msec_timeout(config.write_timeout)
+        && let Err(err) = stream.set_write_timeout(Some(write_timeout))
+    {
+        console::error(format!("Set write timeout failed ({node}): {err}"));
+    }
+    nodes_list.insert(node.clone(), stream);
+    if config.debugger_by_name && node == "Debugger" {
+        nodes_list.debugger = Some(node.clone());
+    }
+    sdata.nodeinfo.insert(node.clone(), info);
+
+    console::print(Style::Connect, format!("{node} connected."));
+    syslog::log(
+        Severity::Info,
+        "connect",
+        &node,
+        peer_ip(&nodes_list, &node),
+        "Connected.",
+    );
+    events::notify(event_tx, ServerEvent::NodeConnected { name: node.clone() });
+
+    notify_subscribers(&node, "_Connected", &mut nodes_list, sdata);
+    if let Some(n) = sdata.realalias.get(&node) {
+        node = n.to_string();
+    }
+    Some(node)
+}
+
+// The node with the oldest activity, except the privileged and the exempt ones.
+// Claimed names have no NodeInfo, so only connections are picked.
+fn stalest_node(sdata: &StarsData, config: &ServerConfig) -> Option<String> {
+    sdata
+        .nodeinfo
+        .iter()
+        .filter(|(name, _)| {
+            !config.evict_exempt.contains(name) && !is_shutdowncmd_allow(name, &sdata.shutallow)
+        })
+        .min_by_key(|(_, info)| info.last_active)
+        .map(|(name, _)| name.clone())
+}
+
+// The nodes from the same ip are counted by the peer address stored at registration.
+fn host_limit_reached(stream: &TcpStream, sdata: &StarsData, config: &ServerConfig) -> bool {
+    let Ok(peer) = stream.peer_addr() else {
+        return false;
+    };
+    if config.max_nodes_per_host == 0 || config.host_limit_exempt.contains(&peer.ip()) {
+        return false;
+    }
+    let count = sdata
+        .nodeinfo
+        .values()
+        .filter(|info| info.peer.is_some_and(|addr| addr.ip() == peer.ip()))
+        .count();
+    count >= config.max_nodes_per_host
+}
+
+fn peer_ip(nodes: &NodeList, node: &str) -> Option<std::net::IpAddr> {
+    nodes.get(node)?.peer_addr().ok().map(|addr| addr.ip())
+}
+
+fn log_rejection(node: &str, stream: &TcpStream, reason: &str, event_tx: &EventSender) {
+    let peer = stream.peer_addr().ok().map(|addr| addr.ip());
+    syslog::log(Severity::Warning, "reject", node, peer, reason);
+    let event = ServerEvent::ConnectionRejected {
+        node: node.to_string(),
+        peer: peer.map(|ip| ip.to_string()).unwrap_or_default(),
+        reason: reason.to_string(),
+    };
+    events::notify(event_tx, event);
+}
+
+// Gives the handler of a just disconnected node the time to remove it, so a fast
+// reconnect is not rejected as duplicate. Must be called without holding the StarsData lock,
+// the handler needs it for the cleanup. Runs on a thread of its own, not the accept loop.
+fn wait_node_released(nodes: &Arc<Mutex<NodeList>>, node: &str, grace: u64) {
+    let mut waited = 0;
+    while waited < grace
+        && nodes
+            .lock()
+            .expect("can't get the lock!")
+            .contains_key(node)
+    {
+        thread::sleep(Duration::from_millis(10));
+        waited += 10;
+    }
+}
+
+fn delnode(
+    node: &str,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    event_tx: &EventSender,
+    reason: DisconnectReason,
+) {
+    // A claimed name only drops the claim, the stream stays with the claiming node.
+    let claimed = nodes.is_claimed(node);
+    if let Some(s) = nodes.remove(node) {
+        sdata.record_disconnect(node, reason);
+
+        console::print(Style::Disconnect, format!("{node} disconnected."));
+        let peer = s.peer_addr().ok().map(|addr| addr.ip());
+        syslog::log(Severity::Info, "disconnect", node, peer, "Disconnected.");
+        events::notify(
+            event_tx,
+            ServerEvent::NodeDisconnected {
+                name: node.to_string(),
+            },
+        );
+
+        if !claimed {
+            let stream_ref = s.try_clone().expect("stream clone failed!");
+            match stream_ref.shutdown(Shutdown::Both) {
+                Ok(_) => (),
+                Err(err) => {
+                    console::error(format!("Shutdown call failed ({}): {}", &node, err));
+                }
+            }
+        }
+        sdata.nodes_flgon.remove(node);
+        sdata.forget_flg_history();
+        sdata.forget_retained(node);
+        sdata.nodeinfo.remove(node);
+        if sdata.prune_subscriptions {
+            sdata.prune_subscriptions(|name| nodes.contains_key(name), Some(node));
+        }
+        notify_subscribers(node, "_Disconnected", nodes, sdata);
+    }
+}
+
+// Sends `what` ('_Connected', '_Disconnected' or an event) of the node to the nodes which
+// subscribed to it with flgon. A subscription may name the node by its registered name or
+// by its alias, and the notice comes from the name subscribed to. A subscriber known by an
+// alias is reached through its real name.
+// Returns the reached subscribers by their registered name. Every notice is recorded in
+// the history of its subscription ('flghistory').
+fn notify_subscribers(
+    node: &str,
+    what: &str,
+    nodes: &mut NodeList,
+    sdata: &mut StarsData,
+) -> Vec<String> {
+    notify_subscribers_as(node, None, what, nodes, sdata)
+}
+
+// notify_subscribers with the notices coming from `sender` instead of the subscribed name.
+fn notify_subscribers_as(
+    node: &str,
+    sender: Option<&str>,
+    what: &str,
+    nodes: &mut NodeList,
+    sdata: &mut StarsData,
+) -> Vec<String> {
+    let mut names = vec![node];
+    for name in [sdata.realalias.get(node), sdata.aliasreal.get(node)]
+        .into_iter()
+        .flatten()
+    {
+        if !names.contains(&name.as_str()) {
+            names.push(name);
+        }
+    }
+    let mut notices = vec![];
+    let mut unreached = vec![];
+    for (subscriber, targets) in &sdata.nodes_flgon {
+        let matched: Vec<&str> = names
+            .iter()
+            .copied()
+            .filter(|n| targets.contains(*n))
+            .collect();
+        if matched.is_empty() {
+            continue;
+        }
+        let top = |name: &str| name.split('.').next().unwrap_or_default().to_string();
+        let mut to = top(subscriber);
+        if !nodes.contains_key(&to)
+            && let Some(real) = sdata.aliasreal.get(&to)
+        {
+            to = top(real);
+        }
+        let Some(stream) = nodes.get(&to).and_then(|s| s.try_clone().ok()) else {
+            unreached.extend(
+                matched
+                    .iter()
+                    .map(|name| (subscriber.clone(), name.to_string())),
+            );
+            continue;
+        };
+        for name in matched {
+            let stream = stream.try_clone().expect("stream clone failed!");
+            let msg = format!("{}>{subscriber} {what}\n", sender.unwrap_or(name));
+            notices.push((
+                subscriber.clone(),
+                name.to_string(),
+                to.clone(),
+                stream,
+                msg,
+            ));
+        }
+    }
+    for (subscriber, target) in unreached {
+        sdata.record_notice(&subscriber, &target, what, NoticeResult::NotConnected);
+    }
+    let mut reached = vec![];
+    for (subscriber, target, to, stream, msg) in notices {
+        let result = if sendtonode(&stream, &msg, nodes.crlf, nodes.write_retries) {
+            NoticeResult::Sent
+        } else {
+            NoticeResult::WriteFailed
+        };
+        sdata.record_notice(&subscriber, &target, what, result);
+        sendtodebugger(&msg, nodes);
+        reached.push(to);
+    }
+    reached
+}
+
+// Arguments a system command takes after its name.
+#[derive(Clone, Copy, PartialEq)]
+enum Args {
+    None,
+    Optional,
+    // The name must be followed by a space.
+    Required,
+}
+
+#[derive(Clone, Copy, PartialEq)]
+enum Permission {
+    Any,
+    // Nodes listed in shutdown_allow.cfg
+    Shutdown,
+}
+
+// State of the server a system command works on.
+struct CommandContext<'a, 's, 'n> {
+    node: &'a str,
+    stream: &'a TcpStream,
+    fromnode: &'a str,
+    // The whole command and the line as received, for the error replies and rawecho.
+    cmd: &'a str,
+    line: &'a str,
+    sdata: &'a mut std::sync::MutexGuard<'s, StarsData>,
+    nodes: &'a mut std::sync::MutexGuard<'n, NodeList>,
+    event_tx: &'a EventSender,
+    config: &'a ServerConfig,
+}
+
+impl<'a> CommandContext<'a, '_, '_> {
+    // Name of the System pseudo node in the replies.
+    fn sys(&self) -> &'a str {
+        &self.config.system_name
+    }
+
+    fn reply(&mut self, msg: String) {
+        writemsg(self.stream, msg, self.nodes);
+    }
+}
+
+type CommandHandler = fn(&mut CommandContext, &str);
+
+pub struct SystemCommand {
+    pub name: &'static str,
+    handler: CommandHandler,
+    args: Args,
+    permission: Permission,
+    pub description: &'static str,
+}
+
+const fn command(
+    name: &'static str,
+    handler: CommandHandler,
+    args: Args,
+    permission: Permission,
+    description: &'static str,
+) -> SystemCommand {
+    SystemCommand {
+        name,
+        handler,
+        args,
+        permission,
+        description,
+    }
+}
+
+// System commands, as dispatched by system_commands and listed by 'help' and 'listcommands'.
+pub static SYSTEM_COMMANDS: &[SystemCommand] = &[
+    command(
+        "flgon",
+        cmd_flgon,
+        Args::Required,
+        Permission::Any,
+        "Receive the events of the given node",
+    ),
+    command(
+        "flgoff",
+        cmd_flgoff,
+        Args::Required,
+        Permission::Any,
+        "Stop receiving the events of the given node",
+    ),
+    command(
+        "flgclear",
+        cmd_flgclear,
+        Args::None,
+        Permission::Any,
+        "Stop receiving the events of all nodes",
+    ),
+    command(
+        "notifysubscribers",
+        cmd_notifysubscribers,
+        Args::Required,
+        Permission::Shutdown,
+        "Send <text> from System to the nodes subscribed to <node> with flgon",
+    ),
+    command(
+        "flghistory",
+        cmd_flghistory,
+        Args::Required,
+        Permission::Any,
+        "Last notices of the subscription of <subscriber> to <node>, newest first",
+    ),
+    command(
+        "loadaliases",
+        cmd_loadaliases,
+        Args::None,
+        Permission::Any,
+        "Reload the alias list",
+    ),
+    command(
+        "listaliases",
+        cmd_listaliases,
+        Args::Optional,
+        Permission::Any,
+        "List the aliases, optionally by page",
+    ),
+    command(
+        "dumpaliases",
+        cmd_dumpaliases,
+        Args::Optional,
+        Permission::Any,
+        "Aliases as alias=real with the conflicts, by page or 'json'",
+    ),
+    command(
+        "dumpdot",
+        cmd_dumpdot,
+        Args::None,
+        Permission::Any,
+        "Nodes and flgon subscriptions as a Graphviz digraph",
+    ),
+    command(
+        "loadhosts",
+        cmd_loadhosts,
+        Args::None,
+        Permission::Shutdown,
+        "Reload the host list for new connections",
+    ),
+    command(
+        "loadpermission",
+        cmd_loadpermission,
+        Args::Optional,
+        Permission::Any,
+        "Reload the command permission lists, 'clear' also drops the allowcmd/denycmd rules",
+    ),
+    command(
+        "allowcmd",
+        cmd_allowcmd,
+        Args::Required,
+        Permission::Shutdown,
+        "Allow a command for a node until the next 'loadpermission clear'",
+    ),
+    command(
+        "denycmd",
+        cmd_denycmd,
+        Args::Required,
+        Permission::Shutdown,
+        "Deny a command for a node until the next 'loadpermission clear'",
+    ),
+    command(
+        "loadreconnectablepermission",
+        cmd_loadreconnectablepermission,
+        Args::None,
+        Permission::Any,
+        "Reload the reconnectable permission lists",
+    ),
+    command(
+        "listnodes",
+        cmd_listnodes,
+        Args::Optional,
+        Permission::Any,
+        "List the connected nodes, optionally by page",
+    ),
+    command(
+        "isup",
+        cmd_isup,
+        Args::Required,
+        Permission::Any,
+        "Whether the given node (or alias) is connected",
+    ),
+    command(
+        "testroute",
+        cmd_testroute,
+        Args::Required,
+        Permission::Any,
+        "What would become of '<node> <command>' sent by the caller, nothing is sent",
+    ),
+    command(
+        "listrestricted",
+        cmd_listrestricted,
+        Args::None,
+        Permission::Any,
+        "List the nodes with restrictions",
+    ),
+    command(
+        "listbyhost",
+        cmd_listbyhost,
+        Args::None,
+        Permission::Any,
+        "List the connected nodes by client address",
+    ),
+    command(
+        "settimeout",
+        cmd_settimeout,
+        Args::Required,
+        Permission::Shutdown,
+        "Set the registration timeout in msec",
+    ),
+    command(
+        "reopenlog",
+        cmd_reopenlog,
+        Args::None,
+        Permission::Shutdown,
+        "Close and reopen the log file, after it was rotated",
+    ),
+    command(
+        "setloglevel",
+        cmd_setloglevel,
+        Args::Required,
+        Permission::Shutdown,
+        "Set the console log level (off, error, warn, info, debug, trace)",
+    ),
+    command(
+        "shutdown",
+        cmd_shutdown,
+        Args::None,
+        Permission::Shutdown,
+        "Shut down the server",
+    ),
+    command(
+        "getversion",
+        cmd_getversion,
+        Args::None,
+        Permission::Any,
+        "Server version",
+    ),
+    command(
+        "buildinfo",
+        cmd_buildinfo,
+        Args::None,
+        Permission::Any,
+        "Compiler, build time, target and features",
+    ),
+    command(
+        "gettime",
+        cmd_gettime,
+        Args::None,
+        Permission::Any,
+        "Server time",
+    ),
+    command("hello", cmd_hello, Args::None, Permission::Any, "Greeting"),
+    command(
+        "help",
+        cmd_help,
+        Args::None,
+        Permission::Any,
+        "List the system command names",
+    ),
+    command(
+        "listcommands",
+        cmd_listcommands,
+        Args::None,
+        Permission::Any,
+        "List the system commands with a description",
+    ),
+    command(
+        "rawecho",
+        cmd_rawecho,
+        Args::Optional,
+        Permission::Any,
+        "Echo the line as received by the server",
+    ),
+    command(
+        "becomedebugger",
+        cmd_becomedebugger,
+        Args::Optional,
+        Permission::Shutdown,
+        "Receive a copy of the traffic, optionally of one node and direction",
+    ),
+    command(
+        "claim",
+        cmd_claim,
+        Args::Required,
+        Permission::Any,
+        "Receive the messages to an additional node name, given with its key",
+    ),
+    command(
+        "verifykey",
+        cmd_verifykey,
+        Args::Required,
+        Permission::Shutdown,
+        "Check '<node> <key> [challenge]' against the key file, the challenge defaults to 0",
+    ),
+    command(
+        "watch",
+        cmd_watch,
+        Args::Required,
+        Permission::Shutdown,
+        "Receive a copy of the messages from or to nodes matching a name pattern",
+    ),
+    command(
+        "unwatch",
+        cmd_unwatch,
+        Args::Required,
+        Permission::Any,
+        "Stop receiving the copies for a name pattern",
+    ),
+    command(
+        "disconnect",
+        cmd_disconnect,
+        Args::Required,
+        Permission::Any,
+        "Disconnect the given node",
+    ),
+    command(
+        "disconnectall",
+        cmd_disconnectall,
+        Args::Optional,
+        Permission::Shutdown,
+        "Disconnect all other nodes with an optional reason",
+    ),
+    command(
+        "gcnodes",
+        cmd_gcnodes,
+        Args::None,
+        Permission::Shutdown,
+        "Remove the connections found dead",
+    ),
+    command(
+        "gcsubscriptions",
+        cmd_gcsubscriptions,
+        Args::None,
+        Permission::Shutdown,
+        "Remove the flgon subscriptions of and to nodes which are not registered",
+    ),
+    command(
+        "mute",
+        cmd_mute,
+        Args::Required,
+        Permission::Shutdown,
+        "Drop the messages of the given node",
+    ),
+    command(
+        "unmute",
+        cmd_unmute,
+        Args::Required,
+        Permission::Shutdown,
+        "Deliver the messages of the given node again",
+    ),
+    command(
+        "drain",
+        cmd_drain,
+        Args::Required,
+        Permission::Shutdown,
+        "Reject the messages to the given node, it stays connected",
+    ),
+    command(
+        "undrain",
+        cmd_undrain,
+        Args::Required,
+        Permission::Shutdown,
+        "Deliver the messages to the given node again",
+    ),
+    command(
+        "threadinfo",
+        cmd_threadinfo,
+        Args::None,
+        Permission::Any,
+        "Running node handler threads compared with the node count",
+    ),
+    command(
+        "resources",
+        cmd_resources,
+        Args::None,
+        Permission::Shutdown,
+        "Memory, open file descriptors and threads of the server process",
+    ),
+    command(
+        "pause",
+        cmd_pause,
+        Args::None,
+        Permission::Shutdown,
+        "Stop routing messages between nodes, System commands still work",
+    ),
+    command(
+        "resume",
+        cmd_resume,
+        Args::None,
+        Permission::Shutdown,
+        "Route messages again, including the ones buffered while paused",
+    ),
+    command(
+        "lockregistrations",
+        cmd_lockregistrations,
+        Args::None,
+        Permission::Shutdown,
+        "Refuse new registrations, the connected nodes keep working",
+    ),
+    command(
+        "unlockregistrations",
+        cmd_unlockregistrations,
+        Args::None,
+        Permission::Shutdown,
+        "Accept registrations again",
+    ),
+    command(
+        "recentdisconnects",
+        cmd_recentdisconnects,
+        Args::None,
+        Permission::Any,
+        "The last disconnected nodes with address, times and reason, newest first",
+    ),
+    command(
+        "stats",
+        cmd_stats,
+        Args::None,
+        Permission::Any,
+        "Server traffic counters",
+    ),
+    command(
+        "acceptstats",
+        cmd_acceptstats,
+        Args::None,
+        Permission::Any,
+        "Connection counters",
+    ),
+    command(
+        "violations",
+        cmd_violations,
+        Args::None,
+        Permission::Any,
+        "Protocol violation counters by kind and by ip",
+    ),
+    command(
+        "resetviolations",
+        cmd_resetviolations,
+        Args::None,
+        Permission::Shutdown,
+        "Zero the protocol violation counters",
+    ),
+    command(
+        "nodestats",
+        cmd_nodestats,
+        Args::None,
+        Permission::Any,
+        "Traffic counters per node",
+    ),
+    command(
+        "nodeinfo",
+        cmd_nodeinfo,
+        Args::Required,
+        Permission::Any,
+        "Connection, traffic, subscriptions, flags and aliases of one node",
+    ),
+    command(
+        "pingnode",
+        cmd_pingnode,
+        Args::Required,
+        Permission::Any,
+        "Round trip time to a node echoing '_ping <token>' to System, optional timeout in msec",
+    ),
+    command(
+        "idlenodes",
+        cmd_idlenodes,
+        Args::Optional,
+        Permission::Any,
+        "Nodes without traffic for at least the given seconds, longest idle first",
+    ),
+];
+
+// Commands recognized by a prefix instead of a name. The handler gets the whole command.
+static SYSTEM_PREFIXES: &[(&str, CommandHandler)] = &[
+    ("_ping ", cmd_pingecho),
+    ("@_ping ", cmd_pingecho),
+    ("_", cmd_event),
+];
+
+#[allow(clippy::too_many_arguments)]
+fn system_commands(
+    node: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    cmd: &str,
+    line: &str,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    event_tx: &EventSender,
+    config: &ServerConfig,
+) {
+    let sys = config.system_name.as_str();
+    let mut ctx = CommandContext {
+        node,
+        stream,
+        fromnode,
+        cmd,
+        line,
+        sdata,
+        nodes,
+        event_tx,
+        config,
+    };
+    if let Some((_, handler)) = SYSTEM_PREFIXES.iter().find(|(p, _)| cmd.starts_with(p)) {
+        handler(&mut ctx, cmd);
+        return;
+    }
+    let (name, mut args) = match cmd.split_once(' ') {
+        Some((name, args)) => (name, Some(args)),
+        None => (cmd, None),
+    };
+    let Some(command) = SYSTEM_COMMANDS.iter().find(|c| c.name == name) else {
+        cmd_unknown(&mut ctx, cmd);
+        return;
+    };
+    if command.permission == Permission::Shutdown
+        && let Some(token) = &config.admin_token
+    {
+        // The token is the last argument. A wrong token gets the same reply
+        // whether the node is allowed or not.
+        let (rest, given) = match args.map(|a| a.rsplit_once(' ')) {
+            None => (None, ""),
+            Some(None) => (None, args.unwrap_or_default()),
+            Some(Some((rest, given))) => (Some(rest), given),
+        };
+        if !constant_time_eq(given.as_bytes(), token.as_bytes()) {
+            ctx.reply(format!(
+                "{sys}>{fromnode} @{name} Er: Authentication failed.\n"
+            ));
+            return;
+        }
+        args = rest;
+    }
+    let args = match (command.args, args) {
+        (Args::None, None) | (Args::Optional, None) => "",
+        (Args::Optional, Some(args)) | (Args::Required, Some(args)) => args,
+        _ => {
+            cmd_unknown(&mut ctx, cmd);
+            return;
+        }
+    };
+    if command.permission == Permission::Shutdown
+        && (ctx.sdata.shutallow.is_empty() || !is_shutdowncmd_allow(fromnode, &ctx.sdata.shutallow))
+    {
+        ctx.reply(format!("{sys}>{fromnode} @{name} Er: Command denied.\n"));
+        return;
+    }
+    (command.handler)(&mut ctx, args);
+}
+
+fn cmd_unknown(ctx: &mut CommandContext, cmd: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let suggestion = match ctx.config.unknown_command {
+        UnknownCommand::Ignore => {
+            console::warn(format!("Unknown command from {fromnode}: {cmd}"));
+            return;
+        }
+        UnknownCommand::Error => None,
+        UnknownCommand::Suggest => {
+            closest_system_command(cmd, SYSTEM_COMMANDS.iter().map(|c| c.name))
+        }
+    };
+    let mut msg =
+        format!("{sys}>{fromnode} @{cmd} Er: Command is not found or parameter is not enough!");
+    if let Some(name) = suggestion {
+        msg.push_str(&format!(" Did you mean '{name}'?"));
+    }
+    msg.push('\n');
+    ctx.reply(msg);
+}
+
+fn cmd_event(ctx: &mut CommandContext, cmd: &str) {
+    system_event(
+        ctx.sys(),
+        ctx.node,
+        cmd,
+        ctx.nodes,
+        ctx.sdata,
+        ctx.config.event_hop_limit,
+    );
+}
+
+// '_ping <token>' echoed by the target of a 'pingnode', or '@_ping <token> Er: ...' from a
+// node which does not know the probe. Without a matching probe '_ping' is a normal event.
+fn cmd_pingecho(ctx: &mut CommandContext, cmd: &str) {
+    let is_error = cmd.starts_with('@');
+    let token = cmd
+        .split_whitespace()
+        .nth(1)
+        .and_then(|t| t.parse::<u64>().ok());
+    let ping = token.and_then(|token| match ctx.sdata.pings.get(&token) {
+        Some(ping) if ping.target == ctx.node => ctx.sdata.pings.remove(&token),
+        _ => None,
+    });
+    let Some(ping) = ping else {
+        if !is_error {
+            cmd_event(ctx, cmd);
+        }
+        return;
+    };
+    let result = if is_error {
+        format!("Er: {} does not answer pings.", ping.target)
+    } else {
+        format!("{:.3} ms", ping.sent.elapsed().as_secs_f64() * 1000.0)
+    };
+    answer_ping(&ping, &result, ctx.nodes, ctx.config);
+}
+
+// 'pingnode <node> [msec]' sends '_ping <token>' to the node, the node echoes it to System.
+fn cmd_pingnode(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let mut words = args.split_whitespace();
+    let target = words.next().unwrap_or_default();
+    let target = ctx
+        .sdata
+        .aliasreal
+        .get(target)
+        .map_or(target, String::as_str)
+        .to_string();
+    let timeout = match words.next().map(str::parse::<u64>) {
+        None => DEFAULT_PINGNODE_TIMEOUT,
+        Some(Ok(msecs)) if msecs > 0 && msecs <= MAX_TIMEOUT => msecs,
+        Some(_) => {
+            ctx.reply(format!("{sys}>{fromnode} @pingnode Er: Invalid timeout.\n"));
+            return;
+        }
+    };
+    let Some(stream) = ctx.nodes.get(&target).and_then(|s| s.try_clone().ok()) else {
+        ctx.reply(format!(
+            "{sys}>{fromnode} @pingnode {target} Er: {target} is down.\n"
+        ));
+        return;
+    };
+    ctx.sdata.last_ping_token += 1;
+    let token = ctx.sdata.last_ping_token;
+    let sent = Instant::now();
+    ctx.sdata.pings.insert(
+        token,
+        PendingPing {
+            caller_node: ctx.node.to_string(),
+            caller: fromnode.to_string(),
+            target: target.clone(),
+            sent,
+            deadline: sent + Duration::from_millis(timeout),
+        },
+    );
+    writemsg(
+        &stream,
+        format!("{sys}>{target} _ping {token}\n"),
+        ctx.nodes,
+    );
+}
+
+fn cmd_flgon(ctx: &mut CommandContext, args: &str) {
+    system_flgon(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        ctx.sdata,
+        ctx.nodes,
+    );
+}
+
+fn cmd_flgoff(ctx: &mut CommandContext, args: &str) {
+    system_flgoff(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        ctx.sdata,
+        ctx.nodes,
+    );
+}
+
+// Drops the whole flgon list of the caller, as a disconnect would, but keeps the connection.
+fn cmd_flgclear(ctx: &mut CommandContext, _args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let count = ctx
+        .sdata
+        .nodes_flgon
+        .remove(fromnode)
+        .map_or(0, |targets| targets.len());
+    ctx.sdata.forget_flg_history();
+    ctx.reply(format!(
+        "{sys}>{fromnode} @flgclear {count} subscription(s) removed.\n"
+    ));
+}
+
+fn cmd_notifysubscribers(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let Some((node, text)) = args
+        .split_once(' ')
+        .filter(|(_, text)| !text.trim().is_empty())
+    else {
+        ctx.reply(format!(
+            "{sys}>{fromnode} @notifysubscribers Er: Parameter is not enough.\n"
+        ));
+        return;
+    };
+    let mut reached = notify_subscribers_as(node, Some(sys), text.trim(), ctx.nodes, ctx.sdata);
+    reached.sort();
+    reached.dedup();
+    let count = reached.len();
+    let msg =
+        format!("{sys}>{fromnode} @notifysubscribers {node} {count} subscriber(s) notified.\n");
+    ctx.reply(msg);
+}
+
+// 'count time,result,event; ...' of the subscription, newest first.
+fn cmd_flghistory(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+    let mut parts = args.split_whitespace();
+    let (Some(subscriber), Some(target), None) = (parts.next(), parts.next(), parts.next()) else {
+        ctx.reply(format!(
+            "{sys}>{fromnode} @flghistory Er: Parameter is not enough.\n"
+        ));
+        return;
+    };
+    let subscribed = ctx
+        .sdata
+        .nodes_flgon
+        .get(subscriber)
+        .is_some_and(|targets| targets.contains(target));
+    let msg = if subscribed {
+        let key = (subscriber.to_string(), target.to_string());
+        let history = ctx.sdata.flg_history.get(&key);
+        let entries: Vec<String> = history
+            .into_iter()
+            .flatten()
+            .rev()
+            .map(|r| {
+                format!(
+                    "{},{},{}",
+                    r.time.format(TIME_FORMAT),
+                    r.result.name(),
+                    r.event
+                )
+            })
+            .collect();
+        let count = entries.len();
+        format!(
+            "{sys}>{fromnode} @flghistory {args} {count} {}\n",
+            entries.join("; ")
+        )
+    } else {
+        format!("{sys}>{fromnode} @flghistory Er: {subscriber} is not subscribed to {target}.\n")
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_disconnect(ctx: &mut CommandContext, args: &str) {
+    system_disconnect(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        ctx.sdata,
+        ctx.nodes,
+        ctx.event_tx,
+    );
+}
+
+fn cmd_disconnectall(ctx: &mut CommandContext, args: &str) {
+    let (sys, node, stream, fromnode) = (ctx.sys(), ctx.node, ctx.stream, ctx.fromnode);
+    let reason = args.trim();
+    system_disconnectall(
+        sys,
+        node,
+        stream,
+        fromnode,
+        reason,
+        ctx.sdata,
+        ctx.nodes,
+        ctx.event_tx,
+    );
+}
+
+fn cmd_mute(ctx: &mut CommandContext, args: &str) {
+    system_mute(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        true,
+        ctx.sdata,
+        ctx.nodes,
+    );
+}
+
+fn cmd_unmute(ctx: &mut CommandContext, args: &str) {
+    system_mute(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        false,
+        ctx.sdata,
+        ctx.nodes,
+    );
+}
+
+fn cmd_drain(ctx: &mut CommandContext, args: &str) {
+    system_drain(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        true,
+        ctx.sdata,
+        ctx.nodes,
+    );
+}
+
+fn cmd_undrain(ctx: &mut CommandContext, args: &str) {
+    system_drain(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        false,
+        ctx.sdata,
+        ctx.nodes,
+    );
+}
+
+fn cmd_becomedebugger(ctx: &mut CommandContext, args: &str) {
+    system_becomedebugger(
+        ctx.sys(),
+        ctx.node,
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        ctx.nodes,
+    );
+}
+
+fn cmd_watch(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let (node, fromnode, pattern) = (ctx.node, ctx.fromnode, args.trim());
+    let patterns = ctx.nodes.watchers.get(node).map_or(&[][..], Vec::as_slice);
+    let msg = if pattern.is_empty() {
+        format!("{sys}>{fromnode} @watch Er: Parameter is not enough.\n")
+    } else if patterns.iter().any(|(p, _)| p == pattern) {
+        format!("{sys}>{fromnode} @watch Er: Pattern {pattern} is already watched.\n")
+    } else if patterns.len() >= MAX_WATCH_PATTERNS {
+        format!("{sys}>{fromnode} @watch Er: Too many patterns (max {MAX_WATCH_PATTERNS}).\n")
+    } else if let Ok(re) = Regex::new(&wildcard_to_regex(pattern)) {
+        let patterns = ctx.nodes.watchers.entry(node.to_string()).or_default();
+        patterns.push((pattern.to_string(), re));
+        format!("{sys}>{fromnode} @watch {pattern}\n")
+    } else {
+        format!("{sys}>{fromnode} @watch Er: Invalid pattern {pattern}.\n")
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_unwatch(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let (node, fromnode, pattern) = (ctx.node, ctx.fromnode, args.trim());
+    let removed = match ctx.nodes.watchers.get_mut(node) {
+        Some(patterns) => {
+            let count = patterns.len();
+            patterns.retain(|(p, _)| p != pattern);
+            count != patterns.len()
+        }
+        None => false,
+    };
+    if ctx.nodes.watchers.get(node).is_some_and(Vec::is_empty) {
+        ctx.nodes.watchers.remove(node);
+    }
+    let msg = if removed {
+        format!("{sys}>{fromnode} @unwatch {pattern}\n")
+    } else {
+        format!("{sys}>{fromnode} @unwatch Er: Pattern {pattern} is not watched.\n")
+    };
+    ctx.reply(msg);
+}
+
+// Runs the registration key check without registering, for 'Bad node name or key' failures.
+fn cmd_verifykey(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let mut parts = args.split_whitespace();
+    let msg = match (parts.next(), parts.next(), parts.next(), parts.next()) {
+        (Some(name), Some(key), challenge, None) => match challenge.map_or(Ok(0), str::parse) {
+            Ok(challenge) => {
+                let valid = check_nodekey(name, challenge, key, &ctx.sdata.keydir);
+                let result = if valid { "valid" } else { "invalid" };
+                format!("{sys}>{} @verifykey {name} {result}\n", ctx.fromnode)
+            }
+            Err(_) => format!("{sys}>{} @verifykey Er: Invalid challenge.\n", ctx.fromnode),
+        },
+        _ => format!(
+            "{sys}>{} @verifykey Er: Parameter is not enough.\n",
+            ctx.fromnode
+        ),
+    };
+    ctx.reply(msg);
+}
+
+// A gateway connection may take further names: 'claim <name> <key>'. The name is checked
+// like a registration, with the nodekey the connection got at connect.
+fn cmd_claim(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let (node, fromnode) = (ctx.node, ctx.fromnode);
+    let reply = |msg: &str| format!("{sys}>{fromnode} @claim {msg}\n");
+    let Some((name, key)) = args.split_once(' ') else {
+        ctx.reply(reply("Er: Parameter is not enough."));
+        return;
+    };
+    let nodekey = ctx.sdata.nodeinfo.get(node).map_or(0, |info| info.nodekey);
+    let claimed = ctx.nodes.claims.get(node).map_or(0, Vec::len);
+    let msg = if !SEARCHSYSNAME.is_match(name) || name == sys {
+        reply(&format!("Er: Invalid node name {name}."))
+    } else if ctx.nodes.contains_key(name) || ctx.sdata.aliasreal.contains_key(name) {
+        reply(&format!("Er: {name} already exists."))
+    } else if claimed >= MAX_CLAIMS {
+        reply(&format!("Er: Too many names, the maximum is {MAX_CLAIMS}."))
+    } else if !check_term_and_host(name, ctx.stream, &ctx.sdata.libdir) {
+        TrafficStats::add(&ACCEPTS.rejected_badhost, 1);
+        reply(&format!("Er: Bad host for {name}."))
+    } else if !check_node_prefix(name, ctx.stream, &ctx.sdata.libdir) {
+        TrafficStats::add(&ACCEPTS.rejected_badhost, 1);
+        reply("Er: Node name not permitted from this host.")
+    } else if !check_nodekey(name, nodekey as usize, key.trim(), &ctx.sdata.keydir) {
+        TrafficStats::add(&ACCEPTS.rejected_badkey, 1);
+        reply(&format!("Er: Bad node name or key for {name}."))
+    } else {
+        let stream = ctx.stream.try_clone().expect("stream clone failed!");
+        ctx.nodes.insert(name.to_string(), stream);
+        ctx.nodes
+            .claims
+            .entry(node.to_string())
+            .or_default()
+            .push(name.to_string());
+        console::print(Style::Connect, format!("{name} claimed by {node}."));
+        reply(name)
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_pause(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = if ctx.sdata.paused {
+        format!("{sys}>{fromnode} @pause Er: Routing is already paused.\n")
+    } else {
+        ctx.sdata.paused = true;
+        events::notify(ctx.event_tx, ServerEvent::RoutingPaused { paused: true });
+        format!("{sys}>{fromnode} @pause Routing paused.\n")
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_lockregistrations(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = if ctx.sdata.registrations_locked {
+        format!("{sys}>{fromnode} @lockregistrations Er: Registrations are already locked.\n")
+    } else {
+        ctx.sdata.registrations_locked = true;
+        events::notify(
+            ctx.event_tx,
+            ServerEvent::RegistrationsLocked { locked: true },
+        );
+        format!("{sys}>{fromnode} @lockregistrations Registrations locked.\n")
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_unlockregistrations(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = if ctx.sdata.registrations_locked {
+        ctx.sdata.registrations_locked = false;
+        events::notify(
+            ctx.event_tx,
+            ServerEvent::RegistrationsLocked { locked: false },
+        );
+        format!("{sys}>{fromnode} @unlockregistrations Registrations unlocked.\n")
+    } else {
+        format!("{sys}>{fromnode} @unlockregistrations Er: Registrations are not locked.\n")
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_resume(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = if ctx.sdata.paused {
+        ctx.sdata.paused = false;
+        let replay: Vec<PausedMessage> = ctx.sdata.paused_messages.drain(..).collect();
+        ctx.sdata.paused_bytes = 0;
+        let count = replay.len();
+        ctx.sdata.replay = replay;
+        events::notify(ctx.event_tx, ServerEvent::RoutingPaused { paused: false });
+        format!("{sys}>{fromnode} @resume Routing resumed, {count} message(s) to replay.\n")
+    } else {
+        format!("{sys}>{fromnode} @resume Er: Routing is not paused.\n")
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_settimeout(ctx: &mut CommandContext, args: &str) {
+    system_settimeout(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        args,
+        ctx.sdata,
+        ctx.nodes,
+    );
+}
+
+fn cmd_reopenlog(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = match console::reopen_logfile() {
+        Ok(true) => format!("{sys}>{fromnode} @reopenlog Log file reopened.\n"),
+        Ok(false) => format!("{sys}>{fromnode} @reopenlog Er: No log file.\n"),
+        Err(err) => format!("{sys}>{fromnode} @reopenlog Er: {err}\n"),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_setloglevel(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = match args.trim().parse::<LogLevel>() {
+        Ok(level) => {
+            console::set_level(level);
+            format!("{sys}>{fromnode} @setloglevel {}\n", level.name())
+        }
+        Err(()) => format!("{sys}>{fromnode} @setloglevel Er: Unknown log level {args}.\n"),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_gcnodes(ctx: &mut CommandContext, _: &str) {
+    system_gcnodes(
+        ctx.sys(),
+        ctx.stream,
+        ctx.fromnode,
+        ctx.sdata,
+        ctx.nodes,
+        ctx.event_tx,
+    );
+}
+
+// Subscriptions made before the target connects are removed as well.
+fn cmd_gcsubscriptions(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let nodes = &ctx.nodes;
+    let removed = ctx
+        .sdata
+        .prune_subscriptions(|name| nodes.contains_key(name), None);
+    let msg = format!(
+        "{sys}>{} @gcsubscriptions Removed {removed} subscription(s).\n",
+        ctx.fromnode
+    );
+    ctx.reply(msg);
+}
+
+// The accept loop shuts down, as the locks held here must be released for the grace time.
+fn cmd_shutdown(ctx: &mut CommandContext, _: &str) {
+    ctx.sdata.shutdown_requested.store(true, Ordering::SeqCst);
+}
+
+fn cmd_isup(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let name = ctx.sdata.aliasreal.get(args).map_or(args, String::as_str);
+    let state = if ctx.nodes.contains_key(name) {
+        "up"
+    } else {
+        "down"
+    };
+    let msg = format!("{sys}>{} @isup {args} {state}\n", ctx.fromnode);
+    ctx.reply(msg);
+}
+
+// Dry-run of sendmes with the caller as sender, for checking the alias and permission
+// files. Nothing is sent and no event is emitted.
+fn cmd_testroute(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let Some((to, cmd)) = args
+        .split_once(' ')
+        .filter(|(_, cmd)| !cmd.trim().is_empty())
+    else {
+        ctx.reply(format!(
+            "{sys}>{fromnode} @testroute Er: Parameter is not enough.\n"
+        ));
+        return;
+    };
+    let (route, tonodes) = resolve_route(
+        ctx.node,
+        to,
+        cmd.trim(),
+        false,
+        ctx.nodes,
+        ctx.sdata,
+        ctx.config,
+    );
+    let tonode = tonodes.split('.').next().unwrap_or_default();
+    let result = match route {
+        Route::Deliver | Route::System => format!("would-deliver to {tonodes}"),
+        Route::Down => "target-down".to_string(),
+        Route::Denied => "would-deny: Command denied.".to_string(),
+        Route::Paused => "would-deny: Routing paused.".to_string(),
+        Route::Muted => "would-deny: You are muted.".to_string(),
+        Route::NotHandled => format!("would-deny: {tonodes} is not handled by {tonode}."),
+        Route::Draining => format!("would-deny: {tonodes} is draining."),
+        Route::NotBinary => format!("would-deny: {tonode} does not accept binary data."),
+    };
+    ctx.reply(format!("{sys}>{fromnode} @testroute {to} {result}\n"));
+}
+
+fn cmd_listnodes(ctx: &mut CommandContext, args: &str) {
+    let list = parse_page(args).map(|page| (page, system_list_nodes(ctx.nodes, page)));
+    reply_list(ctx, "listnodes", list);
+}
+
+fn cmd_listaliases(ctx: &mut CommandContext, args: &str) {
+    let list = parse_page(args).map(|page| (page, system_list_aliases(ctx.sdata, page)));
+    reply_list(ctx, "listaliases", list);
+}
+
+// Both directions of the alias mapping, taken under the one StarsData lock.
+fn cmd_dumpaliases(ctx: &mut CommandContext, args: &str) {
+    if args == "json" {
+        let sys = ctx.sys();
+        let json = system_dump_aliases_json(ctx.sdata);
+        let msg = format!("{sys}>{} @dumpaliases {json}\n", ctx.fromnode);
+        return ctx.reply(msg);
+    }
+    let list = parse_page(args).map(|page| (page, system_dump_aliases(ctx.sdata, page)));
+    reply_list(ctx, "dumpaliases", list);
+}
+
+// One line of DOT, e.g. 'System dumpdot | cut -d' ' -f3- | dot -Tsvg'.
+fn cmd_dumpdot(ctx: &mut CommandContext, _args: &str) {
+    let sys = ctx.sys();
+    let dot = system_dump_dot(ctx.sdata);
+    let msg = format!("{sys}>{} @dumpdot {dot}\n", ctx.fromnode);
+    ctx.reply(msg);
+}
+
+// Page number argument of the list commands, 1 if not given.
+fn parse_page(args: &str) -> Option<usize> {
+    if args.is_empty() {
+        return Some(1);
+    }
+    if !args.bytes().all(|b| b.is_ascii_digit()) {
+        return None;
+    }
+    Some(args.parse().unwrap_or(0))
+}
+
+fn reply_list(ctx: &mut CommandContext, name: &str, list: Option<(usize, Option<String>)>) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = match list {
+        None => return cmd_unknown(ctx, ctx.cmd),
+        Some((_, Some(list))) => format!("{sys}>{fromnode} @{name} {list}\n"),
+        Some((page, None)) => format!("{sys}>{fromnode} @{name} Er: No page {page}.\n"),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_loadhosts(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = match system_load_hosts(ctx.sdata) {
+        Ok(_) => format!("{sys}>{fromnode} @loadhosts Host list has been loaded.\n"),
+        Err(_) => format!("{sys}>{fromnode} @loadhosts Er: Host list has been NOT loaded!\n"),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_loadpermission(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    match args {
+        "" => {}
+        "clear" => ctx.sdata.cmd_overrides.clear(),
+        _ => {
+            ctx.reply(format!(
+                "{sys}>{fromnode} @loadpermission Er: Unknown option {args}.\n"
+            ));
+            return;
+        }
+    }
+    let msg = match system_load_commandpermission(ctx.sdata) {
+        Ok(_) => {
+            format!("{sys}>{fromnode} @loadpermission Command permission list has been loaded.\n")
+        }
+        Err(_) => format!(
+            "{sys}>{fromnode} @loadpermission Er: Command permission list has been NOT loaded!\n"
+        ),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_allowcmd(ctx: &mut CommandContext, args: &str) {
+    set_cmd_override(ctx, "allowcmd", args, true);
+}
+
+fn cmd_denycmd(ctx: &mut CommandContext, args: &str) {
+    set_cmd_override(ctx, "denycmd", args, false);
+}
+
+// 'allowcmd <node> <cmd>': the rule applies to the messages sent by the node, to any target.
+fn set_cmd_override(ctx: &mut CommandContext, name: &str, args: &str, allowed: bool) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = match args.split_whitespace().collect::<Vec<_>>()[..] {
+        [node, cmd] => {
+            ctx.sdata
+                .cmd_overrides
+                .insert((node.to_string(), cmd.to_string()), allowed);
+            let policy = if allowed { "allowed" } else { "denied" };
+            format!("{sys}>{fromnode} @{name} {node} {cmd} {policy}\n")
+        }
+        _ => format!("{sys}>{fromnode} @{name} Er: Usage: {name} <node> <cmd>\n"),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_loadreconnectablepermission(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = match system_load_reconnecttable_permission(ctx.sdata) {
+        Ok(_) => format!(
+            "{sys}>{fromnode} @loadreconnectablepermission Reconnectable permission list has been loaded.\n"
+        ),
+        Err(_) => format!(
+            "{sys}>{fromnode} @loadreconnectablepermission Er: Reconnectable permission list has been NOT loaded!\n"
+        ),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_loadaliases(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let msg = match system_load_aliases(ctx.sdata) {
+        Ok(_) => format!("{sys}>{fromnode} @loadaliases Aliases has been loaded.\n"),
+        Err(_) => format!("{sys}>{fromnode} @loadaliases Er: Aliases has been NOT loaded!\n"),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_listrestricted(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @listrestricted {}\n",
+        ctx.fromnode,
+        system_list_restricted(ctx.sdata)
+    );
+    ctx.reply(msg);
+}
+
+fn cmd_acceptstats(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @acceptstats {}\n",
+        ctx.fromnode,
+        ACCEPTS.summary(ctx.nodes.len())
+    );
+    ctx.reply(msg);
+}
+
+fn cmd_listbyhost(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @listbyhost {}\n",
+        ctx.fromnode,
+        system_list_byhost(ctx.sdata)
+    );
+    ctx.reply(msg);
+}
+
+fn cmd_stats(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @stats nodes={} registrations={} {} invalid_utf8={} paused_dropped={} \
+         mirror_dropped={} {}\n",
+        ctx.fromnode,
+        ctx.nodes.len(),
+        if ctx.sdata.registrations_locked {
+            "locked"
+        } else {
+            "open"
+        },
+        STATS.summary(),
+        TrafficStats::get(&INVALID_UTF8),
+        TrafficStats::get(&PAUSE_DROPS),
+        TrafficStats::get(&MIRROR_DROPS),
+        ACCEPTS.summary(ctx.nodes.len())
+    );
+    ctx.reply(msg);
+}
 
-    let _ = event_tx.send(ServerEvent::NodeConnected { name: node.clone() });
+// 'count name,ip,connected,disconnected,reason ...'
+fn cmd_recentdisconnects(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+    let mut list: Vec<String> = ctx
+        .sdata
+        .recent_disconnects
+        .iter()
+        .rev()
+        .map(|r| {
+            format!(
+                "{},{},{},{},{}",
+                r.node,
+                r.peer
+                    .map_or_else(|| "-".to_string(), |peer| peer.ip().to_string()),
+                r.connected
+                    .map_or_else(|| "-".to_string(), |t| t.format(TIME_FORMAT).to_string()),
+                r.disconnected.format(TIME_FORMAT),
+                r.reason.name()
+            )
+        })
+        .collect();
+    list.insert(0, list.len().to_string());
+    let msg = format!(
+        "{sys}>{} @recentdisconnects {}\n",
+        ctx.fromnode,
+        list.join(" ")
+    );
+    ctx.reply(msg);
+}
 
-    if let Some(n) = sdata.realalias.get(&node) {
-        node = n.to_string();
-    }
-    for key_val in &sdata.nodes_flgon {
-        if key_val.1.contains(&node) {
-            let topre: Vec<String> = key_val.0.split(".").map(str::to_string).collect();
-            if let Some(sock) = nodes_list.get(&topre[0]) {
-                let s = sock.try_clone().expect("stream clone failed!");
-                let msg = format!("{}>{} _Connected\n", node, key_val.0);
-                writemsg(&s, msg, &mut nodes_list);
-            }
-        }
-    }
-    Some(node)
+fn cmd_resources(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @resources {}\n",
+        ctx.fromnode,
+        system_resource_usage()
+    );
+    ctx.reply(msg);
 }
 
-fn delnode(
-    node: &str,
-    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
-    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
-    event_tx: &EventSender,
-) {
-    if let Some(s) = nodes.remove(node) {
-        let mut node = node.to_string();
+// More threads than nodes hints at a handler which was not cleaned up.
+fn cmd_threadinfo(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let threads = ctx.sdata.handlers.load(Ordering::Relaxed);
+    let nodes = ctx.nodes.connections();
+    let state = if threads == nodes { "ok" } else { "mismatch" };
+    let msg = format!(
+        "{sys}>{} @threadinfo threads={threads} nodes={nodes} {state}\n",
+        ctx.fromnode
+    );
+    ctx.reply(msg);
+}
 
-        let _ = event_tx.send(ServerEvent::NodeDisconnected { name: node.clone() });
+// The kinds, then the count per ip since its last ban as 'ips=ip:count,...'.
+fn cmd_violations(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let mut ips: Vec<(&IpAddr, &u32)> = ctx.sdata.violations.iter().collect();
+    ips.sort();
+    let ips: Vec<String> = ips
+        .iter()
+        .map(|(ip, count)| format!("{ip}:{count}"))
+        .collect();
+    let ips = if ips.is_empty() {
+        "-".to_string()
+    } else {
+        ips.join(",")
+    };
+    let msg = format!(
+        "{sys}>{} @violations {} ips={ips}\n",
+        ctx.fromnode,
+        ctx.sdata.violation_stats.summary()
+    );
+    ctx.reply(msg);
+}
 
-        let stream_ref = s.try_clone().expect("stream clone failed!");
-        match stream_ref.shutdown(Shutdown::Both) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("Shutdown call failed ({}): {}", &node, err);
-            }
-        }
-        sdata.nodes_flgon.remove(&node);
-        if let Some(n) = sdata.realalias.get(&node) {
-            node = n.to_string();
-        }
-        for key_val in &sdata.nodes_flgon {
-            if key_val.1.contains(&node) {
-                let topre: Vec<String> = key_val.0.split(".").map(str::to_string).collect();
-                if let Some(sock) = nodes.get(&topre[0]) {
-                    let s = sock.try_clone().expect("stream clone failed!");
-                    let msg = format!("{}>{} _Disconnected\n", node, key_val.0);
-                    writemsg(&s, msg, nodes);
-                }
-            }
-        }
-    }
+// The counts per ip towards --autoban-threshold start over as well, the bans stay.
+fn cmd_resetviolations(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    ctx.sdata.violation_stats.reset();
+    ctx.sdata.violations.clear();
+    ctx.reply(format!(
+        "{sys}>{fromnode} @resetviolations Violation counters have been reset.\n"
+    ));
 }
 
-fn system_commands(
-    node: &str,
-    stream: &TcpStream,
-    fromnode: &str,
-    cmd: &str,
-    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
-    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
-) {
-    if cmd.starts_with("_") {
-        system_event(node, cmd, nodes, sdata);
-    } else if SEARCHDISCONN.is_match(cmd) {
-        let msg = cmd.replace("disconnect ", "");
-        system_disconnect(stream, fromnode, &msg, sdata, nodes);
-    } else if SEARCHFLGON.is_match(cmd) {
-        let msg = cmd.replace("flgon ", "");
-        system_flgon(stream, fromnode, &msg, sdata, nodes);
-    } else if SEARCHFLGOFF.is_match(cmd) {
-        let msg = cmd.replace("flgoff ", "");
-        system_flgoff(stream, fromnode, &msg, sdata, nodes);
+fn cmd_nodestats(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @nodestats {}\n",
+        ctx.fromnode,
+        system_list_nodestats(ctx.sdata)
+    );
+    ctx.reply(msg);
+}
+
+// An alias is answered for its real node, a claimed name for the node which claimed it.
+fn cmd_nodeinfo(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let name = ctx.sdata.aliasreal.get(args).map_or(args, String::as_str);
+    let name = ctx.nodes.owner(name).unwrap_or(name);
+    let msg = match system_node_info(ctx.sdata, name) {
+        Some(info) => format!("{sys}>{fromnode} @nodeinfo {name} {info}\n"),
+        None => format!("{sys}>{fromnode} @nodeinfo Er: Node {args} is down.\n"),
+    };
+    ctx.reply(msg);
+}
+
+fn cmd_idlenodes(ctx: &mut CommandContext, args: &str) {
+    let sys = ctx.sys();
+    let fromnode = ctx.fromnode;
+    let secs = if args.is_empty() {
+        Ok(0)
     } else {
-        match cmd {
-            "loadpermission" => match system_load_commandpermission(sdata) {
-                Ok(_) => {
-                    let msg = format!(
-                        "System>{fromnode} @loadpermission Command permission list has been loaded.\n"
-                    );
-                    writemsg(stream, msg, nodes);
-                }
-                Err(_) => {
-                    let msg = format!(
-                        "System>{fromnode} @loadpermission Er: Command permission list has been NOT loaded!\n"
-                    );
-                    writemsg(stream, msg, nodes);
-                }
-            },
-            "loadreconnectablepermission" => match system_load_reconnecttable_permission(sdata) {
-                Ok(_) => {
-                    let msg = format!(
-                        "System>{fromnode} @loadreconnectablepermission Reconnectable permission list has been loaded.\n"
-                    );
-                    writemsg(stream, msg, nodes);
-                }
-                Err(_) => {
-                    let msg = format!(
-                        "System>{fromnode} @loadreconnectablepermission Er: Reconnectable permission list has been NOT loaded!\n"
-                    );
-                    writemsg(stream, msg, nodes);
-                }
-            },
-            "loadaliases" => match system_load_aliases(sdata) {
-                Ok(_) => {
-                    let msg = format!("System>{fromnode} @loadaliases Aliases has been loaded.\n");
-                    writemsg(stream, msg, nodes);
-                }
-                Err(_) => {
-                    let msg = format!(
-                        "System>{fromnode} @loadaliases Er: Aliases has been NOT loaded!\n"
-                    );
-                    writemsg(stream, msg, nodes);
-                }
-            },
-            "listaliases" => {
-                let msg = format!(
-                    "System>{} @listaliases {}\n",
-                    fromnode,
-                    system_list_aliases(sdata)
-                );
-                writemsg(stream, msg, nodes);
-            }
-            "listnodes" => {
-                let msg = format!(
-                    "System>{} @listnodes {}\n",
-                    fromnode,
-                    system_list_nodes(nodes)
-                );
-                writemsg(stream, msg, nodes);
-            }
-            "getversion" => {
-                let msg =
-                    format!("System>{fromnode} @getversion Version: {VERSION} (Rust Server)\n");
-                writemsg(stream, msg, nodes)
-            }
-            "gettime" => {
-                let msg = format!("System>{} @gettime {}\n", fromnode, system_get_time());
-                writemsg(stream, msg, nodes)
-            }
-            "hello" => {
-                let msg = format!("System>{fromnode} @hello Nice to meet you.\n");
-                writemsg(stream, msg, nodes);
-            }
-            "help" => {
-                let msg = format!(
-                    "System>{fromnode} @help flgon flgoff loadaliases listaliases loadpermission loadreconnectablepermission listnodes shutdown getversion gettime hello disconnect\n",
-                );
-                writemsg(stream, msg, nodes);
-            }
-            "shutdown" => {
-                if !sdata.shutallow.is_empty() && is_shutdowncmd_allow(fromnode, &sdata.shutallow) {
-                    system_shutdown(nodes);
-                } else {
-                    let msg = format!("System>{fromnode} @shutdown Er: Command denied.\n");
-                    writemsg(stream, msg, nodes);
-                }
-            }
-            _ => {
-                let msg = format!(
-                    "System>{fromnode} @{cmd} Er: Command is not found or parameter is not enough!\n"
-                );
-                writemsg(stream, msg, nodes);
-            }
+        args.parse::<u64>()
+    };
+    let msg = match secs {
+        Ok(secs) => {
+            let idle = system_list_idle(ctx.sdata, ctx.nodes, Duration::from_secs(secs));
+            format!("{sys}>{fromnode} @idlenodes {idle}\n")
         }
+        Err(_) => format!("{sys}>{fromnode} @idlenodes Er: Invalid number of seconds.\n"),
     };
+    ctx.reply(msg);
+}
+
+fn cmd_getversion(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @getversion Version: {VERSION} (Rust Server)\n",
+        ctx.fromnode
+    );
+    ctx.reply(msg);
+}
+
+fn cmd_buildinfo(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @buildinfo {}\n",
+        ctx.fromnode,
+        system_get_buildinfo()
+    );
+    ctx.reply(msg);
+}
+
+fn cmd_gettime(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!("{sys}>{} @gettime {}\n", ctx.fromnode, system_get_time());
+    ctx.reply(msg);
+}
+
+fn cmd_hello(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!("{sys}>{} @hello Nice to meet you.\n", ctx.fromnode);
+    ctx.reply(msg);
+}
+
+fn cmd_help(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let names: Vec<&str> = SYSTEM_COMMANDS.iter().map(|c| c.name).collect();
+    let msg = format!("{sys}>{} @help {}\n", ctx.fromnode, names.join(" "));
+    ctx.reply(msg);
+}
+
+fn cmd_listcommands(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    for command in SYSTEM_COMMANDS {
+        let msg = format!(
+            "{sys}>{} @listcommands {} {}\n",
+            ctx.fromnode, command.name, command.description
+        );
+        ctx.reply(msg);
+    }
+}
+
+// The line as split by handle_node, before the from/to parts were removed.
+fn cmd_rawecho(ctx: &mut CommandContext, _: &str) {
+    let sys = ctx.sys();
+    let msg = format!(
+        "{sys}>{} @rawecho {}\n",
+        ctx.fromnode,
+        ctx.line.escape_debug()
+    );
+    ctx.reply(msg);
 }
 
 fn system_event(
+    sys: &str,
     node: &str,
     cmd: &str,
     nodes: &mut std::sync::MutexGuard<'_, NodeList>,
-    sdata: &std::sync::MutexGuard<'_, StarsData>,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    hop_limit: u32,
 ) {
+    // Nodes answering each other's events would bounce them forever. The hop count is only
+    // kept in the server, the events on the wire are unchanged.
+    let hop = sdata
+        .nodeinfo
+        .get(node)
+        .and_then(|info| info.event_hop)
+        .filter(|(_, received)| received.elapsed() < EVENT_HOP_WINDOW)
+        .map_or(0, |(hop, _)| hop + 1);
+    if hop_limit > 0 && hop >= hop_limit {
+        let name = cmd.split_whitespace().next().unwrap_or(cmd);
+        console::warn(format!(
+            "Event {name} of {node} dropped after {hop} hops (flgon loop?)."
+        ));
+        return;
+    }
     let mut frn = node.to_string();
     if let Some(n) = sdata.aliasreal.get(&frn) {
         frn = n.to_string();
     }
-    for key_val in &sdata.nodes_flgon {
-        if key_val.1.contains(&frn) {
-            let topre: Vec<String> = key_val.0.split(".").map(str::to_string).collect();
-            let to = &topre[0];
-            if let Some(sock) = nodes.get(&topre[0]) {
-                let s = sock.try_clone().expect("stream clone failed!");
-                let msg = format!("{frn}>{to} {cmd}\n");
-                writemsg(&s, msg, nodes);
-            }
+    if sdata.nodeinfo.get(node).is_some_and(|info| info.retain) {
+        sdata.retain_message(&frn, sys, cmd);
+    }
+    let now = Instant::now();
+    for to in notify_subscribers(node, cmd, nodes, sdata) {
+        if let Some(info) = sdata.nodeinfo.get_mut(&to) {
+            info.event_hop = Some((hop, now));
         }
     }
 }
 
 fn system_disconnect(
+    sys: &str,
     stream: &TcpStream,
     fromnode: &str,
     cmd: &str,
     sdata: &mut std::sync::MutexGuard<'_, StarsData>,
     nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    event_tx: &EventSender,
 ) {
     if !SEARCHPARAM.is_match(cmd) {
-        let msg = format!("System>{fromnode} @disconnect Er: Parameter is not enough.\n");
+        let msg = format!("{sys}>{fromnode} @disconnect Er: Parameter is not enough.\n");
         writemsg(stream, msg, nodes);
         return;
     }
@@ -625,128 +3415,425 @@ fn system_disconnect(
     match nodes.get(&cmd) {
         Some(_) => {}
         None => {
-            let msg = format!("System>{fromnode} @disconnect Er: Node {cmd} is down.\n");
+            let msg = format!("{sys}>{fromnode} @disconnect Er: Node {cmd} is down.\n");
             writemsg(stream, msg, nodes);
             return;
         }
     }
-    let msg = format!("System>{fromnode} @disconnect {cmd}.\n");
+    let msg = format!("{sys}>{fromnode} @disconnect {cmd}.\n");
     writemsg(stream, msg, nodes);
-    // Note: system_disconnect does not send event_tx because it's called from
-    // within system_commands which doesn't have access to event_tx.
-    // The node will be cleaned up when its handle_node thread detects the disconnect.
-    if let Some(s) = nodes.remove(&cmd) {
-        let mut node = cmd.to_string();
-        let stream_ref = s.try_clone().expect("stream clone failed!");
-        match stream_ref.shutdown(Shutdown::Both) {
-            Ok(_) => (),
-            Err(err) => {
-                eprintln!("Shutdown call failed ({}): {}", &node, err);
-            }
+    delnode(&cmd, nodes, sdata, event_tx, DisconnectReason::Command);
+}
+
+// Disconnects every node except the requesting one. The server keeps accepting new connections.
+// The claimed names go with the node that claimed them, those of the requesting node stay.
+#[allow(clippy::too_many_arguments)]
+fn system_disconnectall(
+    sys: &str,
+    node: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    reason: &str,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    event_tx: &EventSender,
+) {
+    let reason = if reason.is_empty() {
+        "Disconnected by server."
+    } else {
+        reason
+    };
+    let targets: Vec<String> = nodes
+        .keys()
+        .filter(|n| *n != node && !nodes.is_claimed(n))
+        .cloned()
+        .collect();
+    for target in &targets {
+        if let Some(sock) = nodes.get(target) {
+            let s = sock.try_clone().expect("stream clone failed!");
+            let msg = format!("{sys}>{target} Er: {reason}\n");
+            writemsg(&s, msg, nodes);
+        }
+    }
+    for target in &targets {
+        delnode(target, nodes, sdata, event_tx, DisconnectReason::Command);
+    }
+    let msg = format!(
+        "{sys}>{fromnode} @disconnectall {} node(s) disconnected.\n",
+        targets.len()
+    );
+    writemsg(stream, msg, nodes);
+}
+
+// Only one node at a time gets the copy of all messages.
+// It stays the debugger until it disconnects.
+// 'becomedebugger <node> [in|out|both]' limits the copies to the messages to and/or from <node>,
+// tagged with '[in] ' or '[out] '.
+fn system_becomedebugger(
+    sys: &str,
+    node: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    args: &str,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+) {
+    let args: Vec<&str> = args.split_whitespace().collect();
+    let direction = match args.get(1).copied() {
+        None | Some("both") => Some(Direction::Both),
+        Some("in") => Some(Direction::In),
+        Some("out") => Some(Direction::Out),
+        Some(_) => None,
+    };
+    let Some(direction) = direction.filter(|_| args.len() <= 2) else {
+        let msg = format!(
+            "{sys}>{fromnode} @becomedebugger Er: Usage: becomedebugger [node [in|out|both]]\n"
+        );
+        writemsg(stream, msg, nodes);
+        return;
+    };
+    let msg = match &nodes.debugger {
+        Some(debugger) if debugger != node => {
+            format!("{sys}>{fromnode} @becomedebugger Er: {debugger} is already the debugger.\n")
         }
-        sdata.nodes_flgon.remove(&node);
-        if let Some(n) = sdata.realalias.get(&node) {
-            node = n.to_string();
+        _ => {
+            nodes.debugger = Some(node.to_string());
+            nodes.debug_filter = args.first().map(|target| DebugFilter {
+                node: target.to_string(),
+                direction,
+            });
+            format!("{sys}>{fromnode} @becomedebugger Ok:\n")
         }
-        for key_val in &sdata.nodes_flgon {
-            if key_val.1.contains(&node) {
-                let topre: Vec<String> = key_val.0.split(".").map(str::to_string).collect();
-                if let Some(sock) = nodes.get(&topre[0]) {
-                    let s = sock.try_clone().expect("stream clone failed!");
-                    let msg = format!("{}>{} _Disconnected\n", node, key_val.0);
-                    writemsg(&s, msg, nodes);
-                }
-            }
+    };
+    writemsg(stream, msg, nodes);
+}
+
+// Only registrations started after the change use the new timeout.
+fn system_settimeout(
+    sys: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    value: &str,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+) {
+    let msg = match value.trim().parse::<u64>() {
+        Ok(timeout) if timeout <= MAX_TIMEOUT => {
+            let old = sdata.timeout;
+            sdata.timeout = timeout;
+            format!("{sys}>{fromnode} @settimeout Timeout changed from {old} to {timeout} msec.\n")
+        }
+        Ok(_) => format!("{sys}>{fromnode} @settimeout Er: Maximum is {MAX_TIMEOUT} msec.\n"),
+        Err(_) => format!("{sys}>{fromnode} @settimeout Er: Invalid timeout.\n"),
+    };
+    writemsg(stream, msg, nodes);
+}
+
+// Removes the nodes whose connection fails the liveness probe. The probe sends no data,
+// so idle nodes are not affected. The claimed names go with the node that claimed them.
+fn system_gcnodes(
+    sys: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    event_tx: &EventSender,
+) {
+    let dead: Vec<String> = nodes
+        .iter()
+        .filter(|(name, s)| !nodes.is_claimed(name) && is_connection_dead(s))
+        .map(|(name, _)| name.clone())
+        .collect();
+    for name in &dead {
+        delnode(name, nodes, sdata, event_tx, DisconnectReason::Dead);
+    }
+    let msg = format!(
+        "{sys}>{fromnode} @gcnodes {} node(s) removed.\n",
+        dead.len()
+    );
+    writemsg(stream, msg, nodes);
+}
+
+// Muted nodes stay connected, but their messages to other nodes are dropped.
+fn system_mute(
+    sys: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    cmd: &str,
+    mute: bool,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+) {
+    let cmdname = if mute { "mute" } else { "unmute" };
+    if !SEARCHPARAM.is_match(cmd) {
+        let msg = format!("{sys}>{fromnode} @{cmdname} Er: Parameter is not enough.\n");
+        writemsg(stream, msg, nodes);
+        return;
+    }
+    let mut cmd = cmd.to_string();
+    if let Some(v) = sdata.aliasreal.get(&cmd) {
+        cmd = v.to_string();
+    }
+    if let Some(owner) = nodes.owner(&cmd) {
+        cmd = owner.to_string();
+    }
+    let msg = match sdata.nodeinfo.get_mut(&cmd) {
+        Some(info) => {
+            info.muted = mute;
+            format!("{sys}>{fromnode} @{cmdname} {cmd}.\n")
         }
+        None => format!("{sys}>{fromnode} @{cmdname} Er: Node {cmd} is down.\n"),
+    };
+    writemsg(stream, msg, nodes);
+}
+
+// Draining nodes stay connected, but the messages to them are rejected. The receive side
+// counterpart of mute, e.g. for a device which is recalibrating.
+fn system_drain(
+    sys: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    cmd: &str,
+    drain: bool,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+) {
+    let cmdname = if drain { "drain" } else { "undrain" };
+    if !SEARCHPARAM.is_match(cmd) {
+        let msg = format!("{sys}>{fromnode} @{cmdname} Er: Parameter is not enough.\n");
+        writemsg(stream, msg, nodes);
+        return;
+    }
+    let mut cmd = cmd.to_string();
+    if let Some(v) = sdata.aliasreal.get(&cmd) {
+        cmd = v.to_string();
+    }
+    if let Some(owner) = nodes.owner(&cmd) {
+        cmd = owner.to_string();
     }
+    let msg = match sdata.nodeinfo.get_mut(&cmd) {
+        Some(info) => {
+            info.draining = drain;
+            format!("{sys}>{fromnode} @{cmdname} {cmd}.\n")
+        }
+        None => format!("{sys}>{fromnode} @{cmdname} Er: Node {cmd} is down.\n"),
+    };
+    writemsg(stream, msg, nodes);
 }
 
+// 'a,b,c' subscribes to each of them, with one reply line per target.
 fn system_flgon(
+    sys: &str,
     stream: &TcpStream,
     fromnode: &str,
     cmd: &str,
     sdata: &mut std::sync::MutexGuard<'_, StarsData>,
     nodes: &mut std::sync::MutexGuard<'_, NodeList>,
 ) {
+    if cmd.contains(',') {
+        for target in cmd.split(',').map(str::trim) {
+            if is_node_param(target) {
+                flgon_target(sys, stream, fromnode, target, sdata, nodes);
+            } else {
+                invalid_flg_target(sys, stream, fromnode, "flgon", target, nodes);
+            }
+        }
+        return;
+    }
     if !SEARCHPARAM.is_match(cmd) {
-        let msg = format!("System>{fromnode} @disconnect Er: Parameter is not enough.\n");
+        let msg = format!("{sys}>{fromnode} @flgon Er: Parameter is not enough.\n");
         writemsg(stream, msg, nodes);
         return;
     }
+    flgon_target(sys, stream, fromnode, cmd, sdata, nodes);
+}
+
+// A target of a comma separated flgon/flgoff list, unlike SEARCHPARAM alone the whole
+// of it has to be a node name.
+fn is_node_param(target: &str) -> bool {
+    SEARCHPARAM
+        .find(target)
+        .is_some_and(|m| m.end() == target.len())
+}
+
+// The rest of the list is still handled.
+fn invalid_flg_target(
+    sys: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    cmdname: &str,
+    target: &str,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+) {
+    let msg = format!("{sys}>{fromnode} @{cmdname} Er: Invalid node name '{target}', skipped.\n");
+    writemsg(stream, msg, nodes);
+}
+
+fn flgon_target(
+    sys: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    cmd: &str,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+) {
     match sdata.nodes_flgon.get_mut(fromnode) {
         Some(flg_list) => {
             if flg_list.contains(cmd) {
                 let msg =
-                    format!("System>{fromnode} @flgon Er: Node {cmd} is allready in the list.\n");
+                    format!("{sys}>{fromnode} @flgon Er: Node {cmd} is allready in the list.\n");
                 writemsg(stream, msg, nodes);
                 return;
             }
             flg_list.insert(cmd.to_string());
-            let msg = format!("System>{fromnode} @flgon Node {cmd} has been registered.\n");
+            let msg = format!("{sys}>{fromnode} @flgon Node {cmd} has been registered.\n");
             writemsg(stream, msg, nodes);
         }
         _ => {
             let mut val: HashSet<String> = HashSet::new();
             val.insert(cmd.to_string());
             sdata.nodes_flgon.insert(fromnode.to_string(), val);
-            let msg = format!("System>{fromnode} @flgon Node {cmd} has been registered.\n");
+            let msg = format!("{sys}>{fromnode} @flgon Node {cmd} has been registered.\n");
             writemsg(stream, msg, nodes);
         }
     }
+    // Late joiners get the last messages of the node at once, its events addressed to them.
+    let to = fromnode.split('.').next().unwrap_or(fromnode);
+    for ((from, dest), body) in sdata.retained.iter().filter(|((from, _), _)| from == cmd) {
+        let dest = if dest == sys { to } else { dest };
+        let msg = format!("{from}>{dest} {body}\n");
+        writemsg(stream, msg, nodes);
+    }
 }
 
-#[allow(unused_assignments)]
+// 'a,b,c' is handled target by target, with one reply line each.
 fn system_flgoff(
+    sys: &str,
     stream: &TcpStream,
     fromnode: &str,
     cmd: &str,
     sdata: &mut std::sync::MutexGuard<'_, StarsData>,
     nodes: &mut std::sync::MutexGuard<'_, NodeList>,
 ) {
+    if cmd.contains(',') {
+        for target in cmd.split(',').map(str::trim) {
+            if is_node_param(target) {
+                flgoff_target(sys, stream, fromnode, target, sdata, nodes);
+            } else {
+                invalid_flg_target(sys, stream, fromnode, "flgoff", target, nodes);
+            }
+        }
+        return;
+    }
     if !SEARCHPARAM.is_match(cmd) {
-        let msg = format!("System>{fromnode} @disconnect Er: Parameter is not enough.\n");
+        let msg = format!("{sys}>{fromnode} @flgoff Er: Parameter is not enough.\n");
         writemsg(stream, msg, nodes);
         return;
     }
+    flgoff_target(sys, stream, fromnode, cmd, sdata, nodes);
+}
+
+#[allow(unused_assignments)]
+fn flgoff_target(
+    sys: &str,
+    stream: &TcpStream,
+    fromnode: &str,
+    cmd: &str,
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+) {
     match sdata.nodes_flgon.get_mut(fromnode) {
         Some(flg_list) => {
             let mut msg = String::new();
             if flg_list.remove(cmd) {
-                msg = format!("System>{fromnode} @flgoff Node {cmd} has been removed.\n");
+                msg = format!("{sys}>{fromnode} @flgoff Node {cmd} has been removed.\n");
             } else {
-                msg = format!("System>{fromnode} @flgoff Er: Node {cmd} is not in the list.\n");
+                msg = format!("{sys}>{fromnode} @flgoff Er: Node {cmd} is not in the list.\n");
             }
             writemsg(stream, msg, nodes);
+            sdata.forget_flg_history();
         }
         _ => {
-            let msg = format!("System>{fromnode} @flgoff Er: List is void.\n");
+            let msg = format!("{sys}>{fromnode} @flgoff Er: List is void.\n");
             writemsg(stream, msg, nodes);
         }
     }
 }
 
-fn system_shutdown(nodes: &mut std::sync::MutexGuard<'_, NodeList>) {
+// The notice is followed by a half close, so it is flushed before the FIN. A claimed name
+// shares the connection of its owner and gets no notice of its own. The state file is
+// written first, the subscriptions of the nodes are dropped as they disconnect. Returns
+// the notified nodes for close_after_grace.
+fn system_shutdown(
+    sys: &str,
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    sdata: &StarsData,
+    config: &ServerConfig,
+) -> Vec<(String, TcpStream)> {
     println!("SYSTEM SHUTDOWN! -> {}", system_get_time());
-    for (node, s) in nodes.iter_mut() {
+    if let Some(path) = &config.state_file
+        && let Err(err) = sdata.save_state(path)
+    {
+        console::error(format!("Can't save the state to {path}: {err}"));
+    }
+    syslog::log(Severity::Notice, "shutdown", sys, None, "System shutdown.");
+    let (crlf, retries) = (nodes.crlf, nodes.write_retries);
+    let mut notified = Vec::new();
+    for (node, s) in nodes.iter() {
+        if nodes.is_claimed(node) {
+            continue;
+        }
         let stream_ref = s.try_clone().expect("stream clone failed!");
-        let msg = format!("System>{} SYSTEMSHUTDOWN\n", node);
-        sendtonode(&stream_ref, &msg);
-        match stream_ref.shutdown(Shutdown::Both) {
-            Ok(_) => (),
+        let msg = format!("{sys}>{} SYSTEMSHUTDOWN\n", node);
+        sendtonode(&stream_ref, &msg, crlf, retries);
+        match stream_ref.shutdown(Shutdown::Write) {
+            Ok(_) => notified.push((node.clone(), stream_ref)),
             Err(err) => {
-                eprintln!("Shutdown call failed ({}): {}", &node, err);
+                console::error(format!("Shutdown call failed ({}): {}", &node, err));
             }
         }
     }
-    process::exit(0);
+    notified
 }
 
-fn startcheck(sc: GenericResult<()>) {
-    match sc {
-        Ok(_) => {}
-        Err(err) => {
-            eprintln!("Initialization faild! Server will not start!\n{err}");
-            process::exit(1);
+// The handler of a node removes it once the node has closed its side. The nodes get the
+// grace time (in total, not per node) for it, then the remaining sockets are closed. The
+// accept loop ends then and run() returns. The visualization gets the ServerShutdown
+// event and closes its window.
+fn close_after_grace(
+    nodes: &Mutex<NodeList>,
+    notified: Vec<(String, TcpStream)>,
+    config: &ServerConfig,
+) {
+    let deadline = Instant::now() + Duration::from_millis(config.shutdown_grace);
+    let total = notified.len();
+    let mut open = notified;
+    loop {
+        {
+            let nodes = nodes.lock().expect("can't get the lock!");
+            open.retain(|(node, _)| nodes.contains_key(node));
         }
+        if open.is_empty() || Instant::now() >= deadline {
+            break;
+        }
+        thread::sleep(SHUTDOWN_POLL_INTERVAL);
+    }
+    for (node, stream) in &open {
+        // Fails with NotConnected if the node has closed in the meantime.
+        if let Err(err) = stream.shutdown(Shutdown::Both)
+            && err.kind() != ErrorKind::NotConnected
+        {
+            console::error(format!("Shutdown call failed ({node}): {err}"));
+        }
+    }
+    println!(
+        "{} of {total} node(s) closed the connection.",
+        total - open.len()
+    );
+}
+
+fn startcheck(sc: GenericResult<()>) -> GenericResult<()> {
+    match sc {
+        Ok(_) => Ok(()),
+        Err(err) => Err(GenericError::from(StarsError::Startup { source: err })),
     }
 }
@@ -1,4 +1,21 @@
-use std::collections::{HashMap, HashSet};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet, VecDeque},
+    fs,
+    io::ErrorKind,
+    net::{IpAddr, SocketAddr},
+    sync::{
+        Arc,
+        atomic::{AtomicBool, AtomicUsize},
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use chrono::{DateTime, Local};
+
+use crate::definitions::{GenericError, GenericResult, MAX_FLG_HISTORY, MAX_RETAINED_MESSAGES};
+use crate::mirror::MirrorFeed;
+use crate::starserror::StarsError;
+use crate::stats::{TrafficStats, ViolationStats};
 
 // This struct holds all data from the cfg files and also the flgon list for every client.
 #[derive(Debug, Clone)]
@@ -6,6 +23,8 @@ pub struct StarsData {
     pub libdir: String,
     pub keydir: String,
     pub nodes_flgon: HashMap<String, HashSet<String>>,
+    // The last notices of each flgon subscription by (subscriber, target), oldest first.
+    pub flg_history: HashMap<(String, String), VecDeque<NoticeRecord>>,
     pub aliasreal: HashMap<String, String>,
     pub realalias: HashMap<String, String>,
     pub cmddeny: Vec<String>,
@@ -13,6 +32,142 @@ pub struct StarsData {
     pub reconndeny: Vec<String>,
     pub reconnallow: Vec<String>,
     pub shutallow: Vec<String>,
+    // Set with allowcmd/denycmd: allowed or not by (node, command). Checked before the
+    // command_deny/command_allow lists, 'loadpermission' keeps them unless given 'clear'.
+    pub cmd_overrides: HashMap<(String, String), bool>,
+    // Patterns of allow.cfg, checked for new connections. Reloaded with 'loadhosts'.
+    pub hosts: Vec<String>,
+    pub nodeinfo: HashMap<String, NodeInfo>,
+    // Prune the subscriptions to unregistered nodes at every disconnect (--prune-subscriptions).
+    pub prune_subscriptions: bool,
+    // Registration read timeout in msec, 0 waits forever. Changed with 'settimeout'.
+    pub timeout: u64,
+    // Last message by (from, to) of the nodes registered with 'retain=1', oldest first.
+    // Replayed on flgon, dropped when the node disconnects.
+    pub retained: VecDeque<((String, String), String)>,
+    // Running handle_node threads, compared with the node count by 'threadinfo'.
+    pub handlers: Arc<AtomicUsize>,
+    // The shutdown request flag of the server, set by 'shutdown'. The accept loop notifies
+    // the nodes and ends.
+    pub shutdown_requested: Arc<AtomicBool>,
+    // Set by 'pause', only System commands are handled until 'resume'.
+    pub paused: bool,
+    // Set by 'lockregistrations', new registrations are refused until 'unlockregistrations'.
+    pub registrations_locked: bool,
+    // Messages received while paused with --pause-mode buffer.
+    pub paused_messages: VecDeque<PausedMessage>,
+    // Bytes of the messages in paused_messages.
+    pub paused_bytes: usize,
+    // Messages taken from paused_messages by 'resume', routed once the lock is released.
+    pub replay: Vec<PausedMessage>,
+    // The last disconnects, newest last, for 'recentdisconnects'.
+    pub recent_disconnects: VecDeque<DisconnectRecord>,
+    // Size of recent_disconnects (--recent-disconnects), 0 keeps none.
+    pub recent_capacity: usize,
+    // Protocol violations (oversized registration, frame or message) by ip since its last ban.
+    pub violations: HashMap<IpAddr, u32>,
+    // Protocol violations by kind ('violations'), shared with the handler threads.
+    pub violation_stats: Arc<ViolationStats>,
+    // Ips refused until the given time after too many violations (--autoban-threshold).
+    pub banned: HashMap<IpAddr, Instant>,
+    // Probes sent by 'pingnode' waiting for their echo, by token.
+    pub pings: HashMap<u64, PendingPing>,
+    // Token of the last 'pingnode' probe.
+    pub last_ping_token: u64,
+    // Copy of the routed lines for --mirror.
+    pub mirror: Option<MirrorFeed>,
+}
+
+// Why a node was removed from the node list.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DisconnectReason {
+    // The node sent 'exit' or 'quit'.
+    Exit,
+    PeerClosed,
+    ReadError,
+    // The node sent data the server can't handle, e.g. a too large binary frame.
+    Protocol,
+    // A new registration of the name took over the connection.
+    Replaced,
+    // By the 'disconnect' or 'disconnectall' command.
+    Command,
+    // Found dead by 'gcnodes'.
+    Dead,
+    // Made room for a new node at the connection limit (--over-limit-policy evict-oldest).
+    Evicted,
+}
+
+impl DisconnectReason {
+    pub fn name(self) -> &'static str {
+        match self {
+            DisconnectReason::Exit => "exit",
+            DisconnectReason::PeerClosed => "peer_closed",
+            DisconnectReason::ReadError => "read_error",
+            DisconnectReason::Protocol => "protocol",
+            DisconnectReason::Replaced => "replaced",
+            DisconnectReason::Command => "command",
+            DisconnectReason::Dead => "dead",
+            DisconnectReason::Evicted => "evicted",
+        }
+    }
+}
+
+// What became of a notice to a flgon subscriber.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NoticeResult {
+    Sent,
+    WriteFailed,
+    // The subscriber has no connection.
+    NotConnected,
+}
+
+impl NoticeResult {
+    pub fn name(self) -> &'static str {
+        match self {
+            NoticeResult::Sent => "sent",
+            NoticeResult::WriteFailed => "write_failed",
+            NoticeResult::NotConnected => "not_connected",
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct NoticeRecord {
+    pub time: DateTime<Local>,
+    pub event: String,
+    pub result: NoticeResult,
+}
+
+#[derive(Debug, Clone)]
+pub struct DisconnectRecord {
+    pub node: String,
+    pub peer: Option<SocketAddr>,
+    pub connected: Option<DateTime<Local>>,
+    pub disconnected: DateTime<Local>,
+    pub reason: DisconnectReason,
+}
+
+#[derive(Debug, Clone)]
+pub struct PendingPing {
+    // Connection of the caller and the name the reply goes to.
+    pub caller_node: String,
+    pub caller: String,
+    pub target: String,
+    pub sent: Instant,
+    pub deadline: Instant,
+}
+
+#[derive(Debug, Clone)]
+pub struct PausedMessage {
+    pub node: String,
+    pub line: String,
+    pub body: Option<Vec<u8>>,
+}
+
+impl PausedMessage {
+    pub fn size(&self) -> usize {
+        self.line.len() + self.body.as_ref().map_or(0, Vec::len)
+    }
 }
 
 impl StarsData {
@@ -21,6 +176,7 @@ impl StarsData {
             libdir: lib.to_string(),
             keydir: key.to_string(),
             nodes_flgon: HashMap::new(),
+            flg_history: HashMap::new(),
             aliasreal: HashMap::new(),
             realalias: HashMap::new(),
             cmddeny: Vec::new(),
@@ -28,6 +184,323 @@ impl StarsData {
             reconndeny: Vec::new(),
             reconnallow: Vec::new(),
             shutallow: Vec::new(),
+            cmd_overrides: HashMap::new(),
+            hosts: Vec::new(),
+            nodeinfo: HashMap::new(),
+            prune_subscriptions: false,
+            timeout: 0,
+            retained: VecDeque::new(),
+            handlers: Arc::new(AtomicUsize::new(0)),
+            shutdown_requested: Arc::new(AtomicBool::new(false)),
+            paused: false,
+            registrations_locked: false,
+            paused_messages: VecDeque::new(),
+            paused_bytes: 0,
+            replay: Vec::new(),
+            recent_disconnects: VecDeque::new(),
+            recent_capacity: 0,
+            violations: HashMap::new(),
+            violation_stats: Arc::new(ViolationStats::default()),
+            banned: HashMap::new(),
+            pings: HashMap::new(),
+            last_ping_token: 0,
+            mirror: None,
+        }
+    }
+
+    // Counts a protocol violation of the ip, returns its count.
+    pub fn add_violation(&mut self, ip: IpAddr) -> u32 {
+        let count = self.violations.entry(ip).or_default();
+        *count += 1;
+        *count
+    }
+
+    // Expired bans are removed on the way.
+    pub fn is_banned(&mut self, ip: IpAddr, now: Instant) -> bool {
+        self.banned.retain(|_, until| *until > now);
+        self.banned.contains_key(&ip)
+    }
+
+    // Keeps the disconnect of the node, called before its NodeInfo is removed.
+    pub fn record_disconnect(&mut self, node: &str, reason: DisconnectReason) {
+        if self.recent_capacity == 0 {
+            return;
+        }
+        let info = self.nodeinfo.get(node);
+        let record = DisconnectRecord {
+            node: node.to_string(),
+            peer: info.and_then(|info| info.peer),
+            connected: info.and_then(|info| info.connected),
+            disconnected: Local::now(),
+            reason,
+        };
+        while self.recent_disconnects.len() >= self.recent_capacity {
+            self.recent_disconnects.pop_front();
+        }
+        self.recent_disconnects.push_back(record);
+    }
+
+    // Removes the flgon targets which are not registered, and the subscribers which are gone,
+    // except the node `keep` (e.g. the one just disconnecting, it is likely to return).
+    // Returns the number of removed subscriptions.
+    pub fn prune_subscriptions(
+        &mut self,
+        registered: impl Fn(&str) -> bool,
+        keep: Option<&str>,
+    ) -> usize {
+        let aliasreal = &self.aliasreal;
+        let alive = |name: &str| {
+            let top = name.split('.').next().unwrap_or(name);
+            keep == Some(top)
+                || registered(top)
+                || aliasreal.get(top).is_some_and(|real| registered(real))
+        };
+        let mut removed = 0;
+        self.nodes_flgon.retain(|subscriber, targets| {
+            if !alive(subscriber) {
+                removed += targets.len();
+                return false;
+            }
+            let before = targets.len();
+            targets.retain(|target| alive(target));
+            removed += before - targets.len();
+            !targets.is_empty()
+        });
+        self.forget_flg_history();
+        removed
+    }
+
+    // Keeps the last MAX_FLG_HISTORY notices of the subscription.
+    pub fn record_notice(
+        &mut self,
+        subscriber: &str,
+        target: &str,
+        event: &str,
+        result: NoticeResult,
+    ) {
+        let key = (subscriber.to_string(), target.to_string());
+        let history = self.flg_history.entry(key).or_default();
+        if history.len() >= MAX_FLG_HISTORY {
+            history.pop_front();
+        }
+        history.push_back(NoticeRecord {
+            time: Local::now(),
+            event: event.to_string(),
+            result,
+        });
+    }
+
+    // Drops the history of the subscriptions which were removed.
+    pub fn forget_flg_history(&mut self) {
+        let flgon = &self.nodes_flgon;
+        self.flg_history.retain(|(subscriber, target), _| {
+            flgon
+                .get(subscriber)
+                .is_some_and(|targets| targets.contains(target))
+        });
+    }
+
+    // Keeps the message as the last one from `from` to `to`. Beyond MAX_RETAINED_MESSAGES
+    // the oldest one of any node is dropped.
+    pub fn retain_message(&mut self, from: &str, to: &str, body: &str) {
+        if let Some(pos) = self
+            .retained
+            .iter()
+            .position(|((f, t), _)| f == from && t == to)
+        {
+            self.retained.remove(pos);
+        }
+        if self.retained.len() >= MAX_RETAINED_MESSAGES {
+            self.retained.pop_front();
+        }
+        self.retained
+            .push_back(((from.to_string(), to.to_string()), body.to_string()));
+    }
+
+    pub fn forget_retained(&mut self, node: &str) {
+        self.retained.retain(|((from, _), _)| from != node);
+    }
+
+    // The runtime state kept across restarts with --state-file: the flgon subscriptions,
+    // the allowcmd/denycmd overrides and the bans (until, in unix seconds). The cfg files
+    // are loaded again at startup and the connections are gone anyway.
+    pub fn save_state(&self, path: &str) -> GenericResult<()> {
+        let subscriptions: BTreeMap<&String, BTreeSet<&String>> = self
+            .nodes_flgon
+            .iter()
+            .map(|(subscriber, targets)| (subscriber, targets.iter().collect()))
+            .collect();
+        let mut overrides: Vec<(&(String, String), &bool)> = self.cmd_overrides.iter().collect();
+        overrides.sort();
+        let overrides: Vec<serde_json::Value> = overrides
+            .into_iter()
+            .map(|((node, command), allowed)| {
+                serde_json::json!({ "node": node, "command": command, "allowed": allowed })
+            })
+            .collect();
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?;
+        let bans: BTreeMap<String, u64> = self
+            .banned
+            .iter()
+            .filter(|(_, until)| **until > now)
+            .map(|(ip, until)| (ip.to_string(), (unix_now + (*until - now)).as_secs()))
+            .collect();
+        let state = serde_json::json!({
+            "subscriptions": subscriptions,
+            "command_overrides": overrides,
+            "bans": bans,
+        });
+        // Written aside and renamed, so a crash never leaves half a file behind.
+        let tmp = format!("{path}.tmp");
+        fs::write(&tmp, format!("{state}\n"))?;
+        fs::rename(&tmp, path)?;
+        Ok(())
+    }
+
+    // A missing file is a first start and not an error, a malformed one is.
+    pub fn load_state(&mut self, path: &str) -> GenericResult<()> {
+        let content = match fs::read_to_string(path) {
+            Ok(content) => content,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(err) => return Err(GenericError::from(err)),
+        };
+        let malformed = || {
+            GenericError::from(StarsError::InvalidSetting {
+                name: "state file".to_string(),
+                value: path.to_string(),
+            })
+        };
+        let state: serde_json::Value = serde_json::from_str(&content).map_err(|_| malformed())?;
+        let empty = serde_json::Map::new();
+        let section = |name: &str| match state.get(name) {
+            None => Some(&empty),
+            Some(value) => value.as_object(),
+        };
+        for (subscriber, targets) in section("subscriptions").ok_or_else(malformed)? {
+            let targets = targets.as_array().ok_or_else(malformed)?;
+            let entry = self.nodes_flgon.entry(subscriber.clone()).or_default();
+            for target in targets {
+                entry.insert(target.as_str().ok_or_else(malformed)?.to_string());
+            }
+        }
+        let no_overrides = vec![];
+        let overrides = match state.get("command_overrides") {
+            None => &no_overrides,
+            Some(value) => value.as_array().ok_or_else(malformed)?,
+        };
+        for entry in overrides {
+            let node = entry.get("node").and_then(|v| v.as_str());
+            let command = entry.get("command").and_then(|v| v.as_str());
+            let allowed = entry.get("allowed").and_then(|v| v.as_bool());
+            let (Some(node), Some(command), Some(allowed)) = (node, command, allowed) else {
+                return Err(malformed());
+            };
+            self.cmd_overrides
+                .insert((node.to_string(), command.to_string()), allowed);
+        }
+        let now = Instant::now();
+        let unix_now = SystemTime::now().duration_since(UNIX_EPOCH)?.as_secs();
+        for (ip, until) in section("bans").ok_or_else(malformed)? {
+            let ip: IpAddr = ip.parse().map_err(|_| malformed())?;
+            let until = until.as_u64().ok_or_else(malformed)?;
+            // Bans which ran out while the server was down are dropped.
+            if until > unix_now {
+                self.banned
+                    .insert(ip, now + Duration::from_secs(until - unix_now));
+            }
+        }
+        Ok(())
+    }
+}
+
+// Runtime information about a registered node, keyed by the node name in StarsData.
+#[derive(Debug, Clone, Default)]
+pub struct NodeInfo {
+    // Sub-nodes declared with the 'subnodes=' registration capability.
+    pub subnodes: Option<HashSet<String>>,
+    // Traffic of this node. bytes_out and messages_out count the routed messages
+    // delivered to the node, server replies are only counted server wide.
+    pub traffic: Arc<TrafficStats>,
+    // Set by the 'mute' command, messages from the node are not delivered.
+    pub muted: bool,
+    // Set by the 'drain' command, messages to the node are rejected.
+    pub draining: bool,
+    // Set with the 'binary=1' registration capability, the node sends and accepts binary frames.
+    pub binary: bool,
+    // Set with the 'retain=1' registration capability, the last messages are kept for late joiners.
+    pub retain: bool,
+    // Address of the client, set at registration.
+    pub peer: Option<SocketAddr>,
+    // Number sent to the client at connect, its key line. Names claimed later use the same.
+    pub nodekey: u16,
+    // Time of the registration.
+    pub connected: Option<DateTime<Local>>,
+    // Time of the registration or the last message received from the node.
+    pub last_active: Option<Instant>,
+    // Time of the last routed message delivered to the node.
+    pub last_sent: Option<Instant>,
+    // Hop count of the last flgon event delivered to the node, and when.
+    pub event_hop: Option<(u32, Instant)>,
+}
+
+impl NodeInfo {
+    // Applies one registration capability token (e.g. 'subnodes=ch1,ch2').
+    // Returns false for unknown capabilities.
+    pub fn set_capability(&mut self, cap: &str) -> bool {
+        match cap.split_once('=') {
+            Some(("subnodes", list)) => {
+                self.subnodes = Some(
+                    list.split(',')
+                        .filter(|s| !s.is_empty())
+                        .map(str::to_string)
+                        .collect(),
+                );
+                true
+            }
+            Some(("binary", "1")) => {
+                self.binary = true;
+                true
+            }
+            Some(("retain", "1")) => {
+                self.retain = true;
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // Registration capabilities in effect, for the '@caps' reply.
+    pub fn capabilities(&self) -> Vec<&'static str> {
+        let mut list = Vec::new();
+        if self.subnodes.is_some() {
+            list.push("subnodes");
+        }
+        if self.binary {
+            list.push("binary");
+        }
+        if self.retain {
+            list.push("retain");
+        }
+        list
+    }
+
+    // Special states of the node as listed by 'listrestricted'.
+    pub fn restrictions(&self) -> Vec<&'static str> {
+        let mut list = Vec::new();
+        if self.muted {
+            list.push("muted");
+        }
+        if self.draining {
+            list.push("draining");
+        }
+        list
+    }
+
+    pub fn handles_subnode(&self, subnode: &str) -> bool {
+        match &self.subnodes {
+            Some(list) => list.contains(subnode),
+            None => false,
         }
     }
 }
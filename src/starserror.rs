@@ -1,15 +1,98 @@
-use std::fmt;
+use std::{fmt, io, path::PathBuf};
 
-/// StarsError. Contains error messages from the STARS system.
-#[derive(Debug, Clone)]
-pub struct StarsError {
-    pub message: String,
+use crate::definitions::GenericError;
+
+/// StarsError. The failures of the STARS system, the read errors of a node are RecvError.
+#[derive(Debug)]
+pub enum StarsError {
+    /// A required keyword is missing in the config file.
+    MissingKeyword { keyword: String },
+    /// A setting has a value which can't be used.
+    InvalidSetting { name: String, value: String },
+    /// A line of a cfg file which can't be parsed.
+    BadConfigLine { file: PathBuf, line: String },
+    /// A cfg file includes itself, or the includes nest deeper than MAX_INCLUDE_DEPTH.
+    IncludeDepth { file: PathBuf },
+    /// A cfg file needed at startup could not be loaded.
+    Startup { source: GenericError },
+    /// The listening socket could not be created.
+    Bind { port: u16, source: io::Error },
+    /// The server refused a registration, with its reply.
+    AuthFailed { node: String, reply: String },
+    /// Anything else.
+    Message(String),
 }
+
 // make it printable
 impl fmt::Display for StarsError {
     fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
-        write!(f, "{}", self.message)
+        match self {
+            StarsError::MissingKeyword { keyword } => write!(f, "{keyword} keyword not found!"),
+            StarsError::InvalidSetting { name, value } => {
+                write!(f, "ERROR: Invalid {name} '{value}'!")
+            }
+            StarsError::BadConfigLine { file, line } => {
+                write!(f, "Bad line in {}: '{line}'", file.display())
+            }
+            StarsError::IncludeDepth { file } => {
+                write!(
+                    f,
+                    "Include depth exceeded at {} (include cycle?)",
+                    file.display()
+                )
+            }
+            StarsError::Startup { source } => {
+                write!(f, "Initialization faild! Server will not start!\n{source}")
+            }
+            StarsError::Bind { port, source } => {
+                write!(
+                    f,
+                    "ERROR: Can't create socket for listining on port {port}! {source}"
+                )
+            }
+            StarsError::AuthFailed { node, reply } => {
+                write!(f, "{node}: registration failed '{reply}'")
+            }
+            StarsError::Message(message) => write!(f, "{message}"),
+        }
+    }
+}
+
+impl std::error::Error for StarsError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            StarsError::Startup { source } => Some(source.as_ref()),
+            StarsError::Bind { source, .. } => Some(source),
+            _ => None,
+        }
+    }
+}
+
+/// Why reading from a node failed.
+#[derive(Debug)]
+pub enum RecvError {
+    /// The peer closed the connection (or nothing was received).
+    PeerClosed { name: String },
+    /// The read failed, e.g. reset by the peer or timed out.
+    IoError { name: String, source: io::Error },
+}
+
+impl fmt::Display for RecvError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> Result<(), fmt::Error> {
+        match self {
+            RecvError::PeerClosed { name } => write!(f, "({name}) Connection lost!"),
+            RecvError::IoError { name, source } => {
+                write!(f, "({name}) Error reading from client: {source}")
+            }
+        }
+    }
+}
+
+impl std::error::Error for RecvError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            RecvError::PeerClosed { .. } => None,
+            RecvError::IoError { source, .. } => Some(source),
+        }
     }
 }
-//implement default error definitions
-impl std::error::Error for StarsError {}
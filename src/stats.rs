@@ -0,0 +1,163 @@
+use std::{
+    sync::{
+        Arc,
+        atomic::{AtomicU64, AtomicUsize, Ordering},
+    },
+    time::Instant,
+};
+
+use crate::lazy_static;
+
+// Traffic counters, cumulative since server start.
+// Updated with relaxed atomics so the routing path never waits for a lock.
+#[derive(Debug, Default)]
+pub struct TrafficStats {
+    pub bytes_in: AtomicU64,
+    pub bytes_out: AtomicU64,
+    pub messages_in: AtomicU64,
+    pub messages_out: AtomicU64,
+}
+
+impl TrafficStats {
+    pub fn add(counter: &AtomicU64, value: u64) {
+        counter.fetch_add(value, Ordering::Relaxed);
+    }
+
+    pub fn get(counter: &AtomicU64) -> u64 {
+        counter.load(Ordering::Relaxed)
+    }
+
+    pub fn summary(&self) -> String {
+        format!(
+            "bytes_in={} bytes_out={} messages_in={} messages_out={}",
+            TrafficStats::get(&self.bytes_in),
+            TrafficStats::get(&self.bytes_out),
+            TrafficStats::get(&self.messages_in),
+            TrafficStats::get(&self.messages_out),
+        )
+    }
+}
+
+// Connection counters, cumulative since server start.
+#[derive(Debug, Default)]
+pub struct AcceptStats {
+    pub accepted: AtomicU64,
+    pub rejected_badhost: AtomicU64,
+    pub rejected_dupe: AtomicU64,
+    pub rejected_badkey: AtomicU64,
+    // Connections which got the node key but sent no complete registration line in time.
+    pub registration_timeouts: AtomicU64,
+    // Oversized registrations, frames and messages.
+    pub protocol_violations: AtomicU64,
+}
+
+impl AcceptStats {
+    pub fn summary(&self, active: usize) -> String {
+        format!(
+            "accepted={} rejected_badhost={} rejected_dupe={} rejected_badkey={} \
+             registration_timeouts={} protocol_violations={} active={active}",
+            TrafficStats::get(&self.accepted),
+            TrafficStats::get(&self.rejected_badhost),
+            TrafficStats::get(&self.rejected_dupe),
+            TrafficStats::get(&self.rejected_badkey),
+            TrafficStats::get(&self.registration_timeouts),
+            TrafficStats::get(&self.protocol_violations),
+        )
+    }
+}
+
+// Protocol violations by kind, since server start or the last 'resetviolations'.
+// Unlike the other counters they are reset for an investigation, so they are kept per
+// server (StarsData.violation_stats).
+#[derive(Debug, Default)]
+pub struct ViolationStats {
+    // Lines with invalid UTF-8, replaced or rejected (--strict-utf8).
+    pub invalid_utf8: AtomicU64,
+    pub registration_too_long: AtomicU64,
+    // Registration lines with too few tokens or an unknown capability.
+    pub malformed_registration: AtomicU64,
+    pub frame_too_large: AtomicU64,
+    // Messages over --max-body-len.
+    pub message_too_large: AtomicU64,
+}
+
+impl ViolationStats {
+    fn counters(&self) -> [(&'static str, &AtomicU64); 5] {
+        [
+            ("invalid_utf8", &self.invalid_utf8),
+            ("registration_too_long", &self.registration_too_long),
+            ("malformed_registration", &self.malformed_registration),
+            ("frame_too_large", &self.frame_too_large),
+            ("message_too_large", &self.message_too_large),
+        ]
+    }
+
+    pub fn summary(&self) -> String {
+        self.counters()
+            .iter()
+            .map(|(name, counter)| format!("{name}={}", TrafficStats::get(counter)))
+            .collect::<Vec<_>>()
+            .join(" ")
+    }
+
+    pub fn reset(&self) {
+        for (_, counter) in self.counters() {
+            counter.store(0, Ordering::Relaxed);
+        }
+    }
+}
+
+// Routed messages since the last periodic summary line (--summary-interval).
+#[derive(Debug)]
+pub struct IntervalStats {
+    routed: u64,
+    since: Instant,
+}
+
+impl IntervalStats {
+    pub fn new(routed: u64, now: Instant) -> IntervalStats {
+        IntervalStats { routed, since: now }
+    }
+
+    // Returns the messages routed since the last call and their rate per second,
+    // and starts the next interval.
+    pub fn update(&mut self, routed: u64, now: Instant) -> (u64, f64) {
+        let delta = routed.saturating_sub(self.routed);
+        let secs = now
+            .duration_since(self.since)
+            .as_secs_f64()
+            .max(f64::EPSILON);
+        self.routed = routed;
+        self.since = now;
+        (delta, delta as f64 / secs)
+    }
+}
+
+// Counts a running handle_node thread while it is alive, whichever way the thread ends.
+pub struct ThreadCount(Arc<AtomicUsize>);
+
+impl ThreadCount {
+    pub fn enter(counter: &Arc<AtomicUsize>) -> ThreadCount {
+        counter.fetch_add(1, Ordering::Relaxed);
+        ThreadCount(Arc::clone(counter))
+    }
+}
+
+impl Drop for ThreadCount {
+    fn drop(&mut self) {
+        self.0.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+lazy_static! {
+    // Server wide counters. bytes_* count every byte read from or written to a socket,
+    // messages_in counts the received message lines and messages_out the routed messages.
+    pub static ref STATS: TrafficStats = TrafficStats::default();
+    // Received lines with invalid UTF-8 that were passed on with replacement characters.
+    pub static ref INVALID_UTF8: AtomicU64 = AtomicU64::new(0);
+    // Messages dropped from the pause buffer by --pause-overflow.
+    pub static ref PAUSE_DROPS: AtomicU64 = AtomicU64::new(0);
+    // Routed lines the --mirror did not get, as it was down or fell behind.
+    pub static ref MIRROR_DROPS: AtomicU64 = AtomicU64::new(0);
+    pub static ref ACCEPTS: AcceptStats = AcceptStats::default();
+}
@@ -0,0 +1,76 @@
+// Optional copy of the connection events to the local syslog (--syslog, needs the syslog
+// feature). Messages are RFC 3164 as the /dev/log receivers expect, tagged with the server
+// version. The node name and peer address lead the text, as RFC 3164 has no structured data.
+// Without the flag no socket is opened and log() returns.
+use std::net::IpAddr;
+#[cfg(feature = "syslog")]
+use std::{
+    process,
+    sync::{Mutex, OnceLock},
+};
+
+#[cfg(feature = "syslog")]
+use ::syslog::{Facility, Formatter3164, Logger, LoggerBackend};
+
+use crate::definitions::*;
+use crate::starserror::StarsError;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Severity {
+    Warning,
+    Notice,
+    Info,
+}
+
+#[cfg(feature = "syslog")]
+const SYSLOG_UDP: &str = "127.0.0.1:514";
+
+#[cfg(feature = "syslog")]
+static LOGGER: OnceLock<Mutex<Logger<LoggerBackend, Formatter3164>>> = OnceLock::new();
+
+// Connects to /dev/log, or to the syslog UDP port of this host where there is none.
+#[cfg(feature = "syslog")]
+pub fn init() -> GenericResult<()> {
+    let formatter = Formatter3164 {
+        facility: Facility::LOG_DAEMON,
+        hostname: None,
+        process: format!("stars-{VERSION}"),
+        pid: process::id(),
+    };
+    let logger = match ::syslog::unix(formatter.clone()) {
+        Ok(logger) => logger,
+        Err(_) => ::syslog::udp(formatter, "127.0.0.1:0", SYSLOG_UDP)
+            .map_err(|err| StarsError::Message(err.to_string()))?,
+    };
+    let _ = LOGGER.set(Mutex::new(logger));
+    Ok(())
+}
+
+#[cfg(not(feature = "syslog"))]
+pub fn init() -> GenericResult<()> {
+    Err(StarsError::Message("Built without the syslog feature.".to_string()).into())
+}
+
+#[cfg(feature = "syslog")]
+pub fn log(severity: Severity, msgid: &str, node: &str, peer: Option<IpAddr>, text: &str) {
+    let Some(logger) = LOGGER.get() else {
+        return;
+    };
+    let msg = format_message(msgid, node, peer, text);
+    let mut logger = logger.lock().expect("can't get the lock!");
+    // Logging must not disturb the server, a lost message is accepted.
+    let _ = match severity {
+        Severity::Warning => logger.warning(msg),
+        Severity::Notice => logger.notice(msg),
+        Severity::Info => logger.info(msg),
+    };
+}
+
+#[cfg(not(feature = "syslog"))]
+pub fn log(_: Severity, _: &str, _: &str, _: Option<IpAddr>, _: &str) {}
+
+#[cfg(feature = "syslog")]
+pub fn format_message(msgid: &str, node: &str, peer: Option<IpAddr>, text: &str) -> String {
+    let peer = peer.map_or_else(|| "-".to_string(), |ip| ip.to_string());
+    format!("{msgid} node={node} ip={peer}: {text}")
+}
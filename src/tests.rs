@@ -0,0 +1,2340 @@
+// End-to-end tests. Every test starts its own server on an OS assigned port
+// and talks to it over TCP like a real STARS client.
+// The cfg and key files are taken from takaserv-lib (term1, term2, term3 use the key "stars").
+use std::{
+    io::{BufRead, BufReader, Read, Write},
+    net::{SocketAddr, TcpStream},
+    thread,
+    time::Duration,
+};
+
+use crate::definitions::*;
+use crate::events::{self, ServerEvent};
+use crate::server::{
+    LineEnding, OverLimitPolicy, PauseMode, PauseOverflow, SYSTEM_COMMANDS, Server, ServerConfig,
+    ServerHandle, Timestamps, UnknownCommand, run_server,
+};
+use crate::starserror::StarsError;
+
+pub struct TestServer {
+    pub addr: SocketAddr,
+    handle: ServerHandle,
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        self.handle.stop();
+    }
+}
+
+pub fn test_config() -> ServerConfig {
+    ServerConfig {
+        port: 0,
+        libdir: DEFAULT_LIBDIR.to_string(),
+        keydir: DEFAULT_LIBDIR.to_string(),
+        timeout: 2000,
+        ..ServerConfig::default()
+    }
+}
+
+/// Starts a server with the given config on a background thread.
+pub fn start_server_with(config: ServerConfig) -> TestServer {
+    // The receiver is dropped, the server ignores failed event sends.
+    let (event_tx, _) = events::channel();
+    start_server_with_events(config, event_tx)
+}
+
+/// Starts a server which sends its visualization events to `event_tx`.
+pub fn start_server_with_events(config: ServerConfig, event_tx: events::EventSender) -> TestServer {
+    let server = Server::bind(config).expect("server bind failed!");
+    let handle = server.handle();
+    let addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+    thread::spawn(move || server.run(event_tx));
+    TestServer { addr, handle }
+}
+
+pub fn start_server() -> TestServer {
+    start_server_with(test_config())
+}
+
+/// Copy of the default libdir with additional files, for tests which need a different cfg.
+pub fn libdir_with(name: &str, files: &[(&str, &str)]) -> String {
+    let dir = std::env::temp_dir().join(format!("stars-test-{}-{name}", std::process::id()));
+    std::fs::create_dir_all(&dir).expect("create libdir failed!");
+    for entry in std::fs::read_dir(DEFAULT_LIBDIR).expect("read libdir failed!") {
+        let path = entry.expect("read libdir failed!").path();
+        std::fs::copy(&path, dir.join(path.file_name().unwrap())).expect("copy failed!");
+    }
+    for (fname, content) in files {
+        std::fs::write(dir.join(fname), content).expect("write cfg failed!");
+    }
+    dir.to_string_lossy().into_owned()
+}
+
+pub struct TestClient {
+    stream: TcpStream,
+    reader: BufReader<TcpStream>,
+}
+
+impl TestClient {
+    /// Connects to the server and reads the nodekey challenge.
+    pub fn connect(addr: SocketAddr) -> (TestClient, String) {
+        let stream = TcpStream::connect(addr).expect("connect failed!");
+        stream
+            .set_read_timeout(Some(Duration::from_secs(5)))
+            .expect("set timeout failed!");
+        let reader = BufReader::new(stream.try_clone().expect("stream clone failed!"));
+        let mut client = TestClient { stream, reader };
+        let nodekey = client.recv();
+        (client, nodekey)
+    }
+
+    /// Connects and registers as `name` with `key`, returns the server reply.
+    pub fn register(addr: SocketAddr, name: &str, key: &str) -> (TestClient, String) {
+        let (mut client, _) = TestClient::connect(addr);
+        client.send(&format!("{name} {key}"));
+        let reply = client.recv();
+        (client, reply)
+    }
+
+    /// Registers and asserts that the registration was accepted.
+    pub fn login(addr: SocketAddr, name: &str) -> TestClient {
+        let (client, reply) = TestClient::register(addr, name, "stars");
+        assert_eq!(reply, format!("System>{name} Ok:"));
+        client
+    }
+
+    pub fn send_bytes(&mut self, data: &[u8]) {
+        self.stream.write_all(data).expect("write failed!");
+    }
+
+    pub fn send(&mut self, line: &str) {
+        self.stream
+            .write_all(format!("{line}\n").as_bytes())
+            .expect("write failed!");
+    }
+
+    /// Reads one line without the line ending. Returns an empty string on EOF.
+    pub fn recv(&mut self) -> String {
+        let mut line = String::new();
+        self.reader.read_line(&mut line).expect("read failed!");
+        line.trim_end_matches(['\r', '\n']).to_string()
+    }
+
+    pub fn recv_bytes(&mut self, count: usize) -> Vec<u8> {
+        let mut data = vec![0; count];
+        self.reader.read_exact(&mut data).expect("read failed!");
+        data
+    }
+
+    /// Skips received lines until `expected` arrives. Panics on EOF.
+    pub fn wait_for(&mut self, expected: &str) {
+        loop {
+            match self.recv() {
+                line if line == expected => return,
+                line if line.is_empty() => panic!("EOF while waiting for '{expected}'"),
+                _ => {}
+            }
+        }
+    }
+
+    /// Sends a command and returns the next received line.
+    pub fn ask(&mut self, line: &str) -> String {
+        self.send(line);
+        self.recv()
+    }
+}
+
+#[test]
+fn registration_succeeds() {
+    let server = start_server();
+    let (_client, reply) = TestClient::register(server.addr, "term1", "stars");
+    assert_eq!(reply, "System>term1 Ok:");
+}
+
+#[test]
+fn registration_with_bad_key_is_rejected() {
+    let server = start_server();
+    let (mut client, reply) = TestClient::register(server.addr, "term1", "wrong");
+    assert_eq!(reply, "System> Er: Bad node name or key");
+    assert_eq!(client.recv(), "");
+}
+
+#[test]
+fn malformed_key_responses_are_rejected() {
+    // The long key must reach the key check, not the registration length limit.
+    let server = start_server_with(ServerConfig {
+        max_registration_len: 8192,
+        ..test_config()
+    });
+    let long = format!("term1 {}", "9".repeat(5000));
+    for line in [
+        "term1 12ab",
+        &long,
+        "../takaserv-lib/term1 stars",
+        "term1.x stars",
+    ] {
+        let (mut client, _) = TestClient::connect(server.addr);
+        client.send(line);
+        assert_eq!(client.recv(), "System> Er: Bad node name or key", "{line}");
+        assert_eq!(client.recv(), "");
+    }
+    for line in ["term1", "   "] {
+        let (mut client, _) = TestClient::connect(server.addr);
+        client.send(line);
+        assert_eq!(
+            client.recv(),
+            "System> Er: Registration must be '<node> <key>', too few tokens.",
+            "{line}"
+        );
+        assert_eq!(client.recv(), "");
+    }
+    // The server is still fine.
+    let _term1 = TestClient::login(server.addr, "term1");
+}
+
+#[test]
+fn duplicate_name_is_rejected() {
+    let server = start_server();
+    let _first = TestClient::login(server.addr, "term1");
+    let (_second, reply) = TestClient::register(server.addr, "term1", "stars");
+    assert_eq!(reply, "System> Er: term1 already exists.");
+}
+
+#[test]
+fn concurrent_registrations_of_one_name_have_one_winner() {
+    let server = start_server();
+    let addr = server.addr;
+    let racers: Vec<_> = (0..8)
+        .map(|_| thread::spawn(move || TestClient::register(addr, "term1", "stars")))
+        .collect();
+    // The clients are kept until all replies are in, so no name is released early.
+    let results: Vec<_> = racers
+        .into_iter()
+        .map(|racer| racer.join().expect("register thread panicked"))
+        .collect();
+    let winners = results
+        .iter()
+        .filter(|(_, reply)| reply == "System>term1 Ok:")
+        .count();
+    assert_eq!(winners, 1);
+    for (_, reply) in &results {
+        assert!(
+            reply == "System>term1 Ok:" || reply == "System> Er: term1 already exists.",
+            "{reply}"
+        );
+    }
+}
+
+#[test]
+fn message_is_routed_between_clients() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.send("term2 hello world");
+    assert_eq!(term2.recv(), "term1>term2 hello world");
+    term2.send("term1 @hello world");
+    assert_eq!(term1.recv(), "term2>term1 @hello world");
+}
+
+#[test]
+fn getversion_replies_with_version() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("System getversion"),
+        format!("System>term1 @getversion Version: {VERSION} (Rust Server)")
+    );
+}
+
+#[test]
+fn run_server_returns_error_when_port_is_taken() {
+    let server = start_server();
+    let mut config = test_config();
+    config.port = server.addr.port();
+    let (event_tx, _) = events::channel();
+    let err = run_server(config, event_tx).expect_err("bind should fail");
+    assert!(err.to_string().starts_with("ERROR: Can't create socket"));
+}
+
+#[test]
+fn run_server_returns_error_when_libdir_is_missing() {
+    let mut config = test_config();
+    config.libdir = "no-such-libdir".to_string();
+    let (event_tx, _) = events::channel();
+    let err = run_server(config, event_tx).expect_err("cfg loading should fail");
+    assert!(err.to_string().starts_with("Initialization faild!"));
+    assert!(matches!(
+        err.downcast_ref::<StarsError>(),
+        Some(StarsError::Startup { .. })
+    ));
+}
+
+#[test]
+fn malformed_alias_line_fails_startup() {
+    let libdir = libdir_with("badalias", &[(ALIASES, "t3 term2\nlonely\n")]);
+    let config = ServerConfig {
+        libdir,
+        ..test_config()
+    };
+    let err = Server::bind(config).err().expect("bind should fail");
+    let Some(StarsError::Startup { source }) = err.downcast_ref::<StarsError>() else {
+        panic!("unexpected error: {err}");
+    };
+    assert!(matches!(
+        source.downcast_ref::<StarsError>(),
+        Some(StarsError::BadConfigLine { line, .. }) if line == "lonely"
+    ));
+}
+
+#[test]
+fn stopped_server_returns_ok() {
+    let server = Server::bind(test_config()).expect("server bind failed!");
+    let handle = server.handle();
+    let (event_tx, _) = events::channel();
+    let runner = thread::spawn(move || server.run(event_tx));
+    handle.stop();
+    assert!(runner.join().expect("server thread panicked").is_ok());
+}
+
+#[test]
+fn subnode_messages_go_to_the_top_node() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.send("term2.channel2 hello");
+    assert_eq!(term2.recv(), "term1>term2.channel2 hello");
+}
+
+#[test]
+fn down_message_names_the_full_subnode() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("term2.channel2 hello"),
+        "System>term1 @hello Er: term2.channel2 is down."
+    );
+}
+
+#[test]
+fn strict_subnodes_rejects_undeclared_subnodes() {
+    let server = start_server_with(ServerConfig {
+        strict_subnodes: true,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let (mut term2, reply) = TestClient::register(server.addr, "term2", "stars subnodes=ch1,ch2");
+    assert_eq!(reply, "System>term2 Ok:");
+    assert_eq!(term2.recv(), "System>term2 @caps enabled=subnodes");
+    assert_eq!(
+        term1.ask("term2.ch3 hello"),
+        "System>term1 @hello Er: term2.ch3 is not handled by term2."
+    );
+    term1.send("term2.ch2 hello");
+    assert_eq!(term2.recv(), "term1>term2.ch2 hello");
+    // Messages to the top node itself are not affected.
+    term1.send("term2 hello");
+    assert_eq!(term2.recv(), "term1>term2 hello");
+}
+
+#[test]
+fn unknown_registration_capability_is_rejected() {
+    let server = start_server();
+    let (mut client, reply) = TestClient::register(server.addr, "term1", "stars  bogus=1 ");
+    assert_eq!(
+        reply,
+        "System> Er: Registration must be '<node> <key>', \
+         too many tokens (unknown capability 'bogus=1')."
+    );
+    assert_eq!(client.recv(), "");
+}
+
+#[test]
+fn nodestats_counts_bytes_per_node() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.send("term2 hello");
+    assert_eq!(term2.recv(), "term1>term2 hello");
+    assert_eq!(
+        term1.ask("System nodestats"),
+        "System>term1 @nodestats \
+         term1:bytes_in=29,bytes_out=0,messages_in=2,messages_out=0 \
+         term2:bytes_in=0,bytes_out=18,messages_in=0,messages_out=1"
+    );
+}
+
+#[test]
+fn pingnode_measures_the_echo_of_the_target() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.send("System pingnode term2");
+    let probe = term2.recv();
+    let token = probe
+        .strip_prefix("System>term2 _ping ")
+        .expect("ping probe");
+    term2.send(&format!("System _ping {token}"));
+    let reply = term1.recv();
+    assert!(
+        reply.starts_with("System>term1 @pingnode term2 "),
+        "{reply}"
+    );
+    assert!(reply.ends_with(" ms"), "{reply}");
+    term1.send("System pingnode term2 200");
+    assert!(term2.recv().starts_with("System>term2 _ping "));
+    assert_eq!(
+        term1.recv(),
+        "System>term1 @pingnode term2 Er: No reply from term2 within 200 msec."
+    );
+    assert_eq!(
+        term1.ask("System pingnode term3"),
+        "System>term1 @pingnode term3 Er: term3 is down."
+    );
+}
+
+#[test]
+fn testroute_reports_the_route_without_sending() {
+    let libdir = libdir_with("testroute", &[(ALIASES, "t2 term2\n")]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System denycmd term1 ping");
+    assert_eq!(
+        term1.ask("System testroute t2 hello"),
+        "System>term1 @testroute t2 would-deliver to term2"
+    );
+    assert_eq!(
+        term1.ask("System testroute term2 ping"),
+        "System>term1 @testroute term2 would-deny: Command denied."
+    );
+    assert_eq!(
+        term1.ask("System testroute term9 hello"),
+        "System>term1 @testroute term9 target-down"
+    );
+    let reply = term2.ask("System getversion");
+    assert!(reply.starts_with("System>t2 @getversion"), "{reply}");
+}
+
+#[test]
+fn nodeinfo_describes_one_node() {
+    let libdir = libdir_with("nodeinfo", &[(ALIASES, "t2 term2\n")]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System flgon term2");
+    term1.ask("System mute term2");
+    let reply = term1.ask("System nodeinfo t2");
+    assert!(
+        reply.starts_with("System>term1 @nodeinfo term2 connected="),
+        "{reply}"
+    );
+    assert!(reply.contains(" peer=127.0.0.1 bytes_in="), "{reply}");
+    assert!(
+        reply.ends_with(
+            " subscriptions=0 subscribers=1 muted=1 draining=0 rate_limited=n/a aliases=t2"
+        ),
+        "{reply}"
+    );
+    assert_eq!(
+        term1.ask("System nodeinfo term9"),
+        "System>term1 @nodeinfo Er: Node term9 is down."
+    );
+}
+
+#[test]
+fn idlenodes_lists_silent_nodes() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term2.send("term1 hello");
+    assert_eq!(term1.recv(), "term2>term1 hello");
+    thread::sleep(Duration::from_millis(1100));
+    assert_eq!(
+        term1.ask("System idlenodes 1"),
+        "System>term1 @idlenodes term2:idle=1,received=1,sent=-"
+    );
+    assert_eq!(term1.ask("System idlenodes 5"), "System>term1 @idlenodes ");
+    assert_eq!(
+        term1.ask("System idlenodes soon"),
+        "System>term1 @idlenodes Er: Invalid number of seconds."
+    );
+}
+
+#[test]
+fn stats_reports_server_counters() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let reply = term1.ask("System stats");
+    let counters: Vec<&str> = reply
+        .strip_prefix("System>term1 @stats ")
+        .expect("stats reply")
+        .split(' ')
+        .map(|kv| kv.split_once('=').expect("key=value").0)
+        .collect();
+    assert_eq!(
+        counters,
+        [
+            "nodes",
+            "registrations",
+            "bytes_in",
+            "bytes_out",
+            "messages_in",
+            "messages_out",
+            "invalid_utf8",
+            "paused_dropped",
+            "mirror_dropped",
+            "accepted",
+            "rejected_badhost",
+            "rejected_dupe",
+            "rejected_badkey",
+            "registration_timeouts",
+            "protocol_violations",
+            "active"
+        ]
+    );
+}
+
+#[test]
+fn disconnectall_disconnects_other_nodes() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System disconnectall maintenance"),
+        "System>term1 @disconnectall 1 node(s) disconnected."
+    );
+    assert_eq!(term2.recv(), "System>term2 Er: maintenance");
+    assert_eq!(term2.recv(), "");
+    // The listener stays open for new connections.
+    let _term2 = TestClient::login(server.addr, "term2");
+}
+
+#[test]
+fn disconnectall_requires_shutdown_permission() {
+    let server = start_server();
+    let _term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System disconnectall"),
+        "System>term2 @disconnectall Er: Command denied."
+    );
+}
+
+#[test]
+fn muted_node_messages_are_dropped() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(term1.ask("System mute term2"), "System>term1 @mute term2.");
+    assert_eq!(
+        term2.ask("term1 hello"),
+        "System>term2 @hello Er: You are muted."
+    );
+    assert!(
+        term1
+            .ask("System nodestats")
+            .ends_with(",messages_out=0,muted")
+    );
+    assert_eq!(
+        term1.ask("System listrestricted"),
+        "System>term1 @listrestricted 1 term2:muted"
+    );
+    assert_eq!(
+        term1.ask("System unmute term2"),
+        "System>term1 @unmute term2."
+    );
+    term2.send("term1 hello");
+    assert_eq!(term1.recv(), "term2>term1 hello");
+    assert_eq!(
+        term1.ask("System listrestricted"),
+        "System>term1 @listrestricted 0"
+    );
+}
+
+#[test]
+fn messages_to_a_draining_node_are_rejected() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System drain term2"),
+        "System>term1 @drain term2."
+    );
+    assert_eq!(
+        term1.ask("term2 hello"),
+        "System>term1 @hello Er: term2 is draining."
+    );
+    // The draining node still sends.
+    term2.send("term1 hi");
+    assert_eq!(term1.recv(), "term2>term1 hi");
+    assert!(
+        term1
+            .ask("System nodestats")
+            .ends_with(",messages_out=0,draining")
+    );
+    assert_eq!(
+        term1.ask("System undrain term2"),
+        "System>term1 @undrain term2."
+    );
+    term1.send("term2 hello");
+    assert_eq!(term2.recv(), "term1>term2 hello");
+    // The state ends with the connection.
+    assert_eq!(
+        term1.ask("System drain term2"),
+        "System>term1 @drain term2."
+    );
+    term2.send("exit");
+    assert_eq!(term2.recv(), "");
+    let _term2 = TestClient::login(server.addr, "term2");
+    term1.send("term2 hello");
+    assert_eq!(
+        term1.ask("System listrestricted"),
+        "System>term1 @listrestricted 0"
+    );
+}
+
+#[test]
+fn mute_requires_shutdown_permission() {
+    let server = start_server();
+    let _term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System mute term1"),
+        "System>term2 @mute Er: Command denied."
+    );
+}
+
+#[test]
+fn invalid_utf8_is_replaced_by_default() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.send_bytes(b"term2 caf\xe9\n");
+    assert_eq!(term2.recv(), "term1>term2 caf\u{FFFD}");
+    let reply = term1.ask("System stats");
+    let count = reply.split_once("invalid_utf8=").expect("counter").1;
+    let count = count.split(' ').next().unwrap_or_default();
+    assert!(count.parse::<u64>().expect("number") >= 1);
+}
+
+#[test]
+fn strict_utf8_rejects_invalid_lines() {
+    let server = start_server_with(ServerConfig {
+        strict_utf8: true,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.send_bytes(b"term2 caf\xe9\n");
+    assert_eq!(term1.recv(), "System>term1 Er: Invalid UTF-8.");
+    // A character split over two writes is still valid.
+    term1.send_bytes("term2 caf\u{e9}".as_bytes().split_at(10).0);
+    thread::sleep(Duration::from_millis(50));
+    term1.send_bytes(&"term2 caf\u{e9}\n".as_bytes()[10..]);
+    assert_eq!(term2.recv(), "term1>term2 caf\u{e9}");
+}
+
+#[test]
+fn reconnect_within_grace_window_is_accepted() {
+    let server = start_server_with(ServerConfig {
+        reconnect_grace: 1000,
+        ..test_config()
+    });
+    for _ in 0..3 {
+        let term1 = TestClient::login(server.addr, "term1");
+        drop(term1);
+    }
+}
+
+#[test]
+fn reconnect_grace_does_not_hold_up_other_registrations() {
+    let server = start_server_with(ServerConfig {
+        reconnect_grace: 1000,
+        ..test_config()
+    });
+    let _term1 = TestClient::login(server.addr, "term1");
+    let (mut again, _) = TestClient::connect(server.addr);
+    again.send("term1 stars");
+    let start = std::time::Instant::now();
+    let _term2 = TestClient::login(server.addr, "term2");
+    assert!(start.elapsed() < Duration::from_millis(500));
+    assert_eq!(again.recv(), "System> Er: term1 already exists.");
+}
+
+#[test]
+fn gcnodes_keeps_idle_nodes() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System gcnodes"),
+        "System>term1 @gcnodes 0 node(s) removed."
+    );
+    term1.send("term2 hello");
+    assert_eq!(term2.recv(), "term1>term2 hello");
+}
+
+#[test]
+fn rawecho_shows_the_received_line() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    term1.send_bytes(b"System rawecho a\tb\rc\r\n");
+    assert_eq!(
+        term1.recv(),
+        "System>term1 @rawecho System rawecho a\\tb\\rc\\r"
+    );
+}
+
+#[test]
+fn debugger_name_alone_gets_no_traffic() {
+    let server = start_server();
+    let mut debugger = TestClient::login(server.addr, "Debugger");
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    term1.send("term2 hello");
+    assert_eq!(
+        debugger.ask("System hello"),
+        "System>Debugger @hello Nice to meet you."
+    );
+}
+
+#[test]
+fn debugger_by_name_gets_all_traffic() {
+    let server = start_server_with(ServerConfig {
+        debugger_by_name: true,
+        ..test_config()
+    });
+    let mut debugger = TestClient::login(server.addr, "Debugger");
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    debugger.wait_for("System>term2 Ok:");
+    term1.send("term2 hello");
+    debugger.wait_for("term1>term2 hello");
+}
+
+#[test]
+fn becomedebugger_forwards_all_traffic() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    let mut term3 = TestClient::login(server.addr, "term3");
+    assert_eq!(
+        term3.ask("System becomedebugger"),
+        "System>term3 @becomedebugger Er: Command denied."
+    );
+    assert_eq!(
+        term1.ask("System becomedebugger"),
+        "System>term1 @becomedebugger Ok:"
+    );
+    term2.send("term3 hello");
+    assert_eq!(term3.recv(), "term2>term3 hello");
+    term1.wait_for("term2>term3 hello");
+}
+
+#[test]
+fn binary_frames_are_delivered_verbatim() {
+    let server = start_server();
+    let (mut term1, reply) = TestClient::register(server.addr, "term1", "stars binary=1");
+    assert_eq!(reply, "System>term1 Ok:");
+    assert_eq!(term1.recv(), "System>term1 @caps enabled=binary");
+    let (mut term2, reply) = TestClient::register(server.addr, "term2", "stars binary=1");
+    assert_eq!(reply, "System>term2 Ok:");
+    assert_eq!(term2.recv(), "System>term2 @caps enabled=binary");
+    term1.send_bytes(b"term2 ##5\na\nb\xff\0term2 hello\n");
+    assert_eq!(term2.recv(), "term1>term2 ##5");
+    assert_eq!(term2.recv_bytes(5), b"a\nb\xff\0");
+    assert_eq!(term2.recv(), "term1>term2 hello");
+}
+
+#[test]
+fn binary_frames_need_a_binary_recipient() {
+    let server = start_server();
+    let (mut term1, _) = TestClient::register(server.addr, "term1", "stars binary=1");
+    term1.recv();
+    let _term2 = TestClient::login(server.addr, "term2");
+    term1.send_bytes(b"term2 ##2\nab");
+    assert_eq!(
+        term1.recv(),
+        "System>term1 @##2 Er: term2 does not accept binary data."
+    );
+}
+
+#[test]
+fn unknown_command_suggests_the_closest_command() {
+    let server = start_server_with(ServerConfig {
+        unknown_command: UnknownCommand::Suggest,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("System getverison"),
+        "System>term1 @getverison Er: Command is not found or parameter is not enough! \
+         Did you mean 'getversion'?"
+    );
+    assert_eq!(
+        term1.ask("System xyz"),
+        "System>term1 @xyz Er: Command is not found or parameter is not enough!"
+    );
+}
+
+#[test]
+fn unknown_command_can_be_ignored() {
+    let server = start_server_with(ServerConfig {
+        unknown_command: UnknownCommand::Ignore,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    term1.send("System xyz");
+    assert_eq!(
+        term1.ask("System hello"),
+        "System>term1 @hello Nice to meet you."
+    );
+}
+
+#[test]
+fn node_prefix_limits_names_per_host() {
+    let libdir = libdir_with("prefix", &[(NODE_PREFIX, "127.0.0.1 term1\n")]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let (_term2, reply) = TestClient::register(server.addr, "term2", "stars");
+    assert_eq!(reply, "System> Er: Node name not permitted from this host.");
+    assert_eq!(
+        term1.ask("System claim term2 stars"),
+        "System>term1 @claim Er: Node name not permitted from this host."
+    );
+}
+
+#[test]
+fn timestamps_are_added_to_routed_messages() {
+    let server = start_server_with(ServerConfig {
+        timestamps: Timestamps::Prefix,
+        timestamp_format: "%Y".to_string(),
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.send("term2 hello");
+    let year = chrono::Local::now().format("%Y");
+    assert_eq!(term2.recv(), format!("term1>term2 [{year}] hello"));
+    // Replies from System are not changed.
+    assert_eq!(
+        term1.ask("System hello"),
+        "System>term1 @hello Nice to meet you."
+    );
+}
+
+#[test]
+fn listnodes_pages() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("System listnodes"),
+        "System>term1 @listnodes term1"
+    );
+    assert_eq!(
+        term1.ask("System listnodes 1"),
+        "System>term1 @listnodes term1"
+    );
+    assert_eq!(
+        term1.ask("System listnodes 2"),
+        "System>term1 @listnodes Er: No page 2."
+    );
+}
+
+#[test]
+fn listnodes_is_sorted() {
+    let server = start_server();
+    let _term3 = TestClient::login(server.addr, "term3");
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System listnodes"),
+        "System>term1 @listnodes term1 term2 term3"
+    );
+}
+
+#[test]
+fn settimeout_changes_the_registration_timeout() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("System settimeout 100"),
+        "System>term1 @settimeout Timeout changed from 2000 to 100 msec."
+    );
+    assert_eq!(
+        term1.ask("System settimeout 9999999"),
+        "System>term1 @settimeout Er: Maximum is 600000 msec."
+    );
+    // A silent client is dropped after the new timeout.
+    let (mut silent, _) = TestClient::connect(server.addr);
+    assert_eq!(silent.recv(), "System> Er: Registration timed out.");
+    assert_eq!(silent.recv(), "");
+}
+
+#[test]
+fn registration_timeout_bounds_the_registration_without_timeout() {
+    let server = start_server_with(ServerConfig {
+        timeout: 0,
+        registration_timeout: 200,
+        ..test_config()
+    });
+    let (mut silent, _) = TestClient::connect(server.addr);
+    assert_eq!(silent.recv(), "System> Er: Registration timed out.");
+    assert_eq!(silent.recv(), "");
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let reply = term1.ask("System acceptstats");
+    assert!(reply.contains(" registration_timeouts=1 "), "{reply}");
+}
+
+#[test]
+fn registration_timeouts_can_ban_the_host() {
+    let server = start_server_with(ServerConfig {
+        registration_timeout: 100,
+        registration_timeout_violation: true,
+        autoban_threshold: 1,
+        ..test_config()
+    });
+    let (mut silent, _) = TestClient::connect(server.addr);
+    assert_eq!(silent.recv(), "System> Er: Registration timed out.");
+    // Closed without the node key.
+    let stream = TcpStream::connect(server.addr).expect("connect failed!");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("set timeout failed!");
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .expect("read failed!");
+    assert_eq!(reply, "");
+}
+
+#[test]
+fn retained_events_are_replayed_on_flgon() {
+    let server = start_server();
+    let (mut term1, reply) = TestClient::register(server.addr, "term1", "stars retain=1");
+    assert_eq!(reply, "System>term1 Ok:");
+    assert_eq!(term1.recv(), "System>term1 @caps enabled=retain");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    let mut term3 = TestClient::login(server.addr, "term3");
+    term1.send("System _ChangedValue 1");
+    term1.send("term3 hello");
+    assert_eq!(term3.recv(), "term1>term3 hello");
+    term1.send("System _ChangedStatus busy");
+    assert_eq!(
+        term1.ask("System hello"),
+        "System>term1 @hello Nice to meet you."
+    );
+    // The last message by from and to, oldest first. The events come to the subscriber.
+    assert_eq!(
+        term2.ask("System flgon term1"),
+        "System>term2 @flgon Node term1 has been registered."
+    );
+    assert_eq!(term2.recv(), "term1>term3 hello");
+    assert_eq!(term2.recv(), "term1>term2 _ChangedStatus busy");
+    drop(term1);
+    assert_eq!(term2.recv(), "term1>term2 _Disconnected");
+    // Dropped with the node.
+    term3.ask("System flgon term1");
+    assert_eq!(
+        term3.ask("System hello"),
+        "System>term3 @hello Nice to meet you."
+    );
+}
+
+#[test]
+fn notifysubscribers_reaches_the_subscribers_of_a_node() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term2.ask("System flgon term3");
+    assert_eq!(
+        term2.ask("System notifysubscribers term3 going down"),
+        "System>term2 @notifysubscribers Er: Command denied."
+    );
+    assert_eq!(
+        term1.ask("System notifysubscribers term3 going down"),
+        "System>term1 @notifysubscribers term3 1 subscriber(s) notified."
+    );
+    assert_eq!(term2.recv(), "System>term2 going down");
+    assert_eq!(
+        term1.ask("System notifysubscribers term2 going down"),
+        "System>term1 @notifysubscribers term2 0 subscriber(s) notified."
+    );
+}
+
+#[test]
+fn flghistory_shows_the_notices_of_a_subscription() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System flgon term2");
+    term2.send("System _ready 1");
+    assert_eq!(term1.recv(), "term2>term1 _ready 1");
+    let reply = term1.ask("System flghistory term1 term2");
+    assert!(
+        reply.starts_with("System>term1 @flghistory term1 term2 1 "),
+        "{reply}"
+    );
+    assert!(reply.ends_with(",sent,_ready 1"), "{reply}");
+    term1.ask("System flgoff term2");
+    assert_eq!(
+        term1.ask("System flghistory term1 term2"),
+        "System>term1 @flghistory Er: term1 is not subscribed to term2."
+    );
+}
+
+#[test]
+fn flgon_and_flgoff_take_comma_separated_lists() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    term1.ask("System flgon term3");
+    assert_eq!(
+        term1.ask("System flgon term2,te$m,term3"),
+        "System>term1 @flgon Node term2 has been registered."
+    );
+    assert_eq!(
+        term1.recv(),
+        "System>term1 @flgon Er: Invalid node name 'te$m', skipped."
+    );
+    assert_eq!(
+        term1.recv(),
+        "System>term1 @flgon Er: Node term3 is allready in the list."
+    );
+    assert_eq!(
+        term1.ask("System flgoff term2, term4"),
+        "System>term1 @flgoff Node term2 has been removed."
+    );
+    assert_eq!(
+        term1.recv(),
+        "System>term1 @flgoff Er: Node term4 is not in the list."
+    );
+    assert_eq!(
+        term1.ask("System flgoff term3"),
+        "System>term1 @flgoff Node term3 has been removed."
+    );
+    assert_eq!(
+        term1.ask("System flgon $term2"),
+        "System>term1 @flgon Er: Parameter is not enough."
+    );
+    assert_eq!(
+        term1.ask("System flgoff $term2"),
+        "System>term1 @flgoff Er: Parameter is not enough."
+    );
+}
+
+#[test]
+fn flgclear_removes_all_subscriptions_of_the_caller() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System flgon term2,term3");
+    term1.recv();
+    assert_eq!(
+        term1.ask("System flgclear"),
+        "System>term1 @flgclear 2 subscription(s) removed."
+    );
+    assert_eq!(
+        term1.ask("System flgclear"),
+        "System>term1 @flgclear 0 subscription(s) removed."
+    );
+    term2.send("System _ready 1");
+    let reply = term1.ask("System getversion");
+    assert!(reply.starts_with("System>term1 @getversion"), "{reply}");
+}
+
+#[test]
+fn gcsubscriptions_removes_unregistered_targets() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System flgon term2");
+    term1.ask("System flgon term3");
+    assert_eq!(
+        term1.ask("System gcsubscriptions"),
+        "System>term1 @gcsubscriptions Removed 1 subscription(s)."
+    );
+    assert_eq!(
+        term1.ask("System flgon term2"),
+        "System>term1 @flgon Er: Node term2 is allready in the list."
+    );
+    assert_eq!(
+        term1.ask("System flgon term3"),
+        "System>term1 @flgon Node term3 has been registered."
+    );
+}
+
+#[test]
+fn prune_subscriptions_keeps_the_disconnecting_node() {
+    let server = start_server_with(ServerConfig {
+        prune_subscriptions: true,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System flgon term2");
+    term1.ask("System flgon term3");
+    term2.send("exit");
+    assert_eq!(term1.recv(), "term2>term1 _Disconnected");
+    // term3 never connected and is gone, term2 may come back.
+    let _term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(term1.recv(), "term2>term1 _Connected");
+    assert_eq!(
+        term1.ask("System gcsubscriptions"),
+        "System>term1 @gcsubscriptions Removed 0 subscription(s)."
+    );
+}
+
+#[test]
+fn claimed_names_share_the_connection() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System claim term3 stars"),
+        "System>term1 @claim term3"
+    );
+    assert_eq!(
+        term1.ask("System claim term2 stars"),
+        "System>term1 @claim Er: term2 already exists."
+    );
+    assert_eq!(
+        term1.ask("System claim term3.sub stars"),
+        "System>term1 @claim Er: Invalid node name term3.sub."
+    );
+    term2.send("term3 hello");
+    assert_eq!(term1.recv(), "term2>term3 hello");
+    // The connection answers as the claimed name.
+    term1.send("term3>term2 @hello hi");
+    assert_eq!(term2.recv(), "term3>term2 @hello hi");
+    assert_eq!(
+        term2.ask("System threadinfo"),
+        "System>term2 @threadinfo threads=2 nodes=2 ok"
+    );
+    // All names are gone with the connection.
+    term1.send("exit");
+    assert_eq!(term1.recv(), "");
+    assert_eq!(
+        term2.ask("term3 hello"),
+        "System>term2 @hello Er: term3 is down."
+    );
+    let _term3 = TestClient::login(server.addr, "term3");
+}
+
+#[test]
+fn disconnectall_keeps_the_claimed_names_of_the_caller() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System claim term3 stars"),
+        "System>term1 @claim term3"
+    );
+    assert_eq!(
+        term1.ask("System disconnectall"),
+        "System>term1 @disconnectall 1 node(s) disconnected."
+    );
+    assert_eq!(term2.recv(), "System>term2 Er: Disconnected by server.");
+    assert_eq!(term2.recv(), "");
+    assert_eq!(
+        term1.ask("System listnodes"),
+        "System>term1 @listnodes term1 term3"
+    );
+    // Disconnecting a claimed name drops the claim only.
+    assert_eq!(
+        term1.ask("System disconnect term3"),
+        "System>term1 @disconnect term3."
+    );
+    assert_eq!(
+        term1.ask("System listnodes"),
+        "System>term1 @listnodes term1"
+    );
+}
+
+#[test]
+fn claimed_names_stand_for_the_claiming_node() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System claim term3 stars"),
+        "System>term2 @claim term3"
+    );
+    assert_eq!(
+        term1.ask("System drain term3"),
+        "System>term1 @drain term2."
+    );
+    assert_eq!(
+        term1.ask("term3 hello"),
+        "System>term1 @hello Er: term3 is draining."
+    );
+    let info = term1.ask("System nodeinfo term3");
+    assert!(info.starts_with("System>term1 @nodeinfo term2 "), "{info}");
+    assert!(info.contains(" draining=1 "), "{info}");
+    let idle = term1.ask("System idlenodes");
+    assert!(idle.contains("term3:idle="), "{idle}");
+}
+
+#[test]
+fn claim_needs_the_key_of_the_name() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("System claim term3 wrong"),
+        "System>term1 @claim Er: Bad node name or key for term3."
+    );
+    assert_eq!(
+        term1.ask("System claim term3"),
+        "System>term1 @claim Er: Parameter is not enough."
+    );
+}
+
+#[test]
+fn command_overrides_take_precedence_over_the_lists() {
+    let libdir = libdir_with("cmdoverride", &[(CMD_DENY, "term2>term1 hello\n")]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("term1 hello"),
+        "System>term2 @hello Er: Command denied."
+    );
+    assert_eq!(
+        term1.ask("System allowcmd term2 hello"),
+        "System>term1 @allowcmd term2 hello allowed"
+    );
+    term2.send("term1 hello");
+    assert_eq!(term1.recv(), "term2>term1 hello");
+    assert_eq!(
+        term1.ask("System denycmd term2 ping"),
+        "System>term1 @denycmd term2 ping denied"
+    );
+    assert_eq!(
+        term2.ask("term1 ping"),
+        "System>term2 @ping Er: Command denied."
+    );
+    term1.ask("System loadpermission clear");
+    assert_eq!(
+        term2.ask("term1 hello"),
+        "System>term2 @hello Er: Command denied."
+    );
+    term2.send("term1 ping");
+    assert_eq!(term1.recv(), "term2>term1 ping");
+}
+
+#[test]
+fn acceptstats_counts_rejections() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _ = TestClient::register(server.addr, "term2", "wrong");
+    let _ = TestClient::register(server.addr, "term1", "stars");
+    let reply = term1.ask("System acceptstats");
+    let counters: Vec<(&str, u64)> = reply
+        .strip_prefix("System>term1 @acceptstats ")
+        .expect("acceptstats reply")
+        .split(' ')
+        .map(|kv| kv.split_once('=').expect("key=value"))
+        .map(|(k, v)| (k, v.parse().expect("number")))
+        .collect();
+    // The counters are server wide, other tests run in parallel.
+    for (key, min) in [
+        ("accepted", 3),
+        ("rejected_dupe", 1),
+        ("rejected_badkey", 1),
+    ] {
+        assert!(
+            counters.iter().any(|(k, v)| *k == key && *v >= min),
+            "{key}"
+        );
+    }
+    assert!(counters.contains(&("active", 1)));
+}
+
+#[test]
+fn becomedebugger_filters_by_node_and_direction() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    let mut term3 = TestClient::login(server.addr, "term3");
+    assert_eq!(
+        term1.ask("System becomedebugger term2 in"),
+        "System>term1 @becomedebugger Ok:"
+    );
+    term2.send("term3 from term2");
+    assert_eq!(term3.recv(), "term2>term3 from term2");
+    term3.send("term2 to term2");
+    assert_eq!(term2.recv(), "term3>term2 to term2");
+    assert_eq!(term1.recv(), "[in] term3>term2 to term2");
+}
+
+#[test]
+fn listbyhost_groups_nodes_by_ip() {
+    let server = start_server();
+    let _term2 = TestClient::login(server.addr, "term2");
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("System listbyhost"),
+        "System>term1 @listbyhost 127.0.0.1=term1,term2"
+    );
+}
+
+#[test]
+fn violations_are_counted_by_kind_and_ip_and_reset() {
+    let server = start_server_with(ServerConfig {
+        max_body_len: 16,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    term1.ask(&format!("term2 hello {}", "x".repeat(32)));
+    let reply = term1.ask("System violations");
+    let counters: Vec<(&str, &str)> = reply
+        .strip_prefix("System>term1 @violations ")
+        .expect("violations reply")
+        .split(' ')
+        .map(|kv| kv.split_once('=').expect("key=value"))
+        .collect();
+    let names: Vec<&str> = counters.iter().map(|(name, _)| *name).collect();
+    assert_eq!(
+        names,
+        [
+            "invalid_utf8",
+            "registration_too_long",
+            "malformed_registration",
+            "frame_too_large",
+            "message_too_large",
+            "ips"
+        ]
+    );
+    let counts: Vec<&str> = counters.iter().map(|(_, count)| *count).collect();
+    assert_eq!(counts, ["0", "0", "0", "0", "1", "-"]);
+    assert_eq!(
+        term1.ask("System resetviolations"),
+        "System>term1 @resetviolations Violation counters have been reset."
+    );
+    assert_eq!(
+        term1.ask("System violations"),
+        "System>term1 @violations invalid_utf8=0 registration_too_long=0 \
+         malformed_registration=0 frame_too_large=0 message_too_large=0 ips=-"
+    );
+}
+
+#[test]
+fn mirror_gets_a_copy_of_the_routed_lines() {
+    let listener = std::net::TcpListener::bind("127.0.0.1:0").expect("bind failed!");
+    let server = start_server_with(ServerConfig {
+        mirror: Some(listener.local_addr().expect("no address").to_string()),
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.send("term2 hello");
+    assert_eq!(term2.recv(), "term1>term2 hello");
+    let (stream, _) = listener.accept().expect("no mirror connection");
+    let mut line = String::new();
+    BufReader::new(stream)
+        .read_line(&mut line)
+        .expect("mirror read failed!");
+    assert_eq!(line, "term1>term2 hello\n");
+}
+
+#[test]
+fn oversize_messages_are_rejected() {
+    let server = start_server_with(ServerConfig {
+        max_body_len: 16,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask(&format!("term2 hello {}", "x".repeat(32))),
+        "System>term1 @hello Er: Message too large."
+    );
+    // Neither the header nor the command word is part of the body.
+    term1.send("term2 hello 1234567890123456");
+    assert_eq!(term2.recv(), "term1>term2 hello 1234567890123456");
+    // System commands are not limited.
+    assert_eq!(
+        term1.ask(&format!("System isup {}", "x".repeat(32))),
+        format!("System>term1 @isup {} down", "x".repeat(32))
+    );
+}
+
+#[test]
+fn listcommands_matches_help() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let help = term1.ask("System help");
+    let names: Vec<&str> = help
+        .trim_start_matches("System>term1 @help ")
+        .split(' ')
+        .collect();
+    assert_eq!(names.len(), SYSTEM_COMMANDS.len());
+    term1.send("System listcommands");
+    for command in SYSTEM_COMMANDS {
+        assert!(names.contains(&command.name));
+        assert_eq!(
+            term1.recv(),
+            format!(
+                "System>term1 @listcommands {} {}",
+                command.name, command.description
+            )
+        );
+    }
+}
+
+#[test]
+fn every_advertised_command_dispatches() {
+    let server = start_server();
+    // term2 is not allowed to shut down, so the privileged commands are only denied.
+    let mut term2 = TestClient::login(server.addr, "term2");
+    for command in SYSTEM_COMMANDS {
+        let reply = term2.ask(&format!("System {} term3", command.name));
+        let reply = match reply.contains("not found") {
+            // Commands without arguments
+            true => term2.ask(&format!("System {}", command.name)),
+            false => reply,
+        };
+        assert!(
+            reply.starts_with(&format!("System>term2 @{} ", command.name)),
+            "{reply}"
+        );
+        assert!(!reply.contains("Command is not found"), "{reply}");
+        if command.name == "listcommands" {
+            for _ in 1..SYSTEM_COMMANDS.len() {
+                term2.recv();
+            }
+        }
+    }
+}
+
+#[test]
+fn admin_token_is_required_for_privileged_commands() {
+    let server = start_server_with(ServerConfig {
+        admin_token: Some("secret".to_string()),
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    let _term3 = TestClient::login(server.addr, "term3");
+    assert_eq!(
+        term1.ask("System mute term3"),
+        "System>term1 @mute Er: Authentication failed."
+    );
+    assert_eq!(
+        term1.ask("System mute term3 wrong"),
+        "System>term1 @mute Er: Authentication failed."
+    );
+    // Not allowed by shutdown_allow.cfg, the reply does not tell.
+    assert_eq!(
+        term2.ask("System mute term3 wrong"),
+        "System>term2 @mute Er: Authentication failed."
+    );
+    assert_eq!(
+        term2.ask("System mute term3 secret"),
+        "System>term2 @mute Er: Command denied."
+    );
+    assert_eq!(
+        term1.ask("System mute term3 secret"),
+        "System>term1 @mute term3."
+    );
+    assert_eq!(
+        term1.ask("System settimeout 1000 secret"),
+        "System>term1 @settimeout Timeout changed from 2000 to 1000 msec."
+    );
+    // Other commands are not affected.
+    assert_eq!(
+        term2.ask("System hello"),
+        "System>term2 @hello Nice to meet you."
+    );
+}
+
+#[test]
+fn loadhosts_applies_to_new_connections_only() {
+    let libdir = libdir_with("loadhosts", &[]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir.clone(),
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    std::fs::write(
+        std::path::Path::new(&libdir).join("allow.cfg"),
+        "192.0.2.1\n",
+    )
+    .expect("write cfg failed!");
+    // Not reloaded yet
+    let _term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        TestClient::login(server.addr, "term3").ask("System loadhosts"),
+        "System>term3 @loadhosts Er: Command denied."
+    );
+    assert_eq!(
+        term1.ask("System loadhosts"),
+        "System>term1 @loadhosts Host list has been loaded."
+    );
+    let (_, nodekey) = TestClient::connect(server.addr);
+    assert!(nodekey.starts_with("Bad host."), "{nodekey}");
+    assert_eq!(
+        term1.ask("System hello"),
+        "System>term1 @hello Nice to meet you."
+    );
+}
+
+#[test]
+fn full_event_queue_does_not_stall_routing() {
+    // Nobody reads the events, the queue is full after the first one.
+    let (event_tx, event_rx) = std::sync::mpsc::sync_channel(1);
+    let server = start_server_with_events(test_config(), event_tx);
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    for i in 0..10 {
+        term1.send(&format!("term2 hello {i}"));
+        assert_eq!(term2.recv(), format!("term1>term2 hello {i}"));
+    }
+    assert!(matches!(
+        event_rx.try_recv(),
+        Ok(ServerEvent::NodeConnected { name }) if name == "term1"
+    ));
+}
+
+#[test]
+fn watch_copies_messages_matching_the_pattern() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    let _term3 = TestClient::login(server.addr, "term3");
+    assert_eq!(
+        term1.ask("System watch term2.*"),
+        "System>term1 @watch term2.*"
+    );
+    assert_eq!(
+        term1.ask("System watch term2.*"),
+        "System>term1 @watch Er: Pattern term2.* is already watched."
+    );
+    assert_eq!(
+        term2.ask("System watch term*"),
+        "System>term2 @watch Er: Command denied."
+    );
+    term2.send("term3.ch1 hello");
+    term2.send("term3 hello");
+    term2.send("term2.ch1 hello");
+    assert_eq!(term2.recv(), "term2>term2.ch1 hello");
+    // Only the message to term2.ch1 matches.
+    assert_eq!(term1.recv(), "term2>term2.ch1 hello");
+    assert_eq!(
+        term1.ask("System unwatch term2.*"),
+        "System>term1 @unwatch term2.*"
+    );
+    assert_eq!(
+        term1.ask("System unwatch term2.*"),
+        "System>term1 @unwatch Er: Pattern term2.* is not watched."
+    );
+}
+
+#[test]
+fn threadinfo_counts_handler_threads() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System threadinfo"),
+        "System>term1 @threadinfo threads=2 nodes=2 ok"
+    );
+    drop(term2);
+    // The handler ends once it has seen the closed connection.
+    let mut reply = String::new();
+    for _ in 0..50 {
+        reply = term1.ask("System threadinfo");
+        if reply.ends_with("threads=1 nodes=1 ok") {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    assert_eq!(reply, "System>term1 @threadinfo threads=1 nodes=1 ok");
+}
+
+#[test]
+fn loadtest_routes_messages_of_all_clients() {
+    let server = start_server();
+    let test = crate::loadtest::LoadTest {
+        addr: server.addr,
+        keydir: DEFAULT_LIBDIR.to_string(),
+        prefix: "term".to_string(),
+        clients: 3,
+        rate: 20,
+        duration: Duration::from_millis(500),
+    };
+    let report = crate::loadtest::run(&test).expect("loadtest failed!");
+    assert_eq!(report.connected, 3);
+    assert!(report.sent > 0);
+    assert_eq!(report.received, report.sent);
+    assert!(report.percentile(50.0) <= report.percentile(99.0));
+}
+
+#[test]
+fn paused_routing_rejects_messages() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System pause"),
+        "System>term2 @pause Er: Command denied."
+    );
+    assert_eq!(
+        term1.ask("System pause"),
+        "System>term1 @pause Routing paused."
+    );
+    assert_eq!(term2.ask("term1 hello"), "System>term2 Er: Routing paused.");
+    assert_eq!(
+        term2.ask("System hello"),
+        "System>term2 @hello Nice to meet you."
+    );
+    assert_eq!(
+        term1.ask("System resume"),
+        "System>term1 @resume Routing resumed, 0 message(s) to replay."
+    );
+    term2.send("term1 hello");
+    assert_eq!(term1.recv(), "term2>term1 hello");
+}
+
+#[test]
+fn locked_registrations_refuse_new_nodes() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System lockregistrations"),
+        "System>term2 @lockregistrations Er: Command denied."
+    );
+    assert_eq!(
+        term1.ask("System lockregistrations"),
+        "System>term1 @lockregistrations Registrations locked."
+    );
+    assert!(term1.ask("System stats").contains(" registrations=locked "));
+    let (mut term3, reply) = TestClient::register(server.addr, "term3", "stars");
+    assert_eq!(reply, "System> Er: Registrations are temporarily locked.");
+    assert_eq!(term3.recv(), "");
+    term2.send("term1 hello");
+    assert_eq!(term1.recv(), "term2>term1 hello");
+    assert_eq!(
+        term1.ask("System unlockregistrations"),
+        "System>term1 @unlockregistrations Registrations unlocked."
+    );
+    TestClient::login(server.addr, "term3");
+}
+
+#[test]
+fn paused_routing_buffers_messages() {
+    let server = start_server_with(ServerConfig {
+        pause_mode: PauseMode::Buffer,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System pause"),
+        "System>term1 @pause Routing paused."
+    );
+    term2.send("term1 first");
+    term2.send("term1 second");
+    assert_eq!(
+        term2.ask("System hello"),
+        "System>term2 @hello Nice to meet you."
+    );
+    assert_eq!(
+        term1.ask("System resume"),
+        "System>term1 @resume Routing resumed, 2 message(s) to replay."
+    );
+    assert_eq!(term1.recv(), "term2>term1 first");
+    assert_eq!(term1.recv(), "term2>term1 second");
+}
+
+#[test]
+fn full_pause_buffer_drops_the_oldest_messages() {
+    let server = start_server_with(ServerConfig {
+        pause_mode: PauseMode::Buffer,
+        pause_buffer_bytes: 30,
+        pause_overflow: PauseOverflow::DropOldest,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System pause"),
+        "System>term1 @pause Routing paused."
+    );
+    term2.send("term1 first");
+    term2.send("term1 second");
+    term2.send("term1 third");
+    assert_eq!(
+        term2.ask("System hello"),
+        "System>term2 @hello Nice to meet you."
+    );
+    assert_eq!(
+        term1.ask("System resume"),
+        "System>term1 @resume Routing resumed, 2 message(s) to replay."
+    );
+    assert_eq!(term1.recv(), "term2>term1 second");
+    assert_eq!(term1.recv(), "term2>term1 third");
+    assert!(term1.ask("System stats").contains(" paused_dropped=1 "));
+}
+
+#[test]
+fn system_name_is_configurable() {
+    let server = start_server_with(ServerConfig {
+        system_name: "Kernel".to_string(),
+        ..test_config()
+    });
+    let (mut term1, reply) = TestClient::register(server.addr, "term1", "stars");
+    assert_eq!(reply, "Kernel>term1 Ok:");
+    assert_eq!(
+        term1.ask("Kernel hello"),
+        "Kernel>term1 @hello Nice to meet you."
+    );
+    assert_eq!(
+        term1.ask("System hello"),
+        "Kernel>term1 @hello Er: System is down."
+    );
+}
+
+#[test]
+fn invalid_system_name_is_rejected() {
+    let config = ServerConfig {
+        system_name: "Sys.tem".to_string(),
+        ..test_config()
+    };
+    let err = Server::bind(config).err().expect("bind should fail");
+    assert!(
+        err.to_string()
+            .starts_with("ERROR: Invalid system node name")
+    );
+}
+
+#[test]
+fn setloglevel_validates_the_level() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("System setloglevel DEBUG"),
+        "System>term1 @setloglevel debug"
+    );
+    assert_eq!(crate::console::level(), crate::console::LogLevel::Debug);
+    assert_eq!(
+        term1.ask("System setloglevel verbose"),
+        "System>term1 @setloglevel Er: Unknown log level verbose."
+    );
+    assert_eq!(
+        term1.ask("System setloglevel info"),
+        "System>term1 @setloglevel info"
+    );
+}
+
+#[cfg(feature = "syslog")]
+#[test]
+fn syslog_messages_carry_node_and_peer() {
+    use crate::syslog::format_message;
+    let ip = Some([192, 168, 1, 7].into());
+    assert_eq!(
+        format_message("reject", "term1", ip, "Bad host."),
+        "reject node=term1 ip=192.168.1.7: Bad host."
+    );
+    assert_eq!(
+        format_message("connect", "term1", None, "Connected."),
+        "connect node=term1 ip=-: Connected."
+    );
+}
+
+#[test]
+fn interval_stats_report_the_delta_and_rate() {
+    use crate::stats::IntervalStats;
+    let start = std::time::Instant::now();
+    let mut stats = IntervalStats::new(100, start);
+    let (routed, rate) = stats.update(150, start + Duration::from_secs(5));
+    assert_eq!(routed, 50);
+    assert!((rate - 10.0).abs() < 1e-9);
+    // The next interval starts from the last values.
+    let (routed, rate) = stats.update(150, start + Duration::from_secs(10));
+    assert_eq!((routed, rate), (0, 0.0));
+}
+
+#[test]
+fn event_sink_writes_json_lines() {
+    let path = std::env::temp_dir().join(format!("stars-test-{}-events", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let dest = format!("file:{}", path.display())
+        .parse()
+        .expect("valid sink");
+    let (event_tx, event_rx) = events::channel();
+    crate::eventsink::spawn(dest, event_rx, None);
+    let server = start_server_with_events(test_config(), event_tx);
+    let _term1 = TestClient::login(server.addr, "term1");
+    let (_, reply) = TestClient::register(server.addr, "term2", "wrong");
+    assert_eq!(reply, "System> Er: Bad node name or key");
+    let mut lines = Vec::new();
+    for _ in 0..100 {
+        let content = std::fs::read_to_string(&path).unwrap_or_default();
+        lines = content.lines().map(str::to_string).collect();
+        if lines.len() >= 2 {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let events: Vec<serde_json::Value> = lines
+        .iter()
+        .map(|l| serde_json::from_str(l).expect("json"))
+        .collect();
+    assert_eq!(events[0]["event"], "node_connected");
+    assert_eq!(events[0]["name"], "term1");
+    assert_eq!(events[1]["event"], "connection_rejected");
+    assert_eq!(events[1]["reason"], "Bad node name or key.");
+    assert!(events[1]["time"].is_string());
+}
+
+#[test]
+fn recentdisconnects_lists_the_last_disconnects() {
+    let server = start_server_with(ServerConfig {
+        recent_disconnects: 2,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(
+        term1.ask("System recentdisconnects"),
+        "System>term1 @recentdisconnects 0"
+    );
+    for name in ["term2", "term3"] {
+        let mut client = TestClient::login(server.addr, name);
+        client.send("exit");
+        assert_eq!(client.recv(), "");
+    }
+    term1.ask("System disconnect term1");
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let reply = term1.ask("System recentdisconnects");
+    let entries: Vec<&str> = reply.split(' ').skip(2).collect();
+    assert_eq!(entries[0], "2", "{reply}");
+    let last: Vec<&str> = entries[1].split(',').collect();
+    assert_eq!(
+        (last[0], last[1], last[4]),
+        ("term1", "127.0.0.1", "command")
+    );
+    assert!(entries[2].starts_with("term3,127.0.0.1,") && entries[2].ends_with(",exit"));
+}
+
+#[test]
+fn crlf_line_ending_applies_to_replies_and_routed_messages() {
+    let server = start_server_with(ServerConfig {
+        line_ending: LineEnding::Crlf,
+        ..test_config()
+    });
+    let (mut term1, reply) = TestClient::register(server.addr, "term1", "stars");
+    assert_eq!(reply, "System>term1 Ok:");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term2.send("term1 hello");
+    let mut line = String::new();
+    term1.reader.read_line(&mut line).expect("read failed!");
+    assert_eq!(line, "term2>term1 hello\r\n");
+    term1.send("System hello");
+    line.clear();
+    term1.reader.read_line(&mut line).expect("read failed!");
+    assert_eq!(line, "System>term1 @hello Nice to meet you.\r\n");
+}
+
+#[test]
+fn isup_checks_nodes_and_aliases() {
+    let libdir = libdir_with("isup", &[(ALIASES, "t2 term2\n")]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System isup term2"),
+        "System>term1 @isup term2 up"
+    );
+    assert_eq!(term1.ask("System isup t2"), "System>term1 @isup t2 up");
+    assert_eq!(
+        term1.ask("System isup term3"),
+        "System>term1 @isup term3 down"
+    );
+}
+
+#[test]
+fn reopenlog_continues_in_a_new_file() {
+    let dir = std::env::temp_dir();
+    let path = dir.join(format!("stars-test-{}-log", std::process::id()));
+    let rotated = dir.join(format!("stars-test-{}-log.1", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    crate::console::open_logfile(&path).expect("open log file");
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    std::fs::rename(&path, &rotated).expect("rotate");
+    assert_eq!(
+        term1.ask("System reopenlog"),
+        "System>term1 @reopenlog Log file reopened."
+    );
+    let _term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System hello");
+    let old = std::fs::read_to_string(&rotated).expect("rotated log");
+    let new = std::fs::read_to_string(&path).expect("new log");
+    assert!(old.contains(" term1 connected."), "{old}");
+    assert!(new.contains(" term2 connected."), "{new}");
+}
+
+#[test]
+fn connections_per_host_are_limited() {
+    let server = start_server_with(ServerConfig {
+        max_nodes_per_host: 2,
+        ..test_config()
+    });
+    let term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    let (_, reply) = TestClient::register(server.addr, "term3", "stars");
+    assert_eq!(reply, "System> Er: Too many connections from this host.");
+    drop(term1);
+    for _ in 0..50 {
+        if term2.ask("System isup term1").ends_with(" down") {
+            break;
+        }
+        thread::sleep(Duration::from_millis(20));
+    }
+    let (_, reply) = TestClient::register(server.addr, "term3", "stars");
+    assert_eq!(reply, "System>term3 Ok:");
+
+    let server = start_server_with(ServerConfig {
+        max_nodes_per_host: 1,
+        host_limit_exempt: vec!["127.0.0.1".parse().unwrap()],
+        ..test_config()
+    });
+    let _term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+}
+
+#[test]
+fn dumpaliases_flags_conflicting_aliases() {
+    let aliases = "t2 term2\nt3 term2\nterm1 term4\nterm4 term5\n";
+    let libdir = libdir_with("dumpaliases", &[(ALIASES, aliases)]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir,
+        ..test_config()
+    });
+    let mut term3 = TestClient::login(server.addr, "term3");
+    assert_eq!(
+        term3.ask("System dumpaliases"),
+        "System>term3 @dumpaliases t2=term2!reverse:t3 t3=term2 term1=term4 term4=term5!collides"
+    );
+    let reply = term3.ask("System dumpaliases json");
+    let json = reply
+        .strip_prefix("System>term3 @dumpaliases ")
+        .expect(&reply);
+    let dump: serde_json::Value = serde_json::from_str(json).expect("json");
+    assert_eq!(dump["aliases"][0]["conflicts"][0], "reverse:t3");
+    assert_eq!(dump["aliases"][3]["conflicts"][0], "collides");
+    assert_eq!(dump["reverse"]["term2"], "t3");
+}
+
+#[test]
+fn dumpdot_lists_nodes_and_subscriptions() {
+    let libdir = libdir_with("dumpdot", &[(ALIASES, "t2 term2\n")]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term1.ask("System flgon term3"),
+        "System>term1 @flgon Node term3 has been registered."
+    );
+    assert_eq!(
+        term1.ask("System dumpdot"),
+        "System>term1 @dumpdot digraph stars { \"term1\"; \"term2\" [label=\"term2 (t2)\"]; \
+         \"term3\" [style=dashed]; \"term1\" -> \"term3\"; }"
+    );
+}
+
+#[test]
+fn stalled_registration_times_out() {
+    let server = start_server_with(ServerConfig {
+        timeout: 300,
+        ..test_config()
+    });
+    let (mut client, _) = TestClient::connect(server.addr);
+    // Trickling bytes without the newline does not extend the timeout.
+    for part in ["te", "rm", "1 "] {
+        client.send_bytes(part.as_bytes());
+        thread::sleep(Duration::from_millis(120));
+    }
+    assert_eq!(client.recv(), "System> Er: Registration timed out.");
+    assert_eq!(client.recv(), "");
+
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let reply = term1.ask("System acceptstats");
+    assert!(!reply.contains("registration_timeouts=0 "), "{reply}");
+}
+
+#[test]
+fn oversized_registration_is_rejected() {
+    let server = start_server_with(ServerConfig {
+        max_registration_len: 32,
+        ..test_config()
+    });
+    let (mut client, _) = TestClient::connect(server.addr);
+    client.send_bytes(&[b'x'; 64]);
+    assert_eq!(client.recv(), "System> Er: Registration too long.");
+    assert_eq!(client.recv(), "");
+    let (_, reply) = TestClient::register(server.addr, "term1", "stars");
+    assert_eq!(reply, "System>term1 Ok:");
+}
+
+#[test]
+fn repeated_violations_ban_the_host() {
+    let server = start_server_with(ServerConfig {
+        max_registration_len: 32,
+        autoban_threshold: 2,
+        ..test_config()
+    });
+    for _ in 0..2 {
+        let (mut client, _) = TestClient::connect(server.addr);
+        client.send_bytes(&[b'x'; 64]);
+        assert_eq!(client.recv(), "System> Er: Registration too long.");
+    }
+    // Closed without the node key.
+    let stream = TcpStream::connect(server.addr).expect("connect failed!");
+    stream
+        .set_read_timeout(Some(Duration::from_secs(5)))
+        .expect("set timeout failed!");
+    let mut reply = String::new();
+    BufReader::new(stream)
+        .read_line(&mut reply)
+        .expect("read failed!");
+    assert_eq!(reply, "");
+}
+
+#[test]
+fn connect_and_disconnect_notify_all_subscribers() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System flgon term3");
+    term2.ask("System flgon term3");
+    let mut term3 = TestClient::login(server.addr, "term3");
+    assert_eq!(term1.recv(), "term3>term1 _Connected");
+    assert_eq!(term2.recv(), "term3>term2 _Connected");
+    term3.send("System _ready");
+    assert_eq!(term1.recv(), "term3>term1 _ready");
+    assert_eq!(term2.recv(), "term3>term2 _ready");
+    term3.send("exit");
+    assert_eq!(term1.recv(), "term3>term1 _Disconnected");
+    assert_eq!(term2.recv(), "term3>term2 _Disconnected");
+}
+
+#[test]
+fn subscriber_known_by_alias_is_notified() {
+    let libdir = libdir_with("aliassubscriber", &[(ALIASES, "t2 term2\n")]);
+    let server = start_server_with(ServerConfig {
+        libdir: libdir.clone(),
+        keydir: libdir,
+        ..test_config()
+    });
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System flgon term3"),
+        "System>t2 @flgon Node term3 has been registered."
+    );
+    let _term3 = TestClient::login(server.addr, "term3");
+    assert_eq!(term2.recv(), "term3>t2 _Connected");
+}
+
+#[test]
+fn subscriptions_of_absent_nodes_are_skipped() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    // A subscriber which is not connected, and a target which never connects.
+    term1.ask("ghost>System flgon term2");
+    term1.ask("System flgon term9");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term2.send("System _ready");
+    term2.send("exit");
+    assert_eq!(
+        term1.ask("System hello"),
+        "System>term1 @hello Nice to meet you."
+    );
+}
+
+#[test]
+fn resources_reports_process_usage() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let reply = term1.ask("System resources");
+    let pairs: Vec<(&str, &str)> = reply
+        .strip_prefix("System>term1 @resources ")
+        .expect("resources reply")
+        .split(' ')
+        .map(|kv| kv.split_once('=').expect("key=value"))
+        .collect();
+    assert_eq!(
+        pairs.iter().map(|(k, _)| *k).collect::<Vec<_>>(),
+        ["rss_kb", "fds", "threads"]
+    );
+    if cfg!(target_os = "linux") {
+        assert!(
+            pairs
+                .iter()
+                .all(|(_, v)| v.parse::<u64>().is_ok_and(|n| n > 0)),
+            "{reply}"
+        );
+    }
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System resources"),
+        "System>term2 @resources Er: Command denied."
+    );
+}
+
+#[test]
+fn caps_reply_confirms_requested_capabilities() {
+    let server = start_server();
+    let (mut term1, _) = TestClient::register(server.addr, "term1", "stars retain=1 binary=1");
+    assert_eq!(term1.recv(), "System>term1 @caps enabled=binary,retain");
+    // Legacy clients get no extra line.
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System hello"),
+        "System>term2 @hello Nice to meet you."
+    );
+}
+
+#[test]
+fn connection_limit_rejects_or_evicts_the_stalest_node() {
+    let server = start_server_with(ServerConfig {
+        max_connections: 1,
+        ..test_config()
+    });
+    let _term1 = TestClient::login(server.addr, "term1");
+    let (_, reply) = TestClient::register(server.addr, "term2", "stars");
+    assert_eq!(reply, "System> Er: Too many connections.");
+
+    let server = start_server_with(ServerConfig {
+        max_connections: 2,
+        over_limit_policy: OverLimitPolicy::EvictOldest,
+        ..test_config()
+    });
+    let mut term2 = TestClient::login(server.addr, "term2");
+    let mut term3 = TestClient::login(server.addr, "term3");
+    term2.ask("System hello");
+    let mut term1 = TestClient::login(server.addr, "term1");
+    assert_eq!(term3.recv(), "");
+    let reply = term1.ask("System recentdisconnects");
+    assert!(
+        reply.contains(" term3,") && reply.contains(",evicted"),
+        "{reply}"
+    );
+    // term1 is in shutdown_allow.cfg and never evicted, term2 is the only candidate.
+    let _term3 = TestClient::login(server.addr, "term3");
+    assert_eq!(term2.recv(), "");
+
+    let server = start_server_with(ServerConfig {
+        max_connections: 2,
+        over_limit_policy: OverLimitPolicy::EvictOldest,
+        evict_exempt: vec!["term2".to_string()],
+        ..test_config()
+    });
+    let _term1 = TestClient::login(server.addr, "term1");
+    let _term2 = TestClient::login(server.addr, "term2");
+    let (_, reply) = TestClient::register(server.addr, "term3", "stars");
+    assert_eq!(reply, "System> Er: Too many connections.");
+}
+
+#[test]
+fn event_loop_between_subscribers_is_cut() {
+    let server = start_server_with(ServerConfig {
+        event_hop_limit: 4,
+        ..test_config()
+    });
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    term1.ask("System flgon term2");
+    term2.ask("System flgon term1");
+    // Each node answers the event it gets with one of its own. The event is delivered
+    // before the hello reply of the sender, so the other side sees it first if it passed.
+    let (mut from, mut to) = (&mut term1, &mut term2);
+    let mut delivered = 0;
+    loop {
+        from.send("System _ping");
+        from.ask("System hello");
+        let line = to.ask("System hello");
+        if line.ends_with("@hello Nice to meet you.") {
+            break;
+        }
+        assert!(line.ends_with(" _ping"), "{line}");
+        to.recv();
+        delivered += 1;
+        assert!(delivered <= 4, "the loop was not cut");
+        std::mem::swap(&mut from, &mut to);
+    }
+    assert_eq!(delivered, 4);
+}
+
+#[test]
+fn verifykey_checks_a_key_without_registering() {
+    let server = start_server();
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut verify = |args: &str| term1.ask(&format!("System verifykey {args}"));
+    assert_eq!(verify("term2 stars"), "System>term1 @verifykey term2 valid");
+    assert_eq!(
+        verify("term2 start"),
+        "System>term1 @verifykey term2 invalid"
+    );
+    assert_eq!(
+        verify("starsterm ,ee{9F0CC5j 1"),
+        "System>term1 @verifykey starsterm valid"
+    );
+    assert_eq!(
+        verify("starsterm ,ee{9F0CC5j"),
+        "System>term1 @verifykey starsterm invalid"
+    );
+    assert_eq!(
+        verify("term2"),
+        "System>term1 @verifykey Er: Parameter is not enough."
+    );
+    assert_eq!(
+        verify("term2 stars x"),
+        "System>term1 @verifykey Er: Invalid challenge."
+    );
+    assert_eq!(
+        term1.ask("System isup term2"),
+        "System>term1 @isup term2 down"
+    );
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(
+        term2.ask("System verifykey term3 stars"),
+        "System>term2 @verifykey Er: Command denied."
+    );
+}
+
+#[test]
+fn shutdown_ends_the_server_and_sends_the_event() {
+    let server = Server::bind(test_config()).expect("server bind failed!");
+    let addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+    let (event_tx, event_rx) = events::channel();
+    let runner = thread::spawn(move || server.run(event_tx));
+    let mut term1 = TestClient::login(addr, "term1");
+    term1.send("System shutdown");
+    assert_eq!(term1.recv(), "System>term1 SYSTEMSHUTDOWN");
+    drop(term1);
+    assert!(runner.join().expect("server thread panicked").is_ok());
+    assert!(
+        event_rx
+            .try_iter()
+            .any(|ev| matches!(ev, ServerEvent::ServerShutdown))
+    );
+}
+
+#[test]
+fn state_file_keeps_subscriptions_and_overrides_across_a_restart() {
+    let path = std::env::temp_dir().join(format!("stars-test-{}-state", std::process::id()));
+    let _ = std::fs::remove_file(&path);
+    let config = || ServerConfig {
+        state_file: Some(path.to_string_lossy().into_owned()),
+        ..test_config()
+    };
+    let server = Server::bind(config()).expect("server bind failed!");
+    let addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+    let (event_tx, _) = events::channel();
+    let runner = thread::spawn(move || server.run(event_tx));
+    let mut term1 = TestClient::login(addr, "term1");
+    term1.ask("System flgon term2");
+    term1.ask("System denycmd term2 ping");
+    term1.send("System shutdown");
+    assert_eq!(term1.recv(), "System>term1 SYSTEMSHUTDOWN");
+    drop(term1);
+    assert!(runner.join().expect("server thread panicked").is_ok());
+
+    let server = start_server_with(config());
+    let mut term1 = TestClient::login(server.addr, "term1");
+    let mut term2 = TestClient::login(server.addr, "term2");
+    assert_eq!(term1.recv(), "term2>term1 _Connected");
+    assert_eq!(
+        term2.ask("term1 ping"),
+        "System>term2 @ping Er: Command denied."
+    );
+    let _ = std::fs::remove_file(&path);
+}
+
+#[test]
+fn pidfile_is_written_refused_while_running_and_removed() {
+    let path = std::env::temp_dir().join(format!("stars-test-{}.pid", std::process::id()));
+    let config = || ServerConfig {
+        pidfile: Some(path.to_string_lossy().into_owned()),
+        ..test_config()
+    };
+    std::fs::write(&path, format!("{}\n", std::process::id())).expect("write pidfile failed!");
+    assert!(Server::bind(config()).is_err());
+    // Not a running process, as pids are below 2^22.
+    std::fs::write(&path, "99999999\n").expect("write pidfile failed!");
+    let server = Server::bind(config()).expect("server bind failed!");
+    let pid = std::fs::read_to_string(&path).expect("no pidfile");
+    assert_eq!(pid.trim(), std::process::id().to_string());
+    let addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+    let (event_tx, _) = events::channel();
+    let runner = thread::spawn(move || server.run(event_tx));
+    let mut term1 = TestClient::login(addr, "term1");
+    term1.send("System shutdown");
+    assert_eq!(term1.recv(), "System>term1 SYSTEMSHUTDOWN");
+    drop(term1);
+    assert!(runner.join().expect("server thread panicked").is_ok());
+    assert!(!path.exists());
+}
+
+#[test]
+fn requested_shutdown_notifies_the_nodes() {
+    let server = Server::bind(test_config()).expect("server bind failed!");
+    let addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+    let handle = server.handle();
+    let (event_tx, _) = events::channel();
+    let runner = thread::spawn(move || server.run(event_tx));
+    let mut term2 = TestClient::login(addr, "term2");
+    handle.request_shutdown();
+    assert_eq!(term2.recv(), "System>term2 SYSTEMSHUTDOWN");
+    assert_eq!(term2.recv(), "");
+    drop(term2);
+    assert!(runner.join().expect("server thread panicked").is_ok());
+}
+
+#[test]
+fn shutdown_notifies_a_connection_once_for_its_claimed_names() {
+    let server = Server::bind(test_config()).expect("server bind failed!");
+    let addr = SocketAddr::from(([127, 0, 0, 1], server.local_addr().port()));
+    let (event_tx, _) = events::channel();
+    let runner = thread::spawn(move || server.run(event_tx));
+    let mut term1 = TestClient::login(addr, "term1");
+    assert_eq!(
+        term1.ask("System claim term3 stars"),
+        "System>term1 @claim term3"
+    );
+    term1.send("System shutdown");
+    assert_eq!(term1.recv(), "System>term1 SYSTEMSHUTDOWN");
+    assert_eq!(term1.recv(), "");
+    drop(term1);
+    assert!(runner.join().expect("server thread panicked").is_ok());
+}
@@ -1,20 +1,28 @@
-use crate::{dbprint, lazy_static, starsdata::StarsData};
+use crate::{console, dbprint, lazy_static, starsdata::StarsData, starserror::StarsError};
 
 use super::definitions::*;
 
 use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
     env,
     fs::File,
-    io::{BufRead, BufReader},
-    net::TcpStream,
-    path::PathBuf,
-    time::SystemTime,
+    io::{BufRead, BufReader, Write},
+    net::{IpAddr, TcpStream},
+    path::{Path, PathBuf},
+    sync::{
+        Mutex,
+        atomic::{AtomicBool, Ordering},
+        mpsc,
+    },
+    thread,
+    time::{Duration, Instant, SystemTime},
 };
 
 use chrono::{DateTime, offset::Local};
 use dns_lookup::lookup_addr;
 use rand::Rng;
 use regex::Regex;
+use socket2::{SockRef, TcpKeepalive};
 
 pub fn get_serverdir() -> PathBuf {
     env::current_dir().expect("Error reading work directory!")
@@ -29,14 +37,27 @@ pub fn check_file_exists(fname: &str, libdir: &str) -> GenericResult<bool> {
     }
 }
 
-pub fn load_file_to_list(fname: &str, libdir: &str) -> GenericResult<Vec<String>> {
+// Reads a cfg file and returns its content lines.
+// Lines starting with '#' or ';' and empty lines are ignored.
+// 'include <relative-path>' lines are replaced by the content of the given file,
+// the path is resolved against the directory of the including file.
+fn load_cfg_lines(filepath: &Path, depth: usize) -> GenericResult<Vec<String>> {
+    if depth > MAX_INCLUDE_DEPTH {
+        return Err(GenericError::from(StarsError::IncludeDepth {
+            file: filepath.to_path_buf(),
+        }));
+    }
     let mut filecontent: Vec<String> = vec![];
-    let filepath = get_serverdir().join(libdir).join(fname);
-
     let reader = BufReader::new(File::open(filepath)?);
     for line in reader.lines() {
         let lcontent = line?;
-        if lcontent.starts_with('#') || lcontent.is_empty() {
+        if lcontent.starts_with('#') || lcontent.starts_with(';') || lcontent.is_empty() {
+        } else if let Some(incfile) = lcontent.strip_prefix("include ") {
+            let incpath = match filepath.parent() {
+                Some(dir) => dir.join(incfile.trim()),
+                None => PathBuf::from(incfile.trim()),
+            };
+            filecontent.extend(load_cfg_lines(&incpath, depth + 1)?);
         } else {
             filecontent.push(lcontent);
         }
@@ -44,25 +65,28 @@ pub fn load_file_to_list(fname: &str, libdir: &str) -> GenericResult<Vec<String>
     Ok(filecontent)
 }
 
-pub fn load_file_to_map(
-    fname: &str,
-    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
-) -> GenericResult<()> {
+pub fn load_file_to_list(fname: &str, libdir: &str) -> GenericResult<Vec<String>> {
+    let filepath = get_serverdir().join(libdir).join(fname);
+    load_cfg_lines(&filepath, 0)
+}
+
+pub fn load_file_to_map(fname: &str, sdata: &mut StarsData) -> GenericResult<()> {
     let filepath = get_serverdir().join(&sdata.libdir).join(fname);
 
-    let reader = BufReader::new(File::open(filepath)?);
-    for line in reader.lines() {
-        let lcontent = line?;
-        if lcontent.starts_with('#') || lcontent.is_empty() {
-        } else {
-            let aliasreal: Vec<String> = lcontent.split_whitespace().map(str::to_string).collect();
-            sdata
-                .aliasreal
-                .insert(aliasreal[0].clone(), aliasreal[1].clone());
-            sdata
-                .realalias
-                .insert(aliasreal[1].clone(), aliasreal[0].clone());
+    for lcontent in load_cfg_lines(&filepath, 0)? {
+        let aliasreal: Vec<String> = lcontent.split_whitespace().map(str::to_string).collect();
+        if aliasreal.len() < 2 {
+            return Err(GenericError::from(StarsError::BadConfigLine {
+                file: filepath,
+                line: lcontent,
+            }));
         }
+        sdata
+            .aliasreal
+            .insert(aliasreal[0].clone(), aliasreal[1].clone());
+        sdata
+            .realalias
+            .insert(aliasreal[1].clone(), aliasreal[0].clone());
     }
     dbprint!("load alias");
     dbprint!(sdata.aliasreal);
@@ -81,6 +105,12 @@ pub fn load_keyfile(fname: &str, libdir: &str) -> GenericResult<Vec<String>> {
     Ok(filecontent)
 }
 
+// All timeout settings (--timeout, --write-timeout, --registration-timeout, 'settimeout')
+// are msec with 0 for no timeout, converted here for the socket calls.
+pub fn msec_timeout(msec: u64) -> Option<Duration> {
+    (msec > 0).then(|| Duration::from_millis(msec))
+}
+
 pub fn get_node_id_key() -> u16 {
     let mut rng = rand::rng();
     rng.random_range(0..RNDMAX + 1)
@@ -91,12 +121,119 @@ pub fn system_get_time() -> String {
     date_time.format("%Y-%m-%d %H:%M:%S").to_string()
 }
 
+// The system command with the smallest edit distance to 'cmd', if it is close enough
+// to be a typo.
+pub fn closest_system_command<'a>(
+    cmd: &str,
+    commands: impl Iterator<Item = &'a str>,
+) -> Option<&'a str> {
+    let name = cmd.split_whitespace().next().unwrap_or("");
+    commands
+        .map(|c| (edit_distance(name, c), c))
+        .min()
+        .filter(|(dist, c)| *dist <= 2 && *dist < c.len())
+        .map(|(_, c)| c)
+}
+
+// Compares the whole length of both, so the time taken does not tell how many
+// leading bytes of a secret were right.
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    let mut diff = a.len() ^ b.len();
+    for i in 0..a.len().max(b.len()) {
+        let x = a.get(i).copied().unwrap_or(0);
+        let y = b.get(i).copied().unwrap_or(0);
+        diff |= (x ^ y) as usize;
+    }
+    diff == 0
+}
+
+// Levenshtein distance
+fn edit_distance(a: &str, b: &str) -> usize {
+    let b: Vec<char> = b.chars().collect();
+    let mut row: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.chars().enumerate() {
+        let mut prev = row[0];
+        row[0] = i + 1;
+        for (j, cb) in b.iter().enumerate() {
+            let cur = row[j + 1];
+            row[j + 1] = if ca == *cb {
+                prev
+            } else {
+                1 + prev.min(row[j]).min(cur)
+            };
+            prev = cur;
+        }
+    }
+    row[b.len()]
+}
+
+// Compiler, build time, target and cargo features, captured by build.rs.
+pub fn system_get_buildinfo() -> String {
+    let built = env!("STARS_BUILD_TIME")
+        .parse()
+        .ok()
+        .and_then(|secs| DateTime::from_timestamp(secs, 0))
+        .map(|t| {
+            t.with_timezone(&Local)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        })
+        .unwrap_or_default();
+    let features = match env!("STARS_FEATURES") {
+        "" => "none",
+        list => list,
+    };
+    format!(
+        "Version: {VERSION}; Rustc: {}; Built: {built}; Target: {}; Features: {features}",
+        env!("STARS_RUSTC_VERSION"),
+        env!("STARS_TARGET"),
+    )
+}
+
+// Reverse DNS of the connecting hosts, switched off with --no-reverse-dns.
+static REVERSE_DNS: AtomicBool = AtomicBool::new(true);
+
+lazy_static! {
+    // Host name by ip and the time of the lookup. Failed lookups are kept as the ip string.
+    static ref HOST_CACHE: Mutex<HashMap<IpAddr, (String, Instant)>> = Mutex::new(HashMap::new());
+}
+
+pub fn set_reverse_dns(enabled: bool) {
+    REVERSE_DNS.store(enabled, Ordering::Relaxed);
+}
+
 pub fn system_get_hostname_or_ip(stream: &TcpStream) -> (String, String) {
     let ip = stream.local_addr().unwrap().ip();
-    match lookup_addr(&ip) {
-        Ok(host) => (host, ip.to_string()),
-        Err(_) => (ip.to_string(), ip.to_string()),
+    (resolve_host(ip), ip.to_string())
+}
+
+// Cached for DNS_CACHE_TTL. The lookup runs on its own thread, so a hanging resolver
+// delays the accept loop by at most DNS_LOOKUP_TIMEOUT.
+fn resolve_host(ip: IpAddr) -> String {
+    if !REVERSE_DNS.load(Ordering::Relaxed) {
+        return ip.to_string();
+    }
+    if let Some((host, time)) = HOST_CACHE.lock().expect("can't get the lock!").get(&ip)
+        && time.elapsed() < DNS_CACHE_TTL
+    {
+        return host.clone();
     }
+    let (tx, rx) = mpsc::channel();
+    thread::spawn(move || {
+        let _ = tx.send(lookup_addr(&ip));
+    });
+    let host = match rx.recv_timeout(DNS_LOOKUP_TIMEOUT) {
+        Ok(Ok(host)) => host,
+        Ok(Err(_)) => ip.to_string(),
+        Err(_) => {
+            console::error(format!("Reverse DNS lookup of {ip} timed out."));
+            ip.to_string()
+        }
+    };
+    let mut cache = HOST_CACHE.lock().expect("can't get the lock!");
+    cache.retain(|_, (_, time)| time.elapsed() < DNS_CACHE_TTL);
+    cache.insert(ip, (host.clone(), Instant::now()));
+    host
 }
 
 pub fn system_check_host(
@@ -106,10 +243,6 @@ pub fn system_check_host(
     unchecked: bool,
     libdir: &str,
 ) -> bool {
-    let mut check = vec![hostname];
-    if hostname != ipadr {
-        check.push(ipadr);
-    }
     let allowed_host = match load_file_to_list(fname, libdir) {
         Ok(hosts) => hosts,
         Err(err) => {
@@ -117,7 +250,20 @@ pub fn system_check_host(
             return unchecked;
         }
     };
+    check_host_list(&allowed_host, hostname, ipadr, unchecked)
+}
 
+// Matches the host name and ip against the wildcard patterns of a host list.
+pub fn check_host_list(
+    allowed_host: &[String],
+    hostname: &str,
+    ipadr: &str,
+    unchecked: bool,
+) -> bool {
+    let mut check = vec![hostname];
+    if hostname != ipadr {
+        check.push(ipadr);
+    }
     let patterns: Vec<Regex> = allowed_host
         .iter()
         .map(|p| Regex::new(&wildcard_to_regex(p)).unwrap())
@@ -132,7 +278,7 @@ pub fn system_check_host(
     unchecked
 }
 
-fn wildcard_to_regex(pattern: &str) -> String {
+pub fn wildcard_to_regex(pattern: &str) -> String {
     let mut regex = String::from("^");
     for ch in pattern.chars() {
         match ch {
@@ -158,21 +304,52 @@ pub fn check_term_and_host(nd: &str, hd: &TcpStream, libdir: &str) -> bool {
     false
 }
 
+// Checks the node name against the prefixes allowed for the client host in node_prefix.cfg.
+// Lines are '<host or ip> <prefix>', hosts support the same wildcards as allow.cfg.
+// Without the file or an entry for the host every name is allowed.
+pub fn check_node_prefix(nd: &str, hd: &TcpStream, libdir: &str) -> bool {
+    if !check_file_exists(NODE_PREFIX, libdir).unwrap() {
+        return true;
+    }
+    let entries = match load_file_to_list(NODE_PREFIX, libdir) {
+        Ok(entries) => entries,
+        Err(err) => {
+            console::error(format!("Error loading {NODE_PREFIX}: {err}"));
+            return false;
+        }
+    };
+    let (host, ip) = system_get_hostname_or_ip(hd);
+    let prefixes: Vec<String> = entries
+        .iter()
+        .filter_map(|line| {
+            let (pattern, prefix) = line.split_once(char::is_whitespace)?;
+            let re = Regex::new(&wildcard_to_regex(pattern)).ok()?;
+            (re.is_match(&host) || re.is_match(&ip)).then(|| prefix.trim().to_string())
+        })
+        .collect();
+    prefixes.is_empty() || prefixes.iter().any(|p| nd.starts_with(p.as_str()))
+}
+
+// Any malformed name or key response is a mismatch. The name becomes a file name,
+// so only plain node names are looked up.
 pub fn check_nodekey(nname: &str, nkeynum: usize, nkeyval: &str, keydir: &str) -> bool {
-    let file_name = nname.to_owned() + ".key";
-    if !check_file_exists(&file_name, keydir).unwrap() {
+    lazy_static! {
+        static ref NODENAME: Regex = Regex::new(r"^[a-zA-Z_0-9\-]+$").expect("Error parsing regex");
+    }
+    if !NODENAME.is_match(nname) || nkeyval.is_empty() || nkeyval.len() > MAX_KEY_LEN {
         return false;
     }
-    let kfile = load_keyfile(&file_name, keydir).unwrap();
-    let mut kcount = kfile.len();
-    if kcount == 0 {
+    let file_name = nname.to_owned() + ".key";
+    if !check_file_exists(&file_name, keydir).unwrap_or(false) {
         return false;
     }
-    kcount = nkeynum % kcount;
-    if kfile[kcount] == nkeyval {
-        return true;
+    let Ok(kfile) = load_keyfile(&file_name, keydir) else {
+        return false;
+    };
+    if kfile.is_empty() {
+        return false;
     }
-    false
+    constant_time_eq(kfile[nkeynum % kfile.len()].as_bytes(), nkeyval.as_bytes())
 }
 
 fn get_checkcmd_string(buf: &str) -> Option<&str> {
@@ -254,20 +431,372 @@ pub fn is_shutdowncmd_allow(node: &str, shutallow: &[String]) -> bool {
     false
 }
 
-pub fn system_list_nodes(nodes: &mut std::sync::MutexGuard<'_, NodeList>) -> String {
-    nodes.keys().map(|s| &**s).collect::<Vec<_>>().join(" ")
+pub fn system_list_nodes(
+    nodes: &mut std::sync::MutexGuard<'_, NodeList>,
+    page: usize,
+) -> Option<String> {
+    let mut names: Vec<&str> = nodes.keys().map(|s| &**s).collect();
+    names.sort();
+    paginate(names, page)
+}
+
+// Returns one page (starting with 1) of LIST_PAGE_SIZE items, or None if there is no such page.
+// A list longer than one page gets '[page n/total]' appended, shorter lists are returned whole.
+fn paginate(items: Vec<&str>, page: usize) -> Option<String> {
+    let pages = items.len().div_ceil(LIST_PAGE_SIZE).max(1);
+    if page == 0 || page > pages {
+        return None;
+    }
+    let list = items
+        .chunks(LIST_PAGE_SIZE)
+        .nth(page - 1)
+        .unwrap_or_default()
+        .join(" ");
+    if pages == 1 {
+        Some(list)
+    } else {
+        Some(format!("{list} [page {page}/{pages}]"))
+    }
+}
+
+pub fn system_list_nodestats(sdata: &mut std::sync::MutexGuard<'_, StarsData>) -> String {
+    let mut names: Vec<&String> = sdata.nodeinfo.keys().collect();
+    names.sort();
+    names
+        .into_iter()
+        .map(|name| {
+            let info = &sdata.nodeinfo[name];
+            let muted = if info.muted { ",muted" } else { "" };
+            let draining = if info.draining { ",draining" } else { "" };
+            format!(
+                "{name}:{}{muted}{draining}",
+                info.traffic.summary().replace(' ', ",")
+            )
+        })
+        .collect::<Vec<_>>()
+        .join(" ")
 }
 
-pub fn system_list_aliases(sdata: &mut std::sync::MutexGuard<'_, StarsData>) -> String {
-    sdata
+// Nodes without traffic in either direction for at least min_idle as
+// 'name:idle=S,received=S,sent=S', longest idle first. 'sent=-' if nothing was routed to it.
+// A claimed name shares the times of the node which claimed it.
+pub fn system_list_idle(
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    nodes: &NodeList,
+    min_idle: Duration,
+) -> String {
+    let now = Instant::now();
+    let secs = |t: Option<Instant>| t.map(|t| now.duration_since(t).as_secs());
+    let claimed = nodes.claims.iter().flat_map(|(owner, names)| {
+        let info = sdata.nodeinfo.get(owner);
+        names.iter().filter_map(move |name| Some((name, info?)))
+    });
+    let mut idle: Vec<(u64, String)> = sdata
+        .nodeinfo
+        .iter()
+        .chain(claimed)
+        .filter_map(|(name, info)| {
+            let last = info.last_active.max(info.last_sent)?;
+            let idle = now.duration_since(last);
+            (idle >= min_idle).then(|| {
+                let received = secs(info.last_active).map_or("-".to_string(), |s| s.to_string());
+                let sent = secs(info.last_sent).map_or("-".to_string(), |s| s.to_string());
+                let idle_secs = idle.as_secs();
+                let entry = format!("{name}:idle={idle_secs},received={received},sent={sent}");
+                (idle.as_millis() as u64, entry)
+            })
+        })
+        .collect();
+    idle.sort_by(|a, b| b.0.cmp(&a.0).then_with(|| a.1.cmp(&b.1)));
+    idle.into_iter()
+        .map(|(_, entry)| entry)
+        .collect::<Vec<_>>()
+        .join(" ")
+}
+
+// One connected node as 'key=value' pairs, None if it is not connected. Times are
+// seconds ago ('-' for never), what the server does not keep (a rate limit) is 'n/a'.
+pub fn system_node_info(sdata: &StarsData, name: &str) -> Option<String> {
+    const TIME_FORMAT: &str = "%Y-%m-%dT%H:%M:%S";
+    let info = sdata.nodeinfo.get(name)?;
+    let now = Instant::now();
+    let ago = |t: Option<Instant>| {
+        t.map_or("-".to_string(), |t| {
+            now.duration_since(t).as_secs().to_string()
+        })
+    };
+    let flag = |set: bool| if set { 1 } else { 0 };
+    let connected = info
+        .connected
+        .map_or("-".to_string(), |t| t.format(TIME_FORMAT).to_string());
+    let peer = info
+        .peer
+        .map_or("-".to_string(), |peer| peer.ip().to_string());
+    let subscriptions = sdata
+        .nodes_flgon
+        .get(name)
+        .map_or(0, |targets| targets.len());
+    let subscribers = sdata
+        .nodes_flgon
+        .values()
+        .filter(|t| t.contains(name))
+        .count();
+    let mut aliases: Vec<&str> = sdata
         .aliasreal
-        .clone()
+        .iter()
+        .filter(|(_, real)| *real == name)
+        .map(|(alias, _)| &**alias)
+        .collect();
+    aliases.sort();
+    let aliases = if aliases.is_empty() {
+        "-".to_string()
+    } else {
+        aliases.join(",")
+    };
+    Some(format!(
+        "connected={connected} peer={peer} {} received={} sent={} subscriptions={subscriptions} \
+         subscribers={subscribers} muted={} draining={} rate_limited=n/a aliases={aliases}",
+        info.traffic.summary(),
+        ago(info.last_active),
+        ago(info.last_sent),
+        flag(info.muted),
+        flag(info.draining),
+    ))
+}
+
+// Nodes grouped by client ip as 'ip=node1,node2 ...', sorted by ip and node name.
+pub fn system_list_byhost(sdata: &mut std::sync::MutexGuard<'_, StarsData>) -> String {
+    let mut hosts: BTreeMap<String, Vec<&str>> = BTreeMap::new();
+    for (name, info) in &sdata.nodeinfo {
+        let ip = match info.peer {
+            Some(addr) => addr.ip().to_string(),
+            None => "unknown".to_string(),
+        };
+        hosts.entry(ip).or_default().push(name);
+    }
+    hosts
         .into_iter()
-        .map(|(k, v)| format!("{k},{v}"))
+        .map(|(ip, mut names)| {
+            names.sort();
+            format!("{ip}={}", names.join(","))
+        })
         .collect::<Vec<_>>()
         .join(" ")
 }
 
+// Nodes in a special state as 'name:reason,...', prefixed with the number of nodes.
+pub fn system_list_restricted(sdata: &mut std::sync::MutexGuard<'_, StarsData>) -> String {
+    let mut list: Vec<String> = sdata
+        .nodeinfo
+        .iter()
+        .filter_map(|(name, info)| {
+            let reasons = info.restrictions();
+            (!reasons.is_empty()).then(|| format!("{name}:{}", reasons.join(",")))
+        })
+        .collect();
+    list.sort();
+    list.insert(0, list.len().to_string());
+    list.join(" ")
+}
+
+pub fn system_list_aliases(
+    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
+    page: usize,
+) -> Option<String> {
+    let mut aliases: Vec<String> = sdata
+        .aliasreal
+        .iter()
+        .map(|(k, v)| format!("{k},{v}"))
+        .collect();
+    aliases.sort();
+    paginate(aliases.iter().map(|s| &**s).collect(), page)
+}
+
+// Problems of one alias line: the reverse mapping of the real name points to another
+// alias (two aliases of one node), or the alias is itself the real name of another alias.
+fn alias_conflicts(sdata: &StarsData, alias: &str, real: &str) -> Vec<String> {
+    let mut conflicts = vec![];
+    if let Some(other) = sdata.realalias.get(real).filter(|other| *other != alias) {
+        conflicts.push(format!("reverse:{other}"));
+    }
+    if sdata.realalias.contains_key(alias) {
+        conflicts.push("collides".to_string());
+    }
+    conflicts
+}
+
+fn sorted_aliases(sdata: &StarsData) -> Vec<(&String, &String)> {
+    let mut aliases: Vec<(&String, &String)> = sdata.aliasreal.iter().collect();
+    aliases.sort();
+    aliases
+}
+
+// 'alias=real' pairs, a conflict is appended as '!reverse:<alias>' or '!collides'.
+pub fn system_dump_aliases(sdata: &StarsData, page: usize) -> Option<String> {
+    let items: Vec<String> = sorted_aliases(sdata)
+        .into_iter()
+        .map(|(alias, real)| {
+            let flags: String = alias_conflicts(sdata, alias, real)
+                .iter()
+                .map(|c| format!("!{c}"))
+                .collect();
+            format!("{alias}={real}{flags}")
+        })
+        .collect();
+    paginate(items.iter().map(|s| &**s).collect(), page)
+}
+
+pub fn system_dump_aliases_json(sdata: &StarsData) -> String {
+    let aliases: Vec<serde_json::Value> = sorted_aliases(sdata)
+        .into_iter()
+        .map(|(alias, real)| {
+            serde_json::json!({
+                "alias": alias,
+                "real": real,
+                "conflicts": alias_conflicts(sdata, alias, real),
+            })
+        })
+        .collect();
+    serde_json::json!({ "aliases": aliases, "reverse": sdata.realalias }).to_string()
+}
+
+// The connected nodes and the flgon subscriptions (subscriber -> node) as a Graphviz
+// digraph on one line. A node with an alias shows it in the label, a subscriber or
+// target that is not connected is dashed.
+pub fn system_dump_dot(sdata: &StarsData) -> String {
+    let quote = |name: &str| format!("\"{}\"", name.replace('\\', "\\\\").replace('"', "\\\""));
+    let mut names: BTreeSet<&str> = sdata.nodeinfo.keys().map(|s| &**s).collect();
+    let mut edges = BTreeSet::new();
+    for (subscriber, targets) in &sdata.nodes_flgon {
+        for target in targets {
+            edges.insert((&**subscriber, &**target));
+            names.insert(subscriber);
+            names.insert(target);
+        }
+    }
+    let mut dot = String::from("digraph stars {");
+    for name in names {
+        let mut attrs = vec![];
+        if let Some(alias) = sdata.realalias.get(name) {
+            attrs.push(format!("label={}", quote(&format!("{name} ({alias})"))));
+        }
+        if !sdata.nodeinfo.contains_key(name) {
+            attrs.push("style=dashed".to_string());
+        }
+        dot.push_str(&format!(" {}", quote(name)));
+        if !attrs.is_empty() {
+            dot.push_str(&format!(" [{}]", attrs.join(",")));
+        }
+        dot.push(';');
+    }
+    for (from, to) in edges {
+        dot.push_str(&format!(" {} -> {};", quote(from), quote(to)));
+    }
+    dot.push_str(" }");
+    dot
+}
+
+// Resident memory, open file descriptors and threads of the server process, as
+// 'rss_kb=.. fds=.. threads=..'. Only Linux has /proc, elsewhere the values are 'unsupported'.
+pub fn system_resource_usage() -> String {
+    let status = std::fs::read_to_string("/proc/self/status").unwrap_or_default();
+    let field = |name: &str| {
+        status
+            .lines()
+            .find_map(|line| line.strip_prefix(name))
+            .and_then(|value| value.split_whitespace().next())
+            .map_or_else(|| "unsupported".to_string(), str::to_string)
+    };
+    // The directory handle of read_dir is one of the listed descriptors.
+    let fds = std::fs::read_dir("/proc/self/fd").map_or_else(
+        |_| "unsupported".to_string(),
+        |dir| (dir.count().saturating_sub(1)).to_string(),
+    );
+    format!(
+        "rss_kb={} fds={fds} threads={}",
+        field("VmRSS:"),
+        field("Threads:")
+    )
+}
+
+// SO_KEEPALIVE with the probe timing. Where the interval and the count can't be set,
+// the system defaults apply.
+pub fn set_tcp_keepalive(
+    stream: &TcpStream,
+    idle: Duration,
+    interval: Duration,
+    count: u32,
+) -> std::io::Result<()> {
+    let keepalive = TcpKeepalive::new().with_time(idle);
+    #[cfg(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "windows",
+    ))]
+    let keepalive = keepalive.with_interval(interval).with_retries(count);
+    #[cfg(not(any(
+        target_os = "linux",
+        target_os = "android",
+        target_os = "macos",
+        target_os = "ios",
+        target_os = "freebsd",
+        target_os = "netbsd",
+        target_os = "windows",
+    )))]
+    let _ = (interval, count);
+    SockRef::from(stream).set_tcp_keepalive(&keepalive)
+}
+
+// A PID file naming a running process means another server was started with it, a
+// leftover of a crashed one is overwritten.
+pub fn check_pidfile(path: &str) -> GenericResult<()> {
+    let Ok(content) = std::fs::read_to_string(path) else {
+        return Ok(());
+    };
+    match content.trim().parse::<u32>() {
+        Ok(pid) if process_alive(pid) => Err(GenericError::from(StarsError::Message(format!(
+            "PID file {path} names the running process {pid}, is the server already running?"
+        )))),
+        _ => Ok(()),
+    }
+}
+
+pub fn write_pidfile(path: &str) -> GenericResult<()> {
+    std::fs::write(path, format!("{}\n", std::process::id()))?;
+    Ok(())
+}
+
+// Signal 0 only checks the process, EPERM means it exists under another user.
+#[cfg(unix)]
+fn process_alive(pid: u32) -> bool {
+    let Ok(pid) = libc::pid_t::try_from(pid) else {
+        return false;
+    };
+    // SAFETY: kill with signal 0 sends nothing.
+    let sent = unsafe { libc::kill(pid, 0) } == 0;
+    sent || std::io::Error::last_os_error().raw_os_error() == Some(libc::EPERM)
+}
+
+// Without a way to check, the process is taken as gone.
+#[cfg(not(unix))]
+fn process_alive(_pid: u32) -> bool {
+    false
+}
+
+// Liveness probe without sending data: a zero byte write fails with the pending
+// socket error (e.g. after a reset) or when the connection has been shut down.
+pub fn is_connection_dead(stream: &TcpStream) -> bool {
+    if !matches!(stream.take_error(), Ok(None)) {
+        return true;
+    }
+    let mut writer = stream;
+    writer.write(&[]).is_err()
+}
+
 pub fn check_reconnecttable(
     node: &str,
     hd: &TcpStream,
@@ -287,9 +816,7 @@ pub fn check_reconnecttable(
     true
 }
 
-pub fn system_load_commandpermission(
-    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
-) -> GenericResult<()> {
+pub fn system_load_commandpermission(sdata: &mut StarsData) -> GenericResult<()> {
     match load_file_to_list(CMD_DENY, &sdata.libdir) {
         Ok(list) => {
             sdata.cmddeny.extend(list);
@@ -314,7 +841,7 @@ pub fn system_load_commandpermission(
     Ok(())
 }
 
-pub fn system_load_aliases(sdata: &mut std::sync::MutexGuard<'_, StarsData>) -> GenericResult<()> {
+pub fn system_load_aliases(sdata: &mut StarsData) -> GenericResult<()> {
     match load_file_to_map(ALIASES, sdata) {
         Ok(_) => Ok(()),
         Err(err) => {
@@ -324,9 +851,7 @@ pub fn system_load_aliases(sdata: &mut std::sync::MutexGuard<'_, StarsData>) ->
     }
 }
 
-pub fn system_load_reconnecttable_permission(
-    sdata: &mut std::sync::MutexGuard<'_, StarsData>,
-) -> GenericResult<()> {
+pub fn system_load_reconnecttable_permission(sdata: &mut StarsData) -> GenericResult<()> {
     match load_file_to_list(RECONNECT_TABLE_DENY, &sdata.libdir) {
         Ok(list) => {
             sdata.reconndeny.extend(list);
@@ -351,7 +876,21 @@ pub fn system_load_reconnecttable_permission(
     Ok(())
 }
 
-pub fn system_load_shutdown_permission(sdata: &mut std::sync::MutexGuard<'_, StarsData>) {
+// The old list is kept if the file can't be read.
+pub fn system_load_hosts(sdata: &mut StarsData) -> GenericResult<()> {
+    match load_file_to_list(HOST_LIST, &sdata.libdir) {
+        Ok(list) => {
+            sdata.hosts = list;
+            Ok(())
+        }
+        Err(err) => {
+            console::error(format!("Error loading {HOST_LIST} to list: {err}"));
+            Err(err)
+        }
+    }
+}
+
+pub fn system_load_shutdown_permission(sdata: &mut StarsData) {
     match load_file_to_list(SHUTDOWN_ALLOW, &sdata.libdir) {
         Ok(list) => {
             sdata.shutallow.extend(list);
@@ -1,5 +1,7 @@
 use bevy::prelude::*;
-use std::collections::HashMap;
+use std::collections::{BTreeMap, HashMap};
+use std::fs;
+use std::path::{Path, PathBuf};
 use std::sync::Mutex;
 
 use crate::events::{EventReceiver, ServerEvent};
@@ -14,6 +16,64 @@ pub struct VisualNodeGraph {
     pub nodes: HashMap<String, Entity>,
     pub node_positions: HashMap<String, Vec2>,
     pub node_count_changed: bool,
+    /// Positions restored from the positions file; these nodes are not auto-placed.
+    pub saved_positions: HashMap<String, Vec2>,
+}
+
+/// Optional JSON file (node name -> [x, y]) to keep node positions across restarts.
+#[derive(Resource)]
+pub struct PositionsFile {
+    pub path: Option<PathBuf>,
+    pub save_timer: Timer,
+}
+
+impl Default for PositionsFile {
+    fn default() -> Self {
+        PositionsFile {
+            path: None,
+            save_timer: Timer::from_seconds(10.0, TimerMode::Repeating),
+        }
+    }
+}
+
+/// Hand-authored node positions (--layout-file), same format as the positions file.
+/// In the fixed mode (toggled with the L key) the listed nodes stay where the file puts them,
+/// in the auto mode all nodes are placed as without the file.
+#[derive(Resource, Default)]
+pub struct AuthoredLayout {
+    pub path: Option<PathBuf>,
+    pub positions: HashMap<String, Vec2>,
+    pub fixed: bool,
+}
+
+/// Visibility of the System and Debugger pseudo-nodes, toggled with the S and D keys.
+#[derive(Resource)]
+pub struct PseudoNodeVisibility {
+    pub show_system: bool,
+    pub show_debugger: bool,
+}
+
+impl Default for PseudoNodeVisibility {
+    fn default() -> Self {
+        PseudoNodeVisibility {
+            show_system: true,
+            show_debugger: true,
+        }
+    }
+}
+
+impl PseudoNodeVisibility {
+    pub fn is_hidden(&self, name: &str) -> bool {
+        (name == "System" && !self.show_system) || (name == "Debugger" && !self.show_debugger)
+    }
+
+    pub fn of(&self, name: &str) -> Visibility {
+        if self.is_hidden(name) {
+            Visibility::Hidden
+        } else {
+            Visibility::Inherited
+        }
+    }
 }
 
 /// Marker component for node circle entities.
@@ -34,60 +94,134 @@ pub struct MessageDot {
     pub lifetime: Timer,
 }
 
+/// Travel time of a message marker from source to target node.
+#[derive(Resource)]
+pub struct MessageAnimation {
+    pub duration_secs: f32,
+}
+
+impl Default for MessageAnimation {
+    fn default() -> Self {
+        MessageAnimation {
+            duration_secs: MESSAGE_DURATION_SECS,
+        }
+    }
+}
+
+pub const MESSAGE_DURATION_SECS: f32 = 0.5;
+/// Upper bound of message arrows alive at the same time; new edges are dropped beyond it.
+const MAX_LIVE_MESSAGES: usize = 200;
+const MESSAGE_COLOR: Color = Color::srgb(1.0, 1.0, 0.3);
+/// Window background while the server routing is paused.
+const PAUSED_BACKGROUND: Color = Color::srgb(0.3, 0.05, 0.05);
+/// Window background while the registrations are locked (and the routing is not paused).
+const LOCKED_BACKGROUND: Color = Color::srgb(0.05, 0.1, 0.3);
+
+/// Server states shown by the window background.
+#[derive(Default)]
+struct ServerState {
+    paused: bool,
+    registrations_locked: bool,
+}
+
+impl ServerState {
+    fn background(&self) -> Color {
+        if self.paused {
+            PAUSED_BACKGROUND
+        } else if self.registrations_locked {
+            LOCKED_BACKGROUND
+        } else {
+            ClearColor::default().0
+        }
+    }
+}
+
 pub struct StarsVisualizationPlugin;
 
 impl Plugin for StarsVisualizationPlugin {
     fn build(&self, app: &mut App) {
-        app.init_resource::<VisualNodeGraph>().add_systems(
-            Update,
-            (
-                poll_server_events,
-                update_node_layout,
-                animate_messages,
-                draw_connections,
-            ),
-        );
+        app.init_resource::<VisualNodeGraph>()
+            .init_resource::<PositionsFile>()
+            .init_resource::<PseudoNodeVisibility>()
+            .init_resource::<MessageAnimation>()
+            .init_resource::<AuthoredLayout>()
+            .add_systems(Startup, (load_saved_positions, load_layout_file))
+            .add_systems(
+                Update,
+                (
+                    toggle_pseudo_nodes,
+                    toggle_layout_mode,
+                    poll_server_events,
+                    update_node_layout,
+                    animate_messages,
+                    draw_connections,
+                    save_positions,
+                ),
+            );
     }
 }
 
+/// Reserved color of the System pseudo-node.
+const SYSTEM_NODE_COLOR: Color = Color::srgb(0.85, 0.85, 0.85);
+
+/// Stable color per subsystem: the hue is a hash of the first dot-segment of the name,
+/// so `beamline1.*` nodes share a color.
+fn node_color(name: &str) -> Color {
+    let prefix = name.split('.').next().unwrap_or(name);
+    if prefix == "System" {
+        return SYSTEM_NODE_COLOR;
+    }
+    // FNV-1a, unlike DefaultHasher it gives the same hue on every run and Rust version.
+    let hash = prefix.bytes().fold(0x811c9dc5_u32, |hash, byte| {
+        (hash ^ byte as u32).wrapping_mul(0x01000193)
+    });
+    Color::hsv((hash % 360) as f32, 0.65, 0.95)
+}
+
 /// Drain the mpsc channel each frame and apply events.
+#[allow(clippy::too_many_arguments)]
 fn poll_server_events(
     receiver: Res<ServerEventReceiver>,
     mut graph: ResMut<VisualNodeGraph>,
+    visibility: Res<PseudoNodeVisibility>,
+    animation: Res<MessageAnimation>,
+    live_messages: Query<(), With<MessageDot>>,
+    mut clear_color: ResMut<ClearColor>,
+    mut state: Local<ServerState>,
+    mut exit: MessageWriter<AppExit>,
     mut commands: Commands,
 ) {
+    // Messages on the same from->to edge within a frame become a single arrow.
+    let mut edges: HashMap<(String, String), u32> = HashMap::new();
     let rx = receiver.0.lock().unwrap();
     while let Ok(event) = rx.try_recv() {
         match event {
             ServerEvent::NodeConnected { name } => {
-                if !graph.nodes.contains_key(&name) {
-                    let entity = commands
-                        .spawn((
-                            Sprite::from_color(
-                                Color::srgb(0.2, 0.7, 1.0),
-                                Vec2::new(40.0, 40.0),
-                            ),
-                            Transform::from_translation(Vec3::ZERO),
-                            NodeCircle {
-                                name: name.clone(),
-                            },
-                        ))
-                        .with_children(|parent| {
-                            parent.spawn((
-                                Text2d::new(name.clone()),
-                                TextFont {
-                                    font_size: 14.0,
-                                    ..default()
-                                },
-                                TextColor(Color::WHITE),
-                                Transform::from_translation(Vec3::new(0.0, -30.0, 1.0)),
-                                NodeLabel,
-                            ));
-                        })
-                        .id();
-                    graph.nodes.insert(name, entity);
-                    graph.node_count_changed = true;
+                if graph.nodes.contains_key(&name) {
+                    continue;
                 }
+                let entity = commands
+                    .spawn((
+                        Sprite::from_color(node_color(&name), Vec2::new(40.0, 40.0)),
+                        Transform::from_translation(Vec3::ZERO),
+                        visibility.of(&name),
+                        NodeCircle { name: name.clone() },
+                    ))
+                    .with_children(|parent| {
+                        parent.spawn((
+                            Text2d::new(name.clone()),
+                            TextFont {
+                                font_size: 14.0,
+                                ..default()
+                            },
+                            TextColor(Color::WHITE),
+                            Transform::from_translation(Vec3::new(0.0, -30.0, 1.0)),
+                            NodeLabel,
+                        ));
+                    })
+                    .id();
+                graph.nodes.insert(name, entity);
+                graph.node_count_changed = true;
             }
             ServerEvent::NodeDisconnected { name } => {
                 if let Some(entity) = graph.nodes.remove(&name) {
@@ -97,51 +231,164 @@ fn poll_server_events(
                 graph.node_count_changed = true;
             }
             ServerEvent::MessageRouted { from, to } => {
-                let from_pos = graph
-                    .node_positions
-                    .get(&from)
-                    .copied()
-                    .unwrap_or(Vec2::ZERO);
-                let to_pos = graph
-                    .node_positions
-                    .get(&to)
-                    .copied()
-                    .unwrap_or(Vec2::ZERO);
-
-                commands.spawn((
-                    Sprite::from_color(Color::srgb(1.0, 1.0, 0.3), Vec2::new(10.0, 10.0)),
-                    Transform::from_translation(from_pos.extend(2.0)),
-                    MessageDot {
-                        from_pos,
-                        to_pos,
-                        lifetime: Timer::from_seconds(0.5, TimerMode::Once),
-                    },
-                ));
+                if visibility.is_hidden(&from) || visibility.is_hidden(&to) {
+                    continue;
+                }
+                *edges.entry((from, to)).or_insert(0) += 1;
+            }
+            ServerEvent::RoutingPaused { paused } => {
+                state.paused = paused;
+                clear_color.0 = state.background();
             }
+            ServerEvent::RegistrationsLocked { locked } => {
+                state.registrations_locked = locked;
+                clear_color.0 = state.background();
+            }
+            // The window goes with the server, the positions are saved on the way out.
+            ServerEvent::ServerShutdown => {
+                exit.write(AppExit::Success);
+            }
+            ServerEvent::CommandDenied { .. } | ServerEvent::ConnectionRejected { .. } => {}
         }
     }
+
+    // Busiest edges first, so they survive the cap.
+    let mut edges: Vec<_> = edges.into_iter().collect();
+    edges.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+    let free = MAX_LIVE_MESSAGES.saturating_sub(live_messages.iter().count());
+    for ((from, to), count) in edges.into_iter().take(free) {
+        let from_pos = graph
+            .node_positions
+            .get(&from)
+            .copied()
+            .unwrap_or(Vec2::ZERO);
+        let to_pos = graph.node_positions.get(&to).copied().unwrap_or(Vec2::ZERO);
+
+        spawn_message_arrow(
+            &mut commands,
+            from_pos,
+            to_pos,
+            count,
+            animation.duration_secs,
+        );
+    }
+}
+
+/// Show or hide the System (S key) and Debugger (D key) pseudo-nodes.
+fn toggle_pseudo_nodes(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut visibility: ResMut<PseudoNodeVisibility>,
+    mut graph: ResMut<VisualNodeGraph>,
+    mut query: Query<(&NodeCircle, &mut Visibility)>,
+) {
+    if keys.just_pressed(KeyCode::KeyS) {
+        visibility.show_system = !visibility.show_system;
+    } else if keys.just_pressed(KeyCode::KeyD) {
+        visibility.show_debugger = !visibility.show_debugger;
+    } else {
+        return;
+    }
+    // The labels are children of the circles and inherit their visibility.
+    for (node_circle, mut vis) in &mut query {
+        *vis = visibility.of(&node_circle.name);
+    }
+    graph.node_count_changed = true;
+}
+
+/// Switch between the authored layout and the automatic one (L key).
+fn toggle_layout_mode(
+    keys: Res<ButtonInput<KeyCode>>,
+    mut layout: ResMut<AuthoredLayout>,
+    mut graph: ResMut<VisualNodeGraph>,
+) {
+    if keys.just_pressed(KeyCode::KeyL) && layout.path.is_some() {
+        layout.fixed = !layout.fixed;
+        graph.node_count_changed = true;
+    }
+}
+
+/// Spawn an arrow pointing from `from_pos` to `to_pos` with a fading tail behind it.
+/// The parts are children in the arrow's local frame, where +x is the travel direction.
+/// An arrow standing for several messages is drawn larger (up to 3x).
+fn spawn_message_arrow(
+    commands: &mut Commands,
+    from_pos: Vec2,
+    to_pos: Vec2,
+    count: u32,
+    duration_secs: f32,
+) {
+    let direction = (to_pos - from_pos).to_angle();
+    let scale = (1.0 + (count as f32).log2() * 0.5).min(3.0);
+    commands
+        .spawn((
+            Transform::from_translation(from_pos.extend(2.0))
+                .with_rotation(Quat::from_rotation_z(direction))
+                .with_scale(Vec3::splat(scale)),
+            Visibility::default(),
+            MessageDot {
+                from_pos,
+                to_pos,
+                lifetime: Timer::from_seconds(duration_secs, TimerMode::Once),
+            },
+        ))
+        .with_children(|arrow| {
+            // Shaft
+            arrow.spawn((
+                Sprite::from_color(MESSAGE_COLOR, Vec2::new(12.0, 2.0)),
+                Transform::from_xyz(-6.0, 0.0, 0.0),
+            ));
+            // Head
+            for side in [-1.0_f32, 1.0] {
+                arrow.spawn((
+                    Sprite::from_color(MESSAGE_COLOR, Vec2::new(8.0, 2.0)),
+                    Transform::from_xyz(-2.5, side * 2.5, 0.0)
+                        .with_rotation(Quat::from_rotation_z(side * 0.6)),
+                ));
+            }
+            // Tail
+            for (i, alpha) in [0.6_f32, 0.4, 0.2].into_iter().enumerate() {
+                let size = 4.0 - i as f32;
+                arrow.spawn((
+                    Sprite::from_color(MESSAGE_COLOR.with_alpha(alpha), Vec2::splat(size)),
+                    Transform::from_xyz(-16.0 - 6.0 * i as f32, 0.0, 0.0),
+                ));
+            }
+        });
 }
 
 /// Recompute node positions in a circle when node count changes, and lerp towards targets.
+/// Hidden pseudo-nodes get no position, so they are left out of the circle and the connections.
 fn update_node_layout(
     mut graph: ResMut<VisualNodeGraph>,
+    visibility: Res<PseudoNodeVisibility>,
+    layout: Res<AuthoredLayout>,
     mut query: Query<(&NodeCircle, &mut Transform)>,
     windows: Query<&Window>,
 ) {
     if graph.node_count_changed {
-        let node_count = graph.nodes.len();
-        if node_count > 0 {
-            if let Ok(window) = windows.single() {
-                let radius = (window.width().min(window.height()) * 0.35).max(100.0);
-
-                let mut new_positions = HashMap::new();
-                for (i, name) in graph.nodes.keys().enumerate() {
-                    let angle = (i as f32 / node_count as f32) * std::f32::consts::TAU;
-                    let pos = Vec2::new(angle.cos(), angle.sin()) * radius;
-                    new_positions.insert(name.clone(), pos);
-                }
-                graph.node_positions = new_positions;
+        let visible: Vec<String> = graph
+            .nodes
+            .keys()
+            .filter(|name| !visibility.is_hidden(name))
+            .cloned()
+            .collect();
+        let node_count = visible.len();
+        if let Ok(window) = windows.single() {
+            let radius = (window.width().min(window.height()) * 0.35).max(100.0);
+
+            let mut new_positions = HashMap::new();
+            for (i, name) in visible.iter().enumerate() {
+                let authored = layout.positions.get(name).filter(|_| layout.fixed);
+                let pos = match authored.or_else(|| graph.saved_positions.get(name)) {
+                    Some(pos) => *pos,
+                    None => {
+                        let angle = (i as f32 / node_count as f32) * std::f32::consts::TAU;
+                        Vec2::new(angle.cos(), angle.sin()) * radius
+                    }
+                };
+                new_positions.insert(name.clone(), pos);
             }
+            graph.node_positions = new_positions;
         }
         graph.node_count_changed = false;
     }
@@ -155,7 +402,7 @@ fn update_node_layout(
     }
 }
 
-/// Animate message dots from source to target, despawn when done.
+/// Animate message arrows from source to target, despawn when done.
 fn animate_messages(
     mut commands: Commands,
     time: Res<Time>,
@@ -173,9 +420,18 @@ fn animate_messages(
     }
 }
 
-/// Draw lines between all nodes using gizmos.
-fn draw_connections(mut gizmos: Gizmos, graph: Res<VisualNodeGraph>) {
-    let positions: Vec<Vec2> = graph.node_positions.values().copied().collect();
+/// Draw lines between all visible nodes using gizmos.
+fn draw_connections(
+    mut gizmos: Gizmos,
+    graph: Res<VisualNodeGraph>,
+    visibility: Res<PseudoNodeVisibility>,
+) {
+    let positions: Vec<Vec2> = graph
+        .node_positions
+        .iter()
+        .filter(|(name, _)| !visibility.is_hidden(name))
+        .map(|(_, pos)| *pos)
+        .collect();
     let node_count = positions.len();
     if node_count < 2 {
         return;
@@ -187,11 +443,84 @@ fn draw_connections(mut gizmos: Gizmos, graph: Res<VisualNodeGraph>) {
     }
 }
 
+fn read_positions_file(path: &Path) -> HashMap<String, Vec2> {
+    let text = match fs::read_to_string(path) {
+        Ok(text) => text,
+        // No file yet, it is written at the first save.
+        Err(_) => return HashMap::new(),
+    };
+    match serde_json::from_str::<HashMap<String, [f32; 2]>>(&text) {
+        Ok(positions) => positions
+            .into_iter()
+            .map(|(name, pos)| (name, Vec2::from_array(pos)))
+            .collect(),
+        Err(err) => {
+            eprintln!("Error reading node positions {}: {err}", path.display());
+            HashMap::new()
+        }
+    }
+}
+
+fn write_positions_file(path: &Path, positions: &HashMap<String, Vec2>) {
+    let sorted: BTreeMap<&String, [f32; 2]> = positions
+        .iter()
+        .map(|(name, pos)| (name, pos.to_array()))
+        .collect();
+    let result = serde_json::to_string_pretty(&sorted)
+        .map_err(|err| err.to_string())
+        .and_then(|text| fs::write(path, text).map_err(|err| err.to_string()));
+    if let Err(err) = result {
+        eprintln!("Error writing node positions {}: {err}", path.display());
+    }
+}
+
+/// Restore the node positions saved by a previous run.
+fn load_saved_positions(file: Res<PositionsFile>, mut graph: ResMut<VisualNodeGraph>) {
+    if let Some(path) = &file.path {
+        graph.saved_positions = read_positions_file(path);
+    }
+}
+
+/// Read the authored layout, a missing or broken file leaves the auto layout.
+fn load_layout_file(mut layout: ResMut<AuthoredLayout>) {
+    let Some(path) = layout.path.clone() else {
+        return;
+    };
+    if !path.exists() {
+        eprintln!("Layout file {} not found.", path.display());
+        return;
+    }
+    layout.positions = read_positions_file(&path);
+    layout.fixed = !layout.positions.is_empty();
+}
+
+/// Save the current node positions periodically and when the app exits.
+fn save_positions(
+    time: Res<Time>,
+    mut file: ResMut<PositionsFile>,
+    mut graph: ResMut<VisualNodeGraph>,
+    mut exit: MessageReader<AppExit>,
+) {
+    let Some(path) = file.path.clone() else {
+        return;
+    };
+    let due = file.save_timer.tick(time.delta()).just_finished();
+    if due || exit.read().next().is_some() {
+        let current = graph.node_positions.clone();
+        graph.saved_positions.extend(current);
+        write_positions_file(&path, &graph.saved_positions);
+    }
+}
+
 fn setup_camera(mut commands: Commands) {
     commands.spawn(Camera2d);
 }
 
-pub fn run_visualization(receiver: EventReceiver) {
+pub fn run_visualization(
+    receiver: EventReceiver,
+    positions_file: Option<String>,
+    layout_file: Option<String>,
+) {
     App::new()
         .add_plugins(DefaultPlugins.set(WindowPlugin {
             primary_window: Some(Window {
@@ -202,6 +531,14 @@ pub fn run_visualization(receiver: EventReceiver) {
             ..default()
         }))
         .insert_resource(ServerEventReceiver(Mutex::new(receiver)))
+        .insert_resource(PositionsFile {
+            path: positions_file.map(PathBuf::from),
+            ..default()
+        })
+        .insert_resource(AuthoredLayout {
+            path: layout_file.map(PathBuf::from),
+            ..default()
+        })
         .add_plugins(StarsVisualizationPlugin)
         .add_systems(Startup, setup_camera)
         .run();